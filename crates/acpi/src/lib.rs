@@ -10,7 +10,7 @@ use acpi::{
     PhysicalMapping, SsdtIterator,
 };
 use core::ptr::NonNull;
-use log::info;
+use log::{info, warn};
 use x86_64::PhysAddr;
 
 #[derive(Clone)]
@@ -40,8 +40,9 @@ static mut HPET: Option<HpetInfo> = None;
 static mut PCI_CONFIG_REGIONS: Option<PciConfigRegions<'static, alloc::alloc::Global>> = None;
 static mut TABLES: Option<AcpiTables<AcpiMemHandler>> = None;
 
-pub fn apic() -> &'static Apic<'static, alloc::alloc::Global> {
-    unsafe { APIC.as_ref().expect("ACPI not initialized") }
+/// APIC interrupt model, `None` if firmware does not describe one
+pub fn apic() -> Option<&'static Apic<'static, alloc::alloc::Global>> {
+    unsafe { APIC.as_ref() }
 }
 
 pub fn hpet() -> Option<&'static HpetInfo> {
@@ -54,12 +55,16 @@ pub fn pci_config_regions() -> Option<&'static PciConfigRegions<'static, alloc::
 
 pub fn init_acpi(rdsp_addr: Option<u64>) {
     unsafe {
-        TABLES = Some(
-            rdsp_addr
-                .map(|addr| AcpiTables::from_rsdp(AcpiMemHandler, addr as usize))
-                .unwrap_or_else(|| AcpiTables::search_for_rsdp_bios(AcpiMemHandler))
-                .expect("Failed to get ACPI tables"),
-        );
+        let tables = rdsp_addr
+            .map(|addr| AcpiTables::from_rsdp(AcpiMemHandler, addr as usize))
+            .unwrap_or_else(|| AcpiTables::search_for_rsdp_bios(AcpiMemHandler));
+        match tables {
+            Ok(tables) => TABLES = Some(tables),
+            Err(e) => {
+                warn!("Failed to get ACPI tables: {:?}", e);
+                return;
+            }
+        }
         info!("Got ACPI tables");
         let tables = TABLES.as_ref().unwrap();
         let platform_info = tables.platform_info().expect("Failed to get platform info");
@@ -72,9 +77,8 @@ pub fn init_acpi(rdsp_addr: Option<u64>) {
             },
         };
         match platform_info.interrupt_model {
-            InterruptModel::Unknown => panic!("This kernel requires APIC to run"),
             InterruptModel::Apic(apic) => APIC = Some(apic),
-            _ => panic!("ACPI does not have interrupt model info"),
+            _ => warn!("ACPI does not describe APIC interrupt model"),
         }
     }
 }
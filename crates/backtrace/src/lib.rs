@@ -0,0 +1,167 @@
+//! Frame-pointer backtraces.
+//!
+//! The kernel is built with frame pointers, so every frame starts with the caller's `rbp` followed
+//! by the return address. The walk stops at the first frame pointer outside of the stack it started
+//! on. Return addresses are resolved with the symbol table that the runner embeds into the kernel
+//! image after linking, see [`symbols`].
+#![no_std]
+
+extern crate alloc;
+
+mod symbols;
+
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::VirtAddr;
+
+pub use symbols::{init, resolve, SYMBOLS_SIZE};
+
+pub const MAX_FRAMES: usize = 32;
+const MAX_EXTRA_STACKS: usize = 16;
+
+/// Stacks outside of [`distros_memory_stack`], like IST stacks
+static EXTRA_STACKS: [(AtomicU64, AtomicU64); MAX_EXTRA_STACKS] =
+    [const { (AtomicU64::new(0), AtomicU64::new(0)) }; MAX_EXTRA_STACKS];
+
+/// Makes frames on `start..end` walkable. Returns `false` if there is no free slot.
+pub fn register_stack(start: VirtAddr, end: VirtAddr) -> bool {
+    for (s, e) in EXTRA_STACKS.iter() {
+        if s.compare_exchange(0, start.as_u64(), Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            e.store(end.as_u64(), Ordering::Release);
+            return true;
+        }
+    }
+    false
+}
+
+/// Bounds of the known stack that contains `addr`
+pub fn stack_bounds(addr: u64) -> Option<(u64, u64)> {
+    for (s, e) in EXTRA_STACKS.iter() {
+        let (start, end) = (s.load(Ordering::Acquire), e.load(Ordering::Acquire));
+        if start != 0 && addr >= start && addr < end {
+            return Some((start, end));
+        }
+    }
+    distros_memory_stack::stack_bounds(VirtAddr::new_truncate(addr))
+        .map(|(start, end)| (start.as_u64(), end.as_u64()))
+}
+
+/// Frame pointer of the code interrupted by the current interrupt handler. Must be inlined into the
+/// handler itself.
+#[inline(always)]
+pub fn interrupted_frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, qword ptr [rbp]", out(reg) rbp, options(nostack, readonly)) };
+    rbp
+}
+
+#[derive(Clone)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+    /// First frame is the faulting instruction, not a return address
+    exact: bool,
+    /// Walk stopped at a frame pointer outside of known stacks
+    lost: bool,
+}
+
+impl Backtrace {
+    /// Backtrace of the caller
+    #[inline(always)]
+    pub fn capture() -> Backtrace {
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+        let mut backtrace = Backtrace::empty(false);
+        backtrace.walk(rbp);
+        backtrace
+    }
+
+    /// Backtrace of interrupted code, starting at the instruction `rip`. `rbp` is the interrupted
+    /// frame pointer, see [`interrupted_frame_pointer`].
+    pub fn from_interrupt(rip: u64, rbp: u64) -> Backtrace {
+        let mut backtrace = Backtrace::empty(true);
+        backtrace.push(rip);
+        backtrace.walk(rbp);
+        backtrace
+    }
+
+    fn empty(exact: bool) -> Backtrace {
+        Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+            exact,
+            lost: false,
+        }
+    }
+
+    fn push(&mut self, address: u64) -> bool {
+        if self.len == MAX_FRAMES {
+            return false;
+        }
+        self.frames[self.len] = address;
+        self.len += 1;
+        true
+    }
+
+    fn walk(&mut self, mut rbp: u64) {
+        let (start, end) = match stack_bounds(rbp) {
+            Some(bounds) => bounds,
+            None => {
+                self.lost = true;
+                return;
+            }
+        };
+        loop {
+            if rbp % 8 != 0 || rbp < start || rbp + 16 > end {
+                self.lost = true;
+                return;
+            }
+            let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+            // outermost frame
+            if ret == 0 || !self.push(ret) {
+                return;
+            }
+            // callers live above on the stack
+            if next <= rbp {
+                return;
+            }
+            rbp = next;
+        }
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Backtrace:")?;
+        for (i, &address) in self.frames().iter().enumerate() {
+            // return address can be right after the end of a function that ends with a call
+            let lookup = if i == 0 && self.exact {
+                address
+            } else {
+                address - 1
+            };
+            match resolve(lookup) {
+                Some((name, start)) => writeln!(
+                    f,
+                    "  #{:<2} 0x{:016x} {}+0x{:x}",
+                    i,
+                    address,
+                    name,
+                    address - start
+                )?,
+                None => writeln!(f, "  #{:<2} 0x{:016x} ??", i, address)?,
+            }
+        }
+        if self.lost {
+            writeln!(f, "  <frame pointer outside of known stacks>")?;
+        }
+        Ok(())
+    }
+}
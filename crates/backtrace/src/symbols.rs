@@ -0,0 +1,159 @@
+//! Kernel symbol table.
+//!
+//! [`SYMBOLS_SIZE`] bytes of the `.ksyms` section are reserved in the image and filled by the runner
+//! after linking. Layout, little-endian:
+//! - magic `KSYMS\x01\0\0`, or `KSYMS\0\0\0` when the table was not embedded
+//! - `u64` link-time address of the table itself, to find where the kernel was loaded
+//! - `u32` number of symbols
+//! - symbols sorted by address: LEB128 address delta from the previous symbol, LEB128 size, `u8`
+//!   length of the prefix shared with the previous name, LEB128 length of the rest of the name, the
+//!   rest of the name
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ptr::addr_of;
+use log::{info, warn};
+
+pub const SYMBOLS_SIZE: usize = 512 * 1024;
+const MAGIC: &[u8; 8] = b"KSYMS\x01\0\0";
+const MAGIC_EMPTY: &[u8; 8] = b"KSYMS\0\0\0";
+
+#[no_mangle]
+#[used]
+#[link_section = ".ksyms"]
+static mut KSYMS: [u8; SYMBOLS_SIZE] = placeholder();
+
+const fn placeholder() -> [u8; SYMBOLS_SIZE] {
+    let mut data = [0; SYMBOLS_SIZE];
+    let mut i = 0;
+    while i < MAGIC_EMPTY.len() {
+        data[i] = MAGIC_EMPTY[i];
+        i += 1;
+    }
+    data
+}
+
+#[derive(Debug)]
+enum SymbolsError {
+    NotEmbedded,
+    Corrupted,
+}
+
+struct Symbol {
+    start: u64,
+    size: u64,
+    name_start: usize,
+    name_end: usize,
+}
+
+struct SymbolTable {
+    symbols: Vec<Symbol>,
+    names: String,
+}
+
+static mut TABLE: Option<SymbolTable> = None;
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], SymbolsError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(SymbolsError::Corrupted)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, SymbolsError> {
+        let mut value = [0; 8];
+        value.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn u32(&mut self) -> Result<u32, SymbolsError> {
+        let mut value = [0; 4];
+        value.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(value))
+    }
+
+    fn leb128(&mut self) -> Result<u64, SymbolsError> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.bytes(1)?[0];
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(SymbolsError::Corrupted)
+    }
+}
+
+fn decode() -> Result<SymbolTable, SymbolsError> {
+    // the table is patched after linking, so the compiler must not see the initializer
+    let data = unsafe { &*addr_of!(KSYMS) };
+    let mut reader = Reader { data, pos: 0 };
+    if reader.bytes(8)? != MAGIC {
+        return Err(SymbolsError::NotEmbedded);
+    }
+    let slide = (addr_of!(KSYMS) as u64).wrapping_sub(reader.u64()?);
+    let count = reader.u32()? as usize;
+    let mut symbols: Vec<Symbol> = Vec::with_capacity(count);
+    let mut names = String::new();
+    let mut address = 0u64;
+    for _ in 0..count {
+        address = address.wrapping_add(reader.leb128()?);
+        let size = reader.leb128()?;
+        let shared = reader.bytes(1)?[0] as usize;
+        let len = reader.leb128()? as usize;
+        let suffix =
+            core::str::from_utf8(reader.bytes(len)?).map_err(|_| SymbolsError::Corrupted)?;
+        let name_start = names.len();
+        if let Some(prev) = symbols.last() {
+            let prefix = names
+                .get(prev.name_start..prev.name_start + shared)
+                .ok_or(SymbolsError::Corrupted)?;
+            names.push_str(&String::from(prefix));
+        }
+        names.push_str(suffix);
+        symbols.push(Symbol {
+            start: address.wrapping_add(slide),
+            size,
+            name_start,
+            name_end: names.len(),
+        });
+    }
+    Ok(SymbolTable { symbols, names })
+}
+
+/// Decodes the embedded symbol table. Backtraces print raw addresses until it is called.
+pub fn init() {
+    match decode() {
+        Ok(table) => {
+            info!("Loaded {} kernel symbols", table.symbols.len());
+            unsafe { TABLE = Some(table) };
+        }
+        Err(e) => warn!("Kernel symbols are not available: {:?}", e),
+    }
+}
+
+/// Name and start address of the function that contains `address`
+pub fn resolve(address: u64) -> Option<(&'static str, u64)> {
+    let table = unsafe { (*addr_of!(TABLE)).as_ref()? };
+    let index = match table.symbols.binary_search_by_key(&address, |s| s.start) {
+        Ok(index) => index,
+        Err(0) => return None,
+        Err(index) => index - 1,
+    };
+    let symbol = &table.symbols[index];
+    if address - symbol.start >= symbol.size.max(1) {
+        return None;
+    }
+    Some((
+        &table.names[symbol.name_start..symbol.name_end],
+        symbol.start,
+    ))
+}
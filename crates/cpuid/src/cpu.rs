@@ -0,0 +1,68 @@
+//! Dense CPU indices for per-CPU arrays.
+//!
+//! Hardware ids of CPUs (APIC ids) are sparse and 32-bit wide with x2APIC, so they can't index
+//! arrays directly. Every CPU registers itself with [`register_cpu`] during bring-up and gets the
+//! next free index below [`MAX_CPUS`].
+use core::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+
+pub const MAX_CPUS: usize = 64;
+
+const FREE: u32 = u32::MAX;
+
+/// Hardware ids of registered CPUs, by index
+static IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(FREE) }; MAX_CPUS];
+/// Index + 1 of CPUs with hardware ids below 256, which covers all xAPIC ids
+static SMALL_IDS: [AtomicU8; 256] = [const { AtomicU8::new(0) }; 256];
+static mut CPU_ID: fn() -> u32 = || 0;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum CpuIndexError {
+    /// All [`MAX_CPUS`] indices are taken
+    TooManyCpus { id: u32 },
+}
+
+/// Sets function that returns hardware id of the current CPU. It is called from NMI, so it must
+/// not take locks.
+pub fn set_cpu_id(fun: fn() -> u32) {
+    unsafe {
+        CPU_ID = fun;
+    }
+}
+
+/// Assigns index to the current CPU, or returns the one it already has
+pub fn register_cpu() -> Result<usize, CpuIndexError> {
+    let id = unsafe { CPU_ID() };
+    for (index, slot) in IDS.iter().enumerate() {
+        match slot.compare_exchange(FREE, id, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => {
+                if let Some(small) = SMALL_IDS.get(id as usize) {
+                    small.store(index as u8 + 1, Ordering::Release);
+                }
+                return Ok(index);
+            }
+            Err(registered) if registered == id => return Ok(index),
+            Err(_) => {}
+        }
+    }
+    Err(CpuIndexError::TooManyCpus { id })
+}
+
+/// Number of registered CPUs, their indices are `0..cpu_count()`
+pub fn cpu_count() -> usize {
+    IDS.iter()
+        .take_while(|slot| slot.load(Ordering::Acquire) != FREE)
+        .count()
+}
+
+/// Index of the current CPU. The bootstrap CPU has index `0` even before it is registered.
+#[inline]
+pub fn cpu_index() -> usize {
+    let id = unsafe { CPU_ID() };
+    match SMALL_IDS.get(id as usize) {
+        Some(index) => (index.load(Ordering::Acquire) as usize).saturating_sub(1),
+        None => IDS
+            .iter()
+            .position(|slot| slot.load(Ordering::Acquire) == id)
+            .unwrap_or(0),
+    }
+}
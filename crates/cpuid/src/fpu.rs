@@ -1,5 +1,5 @@
-use crate::get_feature_info;
-use raw_cpuid::FeatureInfo;
+use crate::{get_extended_feature_info, get_feature_info};
+use raw_cpuid::{ExtendedFeatures, FeatureInfo};
 
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct FpuInfo {
@@ -10,6 +10,7 @@ pub struct FpuInfo {
     pub sse4_2: bool,
     pub ssse3: bool,
     pub avx: bool,
+    pub avx512f: bool,
     pub fxsave_fxstor: bool,
     pub xsave: bool,
     pub fma: bool,
@@ -17,7 +18,7 @@ pub struct FpuInfo {
 }
 
 impl FpuInfo {
-    fn from_feature_info(info: &FeatureInfo) -> Self {
+    fn from_feature_info(info: &FeatureInfo, extended: Option<&ExtendedFeatures>) -> Self {
         FpuInfo {
             sse: info.has_sse(),
             sse2: info.has_sse2(),
@@ -26,6 +27,7 @@ impl FpuInfo {
             sse4_2: info.has_sse42(),
             ssse3: info.has_ssse3(),
             avx: info.has_avx(),
+            avx512f: extended.map_or(false, |e| e.has_avx512f()),
             fxsave_fxstor: info.has_fxsave_fxstor(),
             xsave: info.has_xsave(),
             fma: info.has_fma(),
@@ -34,6 +36,6 @@ impl FpuInfo {
     }
 
     pub fn load() -> FpuInfo {
-        Self::from_feature_info(&get_feature_info())
+        Self::from_feature_info(&get_feature_info(), get_extended_feature_info().as_ref())
     }
 }
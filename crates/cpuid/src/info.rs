@@ -0,0 +1,496 @@
+//! Full description of the current CPU, see [`CpuInfo`]
+use crate::cpuid;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use raw_cpuid::{
+    Associativity, CacheType, DatType, ExtendedFeatures, ExtendedProcessorFeatureIdentifiers,
+    FeatureInfo, Hypervisor, TopologyType,
+};
+use serde::Serialize;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CpuInfo {
+    pub vendor: String,
+    pub brand: String,
+    pub family: u8,
+    pub model: u8,
+    pub stepping: u8,
+    pub apic_id: u32,
+    pub caches: Vec<CacheInfo>,
+    pub tlbs: Vec<TlbInfo>,
+    pub topology: TopologyInfo,
+    /// Names of supported features, in `/proc/cpuinfo` style
+    pub features: Vec<&'static str>,
+    /// `None` on bare metal
+    pub hypervisor: Option<HypervisorInfo>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CacheInfo {
+    pub level: u8,
+    /// `data`, `instruction` or `unified`
+    pub kind: &'static str,
+    /// Size in bytes
+    pub size: u64,
+    pub line_size: u32,
+    /// Ways of associativity, `0` for fully associative cache
+    pub ways: u32,
+    pub sets: u32,
+    /// Maximum number of logical processors sharing the cache
+    pub shared_by: u32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TlbInfo {
+    pub level: u8,
+    /// `data`, `instruction`, `unified`, `load` or `store`
+    pub kind: &'static str,
+    /// Supported page sizes: `4K`, `2M`, `4M` or `1G`
+    pub pages: Vec<&'static str>,
+    pub entries: u32,
+    /// Ways of associativity, `0` for fully associative TLB
+    pub ways: u32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TopologyInfo {
+    pub threads_per_core: u32,
+    pub cores_per_package: u32,
+    pub logical_per_package: u32,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct HypervisorInfo {
+    pub vendor: String,
+    /// TSC frequency in kHz, `0` if not reported
+    pub tsc_frequency: u32,
+    /// APIC timer frequency in kHz, `0` if not reported
+    pub apic_frequency: u32,
+}
+
+impl CpuInfo {
+    /// Reads CPUID of the current CPU
+    pub fn load() -> CpuInfo {
+        let cpuid = cpuid();
+        let info = cpuid
+            .get_feature_info()
+            .expect("CPUID does not have feature infos");
+        let extended = cpuid.get_extended_feature_info();
+        let processor = cpuid.get_extended_processor_and_feature_identifiers();
+        CpuInfo {
+            vendor: cpuid
+                .get_vendor_info()
+                .map_or(String::new(), |v| v.as_str().to_string()),
+            brand: cpuid
+                .get_processor_brand_string()
+                .map_or(String::new(), |b| b.as_str().trim().to_string()),
+            family: info.family_id(),
+            model: info.model_id(),
+            stepping: info.stepping_id(),
+            apic_id: apic_id(&info),
+            caches: caches(),
+            tlbs: tlbs(),
+            topology: topology(&info),
+            features: features(&info, extended.as_ref(), processor.as_ref()),
+            hypervisor: hypervisor(),
+        }
+    }
+}
+
+fn apic_id(info: &FeatureInfo) -> u32 {
+    cpuid()
+        .get_extended_topology_info()
+        .and_then(|mut levels| levels.next())
+        .map_or(info.initial_local_apic_id() as u32, |l| l.x2apic_id())
+}
+
+fn caches() -> Vec<CacheInfo> {
+    let cpuid = cpuid();
+    if let Some(params) = cpuid.get_cache_parameters() {
+        return params
+            .filter_map(|c| {
+                let kind = match c.cache_type() {
+                    CacheType::Data => "data",
+                    CacheType::Instruction => "instruction",
+                    CacheType::Unified => "unified",
+                    _ => return None,
+                };
+                let ways = if c.is_fully_associative() {
+                    0
+                } else {
+                    c.associativity() as u32
+                };
+                Some(CacheInfo {
+                    level: c.level(),
+                    kind,
+                    size: (c.associativity()
+                        * c.physical_line_partitions()
+                        * c.coherency_line_size()
+                        * c.sets()) as u64,
+                    line_size: c.coherency_line_size() as u32,
+                    ways,
+                    sets: c.sets() as u32,
+                    shared_by: c.max_cores_for_cache() as u32,
+                })
+            })
+            .collect();
+    }
+
+    // older AMD CPUs only report sizes in extended leaves
+    let mut caches = Vec::new();
+    if let Some(l1) = cpuid.get_l1_cache_and_tlb_info() {
+        caches.push(amd_cache(
+            1,
+            "data",
+            l1.dcache_size() as u64 * 1024,
+            l1.dcache_line_size(),
+            l1.dcache_associativity(),
+        ));
+        caches.push(amd_cache(
+            1,
+            "instruction",
+            l1.icache_size() as u64 * 1024,
+            l1.icache_line_size(),
+            l1.icache_associativity(),
+        ));
+    }
+    if let Some(l23) = cpuid.get_l2_l3_cache_and_tlb_info() {
+        caches.push(amd_cache(
+            2,
+            "unified",
+            l23.l2cache_size() as u64 * 1024,
+            l23.l2cache_line_size(),
+            l23.l2cache_associativity(),
+        ));
+        caches.push(amd_cache(
+            3,
+            "unified",
+            l23.l3cache_size() as u64 * 512 * 1024,
+            l23.l3cache_line_size(),
+            l23.l3cache_associativity(),
+        ));
+    }
+    caches.retain(|c| c.size > 0);
+    caches
+}
+
+fn ways(associativity: Associativity) -> u32 {
+    match associativity {
+        Associativity::DirectMapped => 1,
+        Associativity::NWay(n) => n as u32,
+        _ => 0,
+    }
+}
+
+fn amd_cache(
+    level: u8,
+    kind: &'static str,
+    size: u64,
+    line_size: u8,
+    associativity: Associativity,
+) -> CacheInfo {
+    let ways = ways(associativity);
+    let sets = match ways * line_size as u32 {
+        0 => 0,
+        way_size => (size / way_size as u64) as u32,
+    };
+    CacheInfo {
+        level,
+        kind,
+        size,
+        line_size: line_size as u32,
+        ways,
+        sets,
+        shared_by: 0,
+    }
+}
+
+fn tlbs() -> Vec<TlbInfo> {
+    let cpuid = cpuid();
+    if let Some(dat) = cpuid.get_deterministic_address_translation_info() {
+        return dat
+            .filter_map(|t| {
+                let kind = match t.cache_type() {
+                    DatType::DataTLB => "data",
+                    DatType::InstructionTLB => "instruction",
+                    DatType::UnifiedTLB => "unified",
+                    DatType::LoadOnly => "load",
+                    DatType::StoreOnly => "store",
+                    _ => return None,
+                };
+                let pages = [
+                    (t.has_4k_entries(), "4K"),
+                    (t.has_2mb_entries(), "2M"),
+                    (t.has_4mb_entries(), "4M"),
+                    (t.has_1gb_entries(), "1G"),
+                ];
+                Some(TlbInfo {
+                    level: t.cache_level(),
+                    kind,
+                    pages: pages.iter().filter(|p| p.0).map(|p| p.1).collect(),
+                    entries: t.ways() as u32 * t.sets(),
+                    ways: if t.is_fully_associative() {
+                        0
+                    } else {
+                        t.ways() as u32
+                    },
+                })
+            })
+            .collect();
+    }
+
+    let mut tlbs = Vec::new();
+    let mut push = |level, kind, pages: &[&'static str], entries: u32, associativity| {
+        if entries > 0 {
+            tlbs.push(TlbInfo {
+                level,
+                kind,
+                pages: pages.to_vec(),
+                entries,
+                ways: ways(associativity),
+            });
+        }
+    };
+    if let Some(l1) = cpuid.get_l1_cache_and_tlb_info() {
+        push(
+            1,
+            "data",
+            &["4K"],
+            l1.dtlb_4k_size() as u32,
+            l1.dtlb_4k_associativity(),
+        );
+        push(
+            1,
+            "instruction",
+            &["4K"],
+            l1.itlb_4k_size() as u32,
+            l1.itlb_4k_associativity(),
+        );
+        push(
+            1,
+            "data",
+            &["2M", "4M"],
+            l1.dtlb_2m_4m_size() as u32,
+            l1.dtlb_2m_4m_associativity(),
+        );
+        push(
+            1,
+            "instruction",
+            &["2M", "4M"],
+            l1.itlb_2m_4m_size() as u32,
+            l1.itlb_2m_4m_associativity(),
+        );
+    }
+    if let Some(l2) = cpuid.get_l2_l3_cache_and_tlb_info() {
+        push(
+            2,
+            "data",
+            &["4K"],
+            l2.dtlb_4k_size() as u32,
+            l2.dtlb_4k_associativity(),
+        );
+        push(
+            2,
+            "instruction",
+            &["4K"],
+            l2.itlb_4k_size() as u32,
+            l2.itlb_4k_associativity(),
+        );
+        push(
+            2,
+            "data",
+            &["2M", "4M"],
+            l2.dtlb_2m_4m_size() as u32,
+            l2.dtlb_2m_4m_associativity(),
+        );
+        push(
+            2,
+            "instruction",
+            &["2M", "4M"],
+            l2.itlb_2m_4m_size() as u32,
+            l2.itlb_2m_4m_associativity(),
+        );
+    }
+    tlbs
+}
+
+fn topology(info: &FeatureInfo) -> TopologyInfo {
+    let mut threads_per_core = 1;
+    let mut logical_per_package = None;
+    if let Some(levels) = cpuid().get_extended_topology_info() {
+        for level in levels {
+            match level.level_type() {
+                TopologyType::SMT => threads_per_core = level.processors() as u32,
+                TopologyType::Core => logical_per_package = Some(level.processors() as u32),
+                _ => {}
+            }
+        }
+    } else if let Some(amd) = cpuid().get_processor_topology_info() {
+        threads_per_core = amd.threads_per_core() as u32;
+    }
+    let logical_per_package = logical_per_package
+        .or_else(|| {
+            cpuid()
+                .get_processor_capacity_feature_info()
+                .map(|c| c.num_phys_threads() as u32)
+        })
+        .unwrap_or(if info.has_htt() {
+            info.max_logical_processor_ids() as u32
+        } else {
+            1
+        })
+        .max(1);
+    let threads_per_core = threads_per_core.max(1);
+    TopologyInfo {
+        threads_per_core,
+        cores_per_package: (logical_per_package / threads_per_core).max(1),
+        logical_per_package,
+    }
+}
+
+fn features(
+    info: &FeatureInfo,
+    extended: Option<&ExtendedFeatures>,
+    processor: Option<&ExtendedProcessorFeatureIdentifiers>,
+) -> Vec<&'static str> {
+    let mut features = [
+        (info.has_fpu(), "fpu"),
+        (info.has_vme(), "vme"),
+        (info.has_de(), "de"),
+        (info.has_pse(), "pse"),
+        (info.has_tsc(), "tsc"),
+        (info.has_msr(), "msr"),
+        (info.has_pae(), "pae"),
+        (info.has_mce(), "mce"),
+        (info.has_cmpxchg8b(), "cx8"),
+        (info.has_apic(), "apic"),
+        (info.has_sysenter_sysexit(), "sep"),
+        (info.has_mtrr(), "mtrr"),
+        (info.has_pge(), "pge"),
+        (info.has_mca(), "mca"),
+        (info.has_cmov(), "cmov"),
+        (info.has_pat(), "pat"),
+        (info.has_pse36(), "pse36"),
+        (info.has_clflush(), "clflush"),
+        (info.has_mmx(), "mmx"),
+        (info.has_fxsave_fxstor(), "fxsr"),
+        (info.has_sse(), "sse"),
+        (info.has_sse2(), "sse2"),
+        (info.has_ss(), "ss"),
+        (info.has_htt(), "ht"),
+        (info.has_tm(), "tm"),
+        (info.has_sse3(), "pni"),
+        (info.has_pclmulqdq(), "pclmulqdq"),
+        (info.has_monitor_mwait(), "monitor"),
+        (info.has_vmx(), "vmx"),
+        (info.has_smx(), "smx"),
+        (info.has_eist(), "est"),
+        (info.has_tm2(), "tm2"),
+        (info.has_ssse3(), "ssse3"),
+        (info.has_fma(), "fma"),
+        (info.has_cmpxchg16b(), "cx16"),
+        (info.has_pdcm(), "pdcm"),
+        (info.has_pcid(), "pcid"),
+        (info.has_sse41(), "sse4_1"),
+        (info.has_sse42(), "sse4_2"),
+        (info.has_x2apic(), "x2apic"),
+        (info.has_movbe(), "movbe"),
+        (info.has_popcnt(), "popcnt"),
+        (info.has_tsc_deadline(), "tsc_deadline_timer"),
+        (info.has_aesni(), "aes"),
+        (info.has_xsave(), "xsave"),
+        (info.has_avx(), "avx"),
+        (info.has_f16c(), "f16c"),
+        (info.has_rdrand(), "rdrand"),
+        (info.has_hypervisor(), "hypervisor"),
+    ]
+    .iter()
+    .filter(|f| f.0)
+    .map(|f| f.1)
+    .collect::<Vec<_>>();
+    if let Some(e) = processor {
+        let flags = [
+            (e.has_syscall_sysret(), "syscall"),
+            (e.has_execute_disable(), "nx"),
+            (e.has_1gib_pages(), "pdpe1gb"),
+            (e.has_rdtscp(), "rdtscp"),
+            (e.has_64bit_mode(), "lm"),
+            (e.has_lahf_sahf(), "lahf_lm"),
+            (e.has_svm(), "svm"),
+            (e.has_lzcnt(), "abm"),
+            (e.has_sse4a(), "sse4a"),
+            (e.has_prefetchw(), "3dnowprefetch"),
+        ];
+        features.extend(flags.iter().filter(|f| f.0).map(|f| f.1));
+    }
+    if let Some(e) = extended {
+        let flags = [
+            (e.has_fsgsbase(), "fsgsbase"),
+            (e.has_tsc_adjust_msr(), "tsc_adjust"),
+            (e.has_bmi1(), "bmi1"),
+            (e.has_hle(), "hle"),
+            (e.has_avx2(), "avx2"),
+            (e.has_smep(), "smep"),
+            (e.has_bmi2(), "bmi2"),
+            (e.has_rep_movsb_stosb(), "erms"),
+            (e.has_invpcid(), "invpcid"),
+            (e.has_rtm(), "rtm"),
+            (e.has_mpx(), "mpx"),
+            (e.has_avx512f(), "avx512f"),
+            (e.has_avx512dq(), "avx512dq"),
+            (e.has_rdseed(), "rdseed"),
+            (e.has_adx(), "adx"),
+            (e.has_smap(), "smap"),
+            (e.has_avx512_ifma(), "avx512ifma"),
+            (e.has_clflushopt(), "clflushopt"),
+            (e.has_clwb(), "clwb"),
+            (e.has_processor_trace(), "intel_pt"),
+            (e.has_avx512pf(), "avx512pf"),
+            (e.has_avx512er(), "avx512er"),
+            (e.has_avx512cd(), "avx512cd"),
+            (e.has_sha(), "sha_ni"),
+            (e.has_avx512bw(), "avx512bw"),
+            (e.has_avx512vl(), "avx512vl"),
+            (e.has_umip(), "umip"),
+            (e.has_pku(), "pku"),
+            (e.has_ospke(), "ospke"),
+            (e.has_waitpkg(), "waitpkg"),
+            (e.has_gfni(), "gfni"),
+            (e.has_vaes(), "vaes"),
+            (e.has_vpclmulqdq(), "vpclmulqdq"),
+            (e.has_avx512vnni(), "avx512_vnni"),
+            (e.has_avx512bitalg(), "avx512_bitalg"),
+            (e.has_avx512vpopcntdq(), "avx512_vpopcntdq"),
+            (e.has_la57(), "la57"),
+            (e.has_rdpid(), "rdpid"),
+            (e.has_sgx(), "sgx"),
+        ];
+        features.extend(flags.iter().filter(|f| f.0).map(|f| f.1));
+    }
+    if let Some(apm) = cpuid().get_advanced_power_mgmt_info() {
+        if apm.has_invariant_tsc() {
+            features.push("constant_tsc");
+        }
+    }
+    features
+}
+
+fn hypervisor() -> Option<HypervisorInfo> {
+    let info = cpuid().get_hypervisor_info()?;
+    let vendor = match info.identify() {
+        Hypervisor::Xen => "xen",
+        Hypervisor::VMware => "vmware",
+        Hypervisor::HyperV => "hyperv",
+        Hypervisor::KVM => "kvm",
+        Hypervisor::QEMU => "qemu",
+        Hypervisor::Bhyve => "bhyve",
+        Hypervisor::QNX => "qnx",
+        Hypervisor::ACRN => "acrn",
+        Hypervisor::Unknown(..) => "unknown",
+    };
+    Some(HypervisorInfo {
+        vendor: vendor.to_string(),
+        tsc_frequency: info.tsc_frequency().unwrap_or(0),
+        apic_frequency: info.apic_frequency().unwrap_or(0),
+    })
+}
@@ -1,11 +1,23 @@
 #![no_std]
 
+extern crate alloc;
+
+mod cpu;
 mod fpu;
+mod info;
+mod security;
 
 use log::info;
-use raw_cpuid::{CpuId, CpuIdReaderNative, FeatureInfo, ProcessorFrequencyInfo};
+use raw_cpuid::{
+    CpuId, CpuIdReaderNative, ExtendedFeatures, ExtendedProcessorFeatureIdentifiers,
+    ExtendedStateInfo, FeatureInfo, MonitorMwaitInfo, PerformanceMonitoringInfo,
+    ProcessorFrequencyInfo, ThermalPowerInfo,
+};
 
+pub use cpu::{cpu_count, cpu_index, register_cpu, set_cpu_id, CpuIndexError, MAX_CPUS};
 pub use fpu::FpuInfo;
+pub use info::{CacheInfo, CpuInfo, HypervisorInfo, TlbInfo, TopologyInfo};
+pub use security::SecurityInfo;
 
 static mut CPUID: Option<CpuId<CpuIdReaderNative>> = None;
 
@@ -16,6 +28,10 @@ pub fn load() {
     info!("CPUID set");
 }
 
+pub(crate) fn cpuid() -> &'static CpuId<CpuIdReaderNative> {
+    unsafe { CPUID.as_ref().expect("CPUID should be loaded first") }
+}
+
 pub fn get_feature_info() -> FeatureInfo {
     unsafe {
         CPUID
@@ -34,3 +50,55 @@ pub fn get_processor_frequency_info() -> Option<ProcessorFrequencyInfo> {
             .get_processor_frequency_info()
     }
 }
+
+pub fn get_monitor_mwait_info() -> Option<MonitorMwaitInfo> {
+    unsafe {
+        CPUID
+            .as_ref()
+            .expect("CPUID should be loaded first")
+            .get_monitor_mwait_info()
+    }
+}
+
+pub fn get_thermal_power_info() -> Option<ThermalPowerInfo> {
+    unsafe {
+        CPUID
+            .as_ref()
+            .expect("CPUID should be loaded first")
+            .get_thermal_power_info()
+    }
+}
+
+pub fn get_extended_feature_info() -> Option<ExtendedFeatures> {
+    unsafe {
+        CPUID
+            .as_ref()
+            .expect("CPUID should be loaded first")
+            .get_extended_feature_info()
+    }
+}
+
+/// Leaf 0xD. Save area sizes depend on XCR0, so it must be queried again after XCR0 changes.
+pub fn get_extended_state_info() -> Option<ExtendedStateInfo<CpuIdReaderNative>> {
+    unsafe {
+        CPUID
+            .as_ref()
+            .expect("CPUID should be loaded first")
+            .get_extended_state_info()
+    }
+}
+
+pub fn get_extended_processor_and_feature_identifiers(
+) -> Option<ExtendedProcessorFeatureIdentifiers> {
+    unsafe {
+        CPUID
+            .as_ref()
+            .expect("CPUID should be loaded first")
+            .get_extended_processor_and_feature_identifiers()
+    }
+}
+
+/// Leaf 0xA, architectural performance monitoring
+pub fn get_performance_monitoring_info() -> Option<PerformanceMonitoringInfo> {
+    cpuid().get_performance_monitoring_info()
+}
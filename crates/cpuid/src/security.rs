@@ -0,0 +1,31 @@
+use crate::{get_extended_feature_info, get_extended_processor_and_feature_identifiers};
+use raw_cpuid::{ExtendedFeatures, ExtendedProcessorFeatureIdentifiers};
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct SecurityInfo {
+    pub smep: bool,
+    pub smap: bool,
+    pub umip: bool,
+    pub nx: bool,
+}
+
+impl SecurityInfo {
+    fn from_feature_info(
+        extended: Option<&ExtendedFeatures>,
+        processor: Option<&ExtendedProcessorFeatureIdentifiers>,
+    ) -> Self {
+        SecurityInfo {
+            smep: extended.map_or(false, |e| e.has_smep()),
+            smap: extended.map_or(false, |e| e.has_smap()),
+            umip: extended.map_or(false, |e| e.has_umip()),
+            nx: processor.map_or(false, |p| p.has_execute_disable()),
+        }
+    }
+
+    pub fn load() -> SecurityInfo {
+        Self::from_feature_info(
+            get_extended_feature_info().as_ref(),
+            get_extended_processor_and_feature_identifiers().as_ref(),
+        )
+    }
+}
@@ -0,0 +1,124 @@
+//! Lazy FPU context switching.
+//!
+//! FPU registers are not saved when the scheduler switches tasks. Instead CR0.TS is set, and the
+//! first FPU or SSE instruction of the new task raises #NM. Its handler saves the registers into
+//! the state of the task that owns them and loads the state of the current task. Tasks that never
+//! touch the FPU never pay for the switch.
+use crate::{enabled, FpuState};
+use core::arch::asm;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use distros_cpuid::{cpu_index, MAX_CPUS};
+use x86_64::registers::control::{Cr0, Cr0Flags};
+
+struct CpuFpu {
+    /// State the FPU registers belong to
+    owner: AtomicPtr<FpuState>,
+    /// State of the task that runs on the CPU
+    current: AtomicPtr<FpuState>,
+    switches: AtomicU64,
+    traps: AtomicU64,
+    saves: AtomicU64,
+}
+
+impl CpuFpu {
+    const fn new() -> Self {
+        CpuFpu {
+            owner: AtomicPtr::new(ptr::null_mut()),
+            current: AtomicPtr::new(ptr::null_mut()),
+            switches: AtomicU64::new(0),
+            traps: AtomicU64::new(0),
+            saves: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Lazy switching statistics of a CPU, see [`stats`]
+#[derive(Copy, Clone, Debug)]
+pub struct FpuStats {
+    /// Task switches
+    pub switches: u64,
+    /// #NM exceptions, each one is a switch after which the new task used the FPU
+    pub traps: u64,
+    /// Registers saved into the state of their previous owner
+    pub saves: u64,
+}
+
+static CPUS: [CpuFpu; MAX_CPUS] = [const { CpuFpu::new() }; MAX_CPUS];
+
+fn cpu() -> &'static CpuFpu {
+    &CPUS[cpu_index()]
+}
+
+/// Makes `state` the FPU state of the task that runs on current CPU. Registers are loaded on the
+/// first FPU instruction of the task.
+///
+/// Must be called with interrupts disabled. `state` must stay in place until it is passed to
+/// [`release`].
+pub unsafe fn switch_to(state: *mut FpuState) {
+    if !enabled() {
+        return;
+    }
+    let cpu = cpu();
+    cpu.current.store(state, Ordering::Relaxed);
+    cpu.switches.fetch_add(1, Ordering::Relaxed);
+    if cpu.owner.load(Ordering::Relaxed) == state {
+        asm!("clts", options(nomem, nostack));
+    } else {
+        Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED));
+    }
+}
+
+/// Handles #NM exception. Returns `false` if it was not caused by lazy switching.
+pub fn device_not_available() -> bool {
+    if !enabled() {
+        return false;
+    }
+    let cpu = cpu();
+    unsafe {
+        asm!("clts", options(nomem, nostack));
+    }
+    cpu.traps.fetch_add(1, Ordering::Relaxed);
+    let current = cpu.current.load(Ordering::Relaxed);
+    let owner = cpu.owner.swap(current, Ordering::Relaxed);
+    if owner == current {
+        return true;
+    }
+    unsafe {
+        if let Some(owner) = owner.as_mut() {
+            owner.save();
+            cpu.saves.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(current) = current.as_ref() {
+            current.restore();
+        }
+    }
+    true
+}
+
+/// Forgets `state` before it is freed, registers it owns are dropped
+pub fn release(state: *mut FpuState) {
+    for cpu in CPUS.iter() {
+        let _ = cpu.owner.compare_exchange(
+            state,
+            ptr::null_mut(),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        let _ = cpu.current.compare_exchange(
+            state,
+            ptr::null_mut(),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+pub fn stats(cpu: usize) -> Option<FpuStats> {
+    let fpu = CPUS.get(cpu)?;
+    Some(FpuStats {
+        switches: fpu.switches.load(Ordering::Relaxed),
+        traps: fpu.traps.load(Ordering::Relaxed),
+        saves: fpu.saves.load(Ordering::Relaxed),
+    })
+}
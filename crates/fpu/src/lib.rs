@@ -1,19 +1,39 @@
 #![no_std]
 
+extern crate alloc;
+
+mod lazy;
+
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
 use core::arch::asm;
+use core::ptr;
 use distros_cpuid::FpuInfo;
 use log::{debug, info, warn};
 use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
 use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
 
-#[derive(Copy, Clone, Eq, PartialEq)]
+pub use lazy::{device_not_available, release, stats, switch_to, FpuStats};
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
 enum SaveState {
     None,
     Fxsave,
     Xsave,
+    /// XSAVE that skips components not modified since the last XRSTOR from the same area
+    Xsaveopt,
+    /// Compacted format, also skips unmodified and initial components
+    Xsaves,
 }
 
+/// Size of the legacy FXSAVE area
+const FXSAVE_SIZE: usize = 512;
+const IA32_XSS: u32 = 0xDA0;
+
 static mut SAVE_STATE: SaveState = SaveState::None;
+static mut AREA_SIZE: usize = 0;
+/// State right after `fninit`, new tasks start with it
+static mut INITIAL_STATE: Option<FpuState> = None;
 
 fn init_sse(info: &FpuInfo) -> bool {
     if !info.sse {
@@ -34,25 +54,47 @@ fn init_sse(info: &FpuInfo) -> bool {
     true
 }
 
-fn init_avx(info: &FpuInfo) -> bool {
-    if !info.avx {
-        warn!("CPU does not have AVX support");
-        return false;
-    }
+/// Enables XSAVE with every supported user state component. Returns save instruction and size of
+/// the save area.
+fn init_xsave(info: &FpuInfo) -> Option<(SaveState, usize)> {
     if !info.xsave {
-        warn!("CPU does not hve xsave support");
-        return false;
+        warn!("CPU does not have xsave support");
+        return None;
+    }
+    let xstate = distros_cpuid::get_extended_state_info()?;
+    let mut flags = XCr0Flags::X87 | XCr0Flags::SSE;
+    if info.avx && xstate.xcr0_supports_avx_256() {
+        flags |= XCr0Flags::AVX;
+        let avx512 = xstate.xcr0_supports_avx512_opmask()
+            && xstate.xcr0_supports_avx512_zmm_hi256()
+            && xstate.xcr0_supports_avx512_zmm_hi16();
+        if info.avx512f && avx512 {
+            flags |= XCr0Flags::OPMASK | XCr0Flags::ZMM_HI256 | XCr0Flags::HI16_ZMM;
+        }
+    } else {
+        warn!("CPU does not have AVX support");
     }
-
     unsafe {
-        let mut flags = XCr0::read();
-        flags.set(XCr0Flags::AVX, true);
-        flags.set(XCr0Flags::SSE, true);
-        flags.set(XCr0Flags::X87, true);
+        Cr4::update(|flags| flags.set(Cr4Flags::OSXSAVE, true));
         XCr0::write(flags);
     }
-    info!("AVX enabled");
-    true
+
+    // sizes reported by CPUID depend on enabled components
+    let xstate = distros_cpuid::get_extended_state_info()?;
+    let (state, size) = if xstate.has_xsaves_xrstors() {
+        // supervisor components are not used, IA32_XSS keeps them out of the area
+        unsafe { Msr::new(IA32_XSS).write(0) };
+        (SaveState::Xsaves, xstate.xsave_size())
+    } else if xstate.has_xsaveopt() {
+        (
+            SaveState::Xsaveopt,
+            xstate.xsave_area_size_enabled_features(),
+        )
+    } else {
+        (SaveState::Xsave, xstate.xsave_area_size_enabled_features())
+    };
+    info!("XSAVE enabled: {:?}, {:?}, {} bytes", flags, state, size);
+    Some((state, size as usize))
 }
 
 fn check_fpu(info: &FpuInfo) -> bool {
@@ -84,86 +126,123 @@ pub fn init() {
     let info = FpuInfo::load();
     debug!("CPU info: {:?}", &info);
     if init_sse(&info) && check_fpu(&info) {
-        let state = if init_avx(&info) {
-            SaveState::Xsave
-        } else {
-            SaveState::Fxsave
-        };
-        unsafe { SAVE_STATE = state }
+        let (state, size) = init_xsave(&info).unwrap_or((SaveState::Fxsave, FXSAVE_SIZE));
+        unsafe {
+            SAVE_STATE = state;
+            AREA_SIZE = size;
+            let mut initial = FpuState::zeroed();
+            initial.save();
+            INITIAL_STATE = Some(initial);
+        }
     }
 }
 
-#[repr(C, align(64))]
-#[derive(Clone)]
+fn enabled() -> bool {
+    unsafe { SAVE_STATE != SaveState::None }
+}
+
+/// Size of the FPU save area in bytes
+pub fn area_size() -> usize {
+    unsafe { AREA_SIZE }
+}
+
+/// Save area of FPU, SSE and enabled XSAVE components. Its size is known only after [`init`].
 pub struct FpuState {
-    data: [u8; 2584],
+    /// 64-byte aligned area of [`area_size`] bytes, null if the FPU is not enabled
+    area: *mut u8,
 }
 
+unsafe impl Send for FpuState {}
+unsafe impl Sync for FpuState {}
+
 impl FpuState {
-    pub const fn new() -> Self {
-        FpuState { data: [0u8; 2584] }
+    fn layout() -> Layout {
+        Layout::from_size_align(area_size(), 64).expect("Invalid FPU area layout")
+    }
+
+    fn zeroed() -> Self {
+        if area_size() == 0 {
+            return FpuState {
+                area: ptr::null_mut(),
+            };
+        }
+        let layout = Self::layout();
+        let area = unsafe { alloc_zeroed(layout) };
+        if area.is_null() {
+            handle_alloc_error(layout);
+        }
+        FpuState { area }
+    }
+
+    /// Clean state with default control words
+    pub fn initial() -> Self {
+        unsafe {
+            INITIAL_STATE
+                .as_ref()
+                .map(|s| s.clone())
+                .unwrap_or_else(FpuState::zeroed)
+        }
     }
+
     pub unsafe fn save(&mut self) {
         match SAVE_STATE {
-            SaveState::Fxsave => self.fxsave(),
-            SaveState::Xsave => self.xsave(),
+            SaveState::Fxsave => asm!("fxsave64 [{}]", in(reg) self.area),
+            SaveState::Xsave => asm!(
+                "xsave64 [{}]",
+                in(reg) self.area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+            ),
+            SaveState::Xsaveopt => asm!(
+                "xsaveopt64 [{}]",
+                in(reg) self.area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+            ),
+            SaveState::Xsaves => asm!(
+                "xsaves64 [{}]",
+                in(reg) self.area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+            ),
             SaveState::None => {}
         }
     }
 
     pub unsafe fn restore(&self) {
         match SAVE_STATE {
-            SaveState::Fxsave => self.fxrstor(),
-            SaveState::Xsave => self.xrstor(),
+            SaveState::Fxsave => asm!("fxrstor64 [{}]", in(reg) self.area),
+            SaveState::Xsave | SaveState::Xsaveopt => asm!(
+                "xrstor64 [{}]",
+                in(reg) self.area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+            ),
+            SaveState::Xsaves => asm!(
+                "xrstors64 [{}]",
+                in(reg) self.area,
+                in("eax") u32::MAX,
+                in("edx") u32::MAX,
+            ),
             SaveState::None => {}
         }
     }
+}
 
-    #[inline(never)]
-    unsafe fn fxsave(&mut self) {
-        asm!(
-        "mov eax, ~0",
-        "mov edx, ~0",
-        "fxsave [{}]",
-        in(reg) self,
-        out("eax") _,
-        out("edx") _,
-        )
-    }
-
-    #[inline(never)]
-    unsafe fn xsave(&mut self) {
-        asm!(
-        "mov eax, ~0",
-        "mov edx, ~0",
-        "xsave [{}]",
-        in(reg) self,
-        out("eax") _,
-        out("edx") _,
-        )
-    }
-
-    #[inline(never)]
-    unsafe fn xrstor(&self) {
-        asm!(
-        "mov eax, ~0",
-        "mov edx, ~0",
-        "xrstor [{}]",
-        in(reg) self,
-        out("eax") _,
-        out("edx") _,
-        )
+impl Clone for FpuState {
+    fn clone(&self) -> Self {
+        let state = FpuState::zeroed();
+        if !self.area.is_null() {
+            unsafe { ptr::copy_nonoverlapping(self.area, state.area, area_size()) };
+        }
+        state
     }
+}
 
-    #[inline(never)]
-    unsafe fn fxrstor(&self) {
-        asm!(
-        "mov eax, ~0",
-        "mov edx, ~0",
-        "fxrstor [{}]",
-        in(reg) self,
-        out("eax") _,
-        out("edx") _,
-        )
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        if !self.area.is_null() {
+            unsafe { dealloc(self.area, Self::layout()) };
+        }
     }
 }
@@ -0,0 +1,125 @@
+//! Inter-processor interrupts. CPUs are addressed by APIC id, see [`crate::lapic_id`].
+use crate::lapic::{self, DELIVERY_MODE_NMI};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use distros_interrupt::{InterruptId, IrqContext, IrqReturn};
+use log::debug;
+use spin::{Mutex, RwLock};
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_SHORTHAND_SELF: u32 = 0b01 << 18;
+const ICR_SHORTHAND_ALL: u32 = 0b10 << 18;
+const ICR_SHORTHAND_ALL_BUT_SELF: u32 = 0b11 << 18;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum IpiTarget {
+    /// CPU with APIC id
+    Cpu(u32),
+    Current,
+    All,
+    AllButCurrent,
+}
+
+impl IpiTarget {
+    fn destination(self) -> (u32, u32) {
+        match self {
+            IpiTarget::Cpu(id) => (id, 0),
+            IpiTarget::Current => (0, ICR_SHORTHAND_SELF),
+            IpiTarget::All => (0, ICR_SHORTHAND_ALL),
+            IpiTarget::AllButCurrent => (0, ICR_SHORTHAND_ALL_BUT_SELF),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum IpiError {
+    /// CPU did not initialize its LAPIC, so it can't run calls
+    CpuOffline { cpu: u32 },
+}
+
+struct Call {
+    func: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+    done: AtomicBool,
+}
+
+static mut CALL_VECTOR: Option<InterruptId> = None;
+/// CPUs that can run calls
+static ONLINE: RwLock<BTreeSet<u32>> = RwLock::new(BTreeSet::new());
+/// Pending calls, by APIC id
+static CALLS: Mutex<BTreeMap<u32, Vec<Arc<Call>>>> = Mutex::new(BTreeMap::new());
+
+/// Allocates the call vector and marks current CPU online
+pub(crate) fn init() {
+    unsafe {
+        if CALL_VECTOR.is_none() {
+            let vector = distros_interrupt::alloc_vector().expect("No free vector for IPI calls");
+            distros_interrupt::register_handler(vector, "ipi-call", run_calls)
+                .expect("Failed to register IPI call handler");
+            debug!("IPI calls use vector {}", vector.int());
+            CALL_VECTOR = Some(vector);
+        }
+    }
+    ONLINE.write().insert(lapic::id());
+}
+
+/// Sends fixed interrupt `vector` to `target`
+pub fn send_ipi(target: IpiTarget, vector: InterruptId) {
+    let (destination, shorthand) = target.destination();
+    lapic::write_icr(destination, ICR_ASSERT | shorthand | vector.int() as u32);
+}
+
+/// Sends NMI to `target`
+pub fn send_nmi(target: IpiTarget) {
+    let (destination, shorthand) = target.destination();
+    lapic::write_icr(destination, ICR_ASSERT | shorthand | DELIVERY_MODE_NMI);
+}
+
+/// Runs `func` on `cpu` in interrupt context and waits for the result. Runs it in place if `cpu` is
+/// the current CPU. Must be called with interrupts enabled: two CPUs waiting for each other with
+/// interrupts disabled would deadlock.
+pub fn on_cpu<R: Send + 'static>(
+    cpu: u32,
+    func: impl FnOnce() -> R + Send + 'static,
+) -> Result<R, IpiError> {
+    if cpu == lapic::id() {
+        return Ok(without_interrupts(func));
+    }
+    if !ONLINE.read().contains(&cpu) {
+        return Err(IpiError::CpuOffline { cpu });
+    }
+    debug_assert!(interrupts::are_enabled());
+    let result = Arc::new(Mutex::new(None));
+    let slot = result.clone();
+    let call = Arc::new(Call {
+        func: Mutex::new(Some(Box::new(move || *slot.lock() = Some(func())))),
+        done: AtomicBool::new(false),
+    });
+    let vector = unsafe { CALL_VECTOR.expect("IPI is not initialized") };
+    without_interrupts(|| {
+        CALLS.lock().entry(cpu).or_default().push(call.clone());
+        send_ipi(IpiTarget::Cpu(cpu), vector);
+    });
+    while !call.done.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    let result = result.lock().take();
+    Ok(result.expect("IPI call finished without result"))
+}
+
+fn run_calls(_: &IrqContext) -> IrqReturn {
+    let calls = CALLS.lock().remove(&lapic::id()).unwrap_or_default();
+    if calls.is_empty() {
+        return IrqReturn::Unhandled;
+    }
+    for call in calls {
+        if let Some(func) = call.func.lock().take() {
+            func();
+        }
+        call.done.store(true, Ordering::Release);
+    }
+    IrqReturn::Handled
+}
@@ -0,0 +1,147 @@
+//! IRQ lines with shareable handlers, built on [`distros_interrupt::register_handler`]. Lines are
+//! routed through IOAPIC, or through PIC8259 on machines without APIC.
+use crate::ioapic::{self, IoApicError};
+use crate::{controller, pic8259, InterruptController, Irq, IrqDestination, IrqId, IrqMode};
+use acpi::platform::interrupt::TriggerMode;
+use alloc::collections::BTreeMap;
+use distros_interrupt::{
+    DispatchError, HandlerId, InterruptId, IrqContext, IrqReturn, OverrideMode,
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::HandlerFunc;
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum IrqError {
+    IoApic(IoApicError),
+    Dispatch(DispatchError),
+    /// Line is already used with another configuration, or it is not level-triggered
+    NotShareable,
+    /// PIC8259 has only ISA lines
+    NoSuchLine {
+        gsi: IrqId,
+    },
+}
+
+/// Handler registered with [`request_irq`]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct IrqHandle {
+    irq: Irq,
+    handler: HandlerId,
+}
+
+impl IrqHandle {
+    pub fn vector(&self) -> InterruptId {
+        self.handler.vector()
+    }
+}
+
+struct Line {
+    irq: Irq,
+    vector: InterruptId,
+    handlers: usize,
+}
+
+/// Lines with handlers, by global system interrupt
+static LINES: Mutex<BTreeMap<u32, Line>> = Mutex::new(BTreeMap::new());
+
+/// Adds `handler` to line `irq` and enables the line. Only level-triggered lines with the
+/// same polarity can be shared, as edge-triggered interrupts of different devices would get lost.
+pub fn request_irq(
+    irq: Irq,
+    name: &'static str,
+    handler: impl Fn(&IrqContext) -> IrqReturn + Send + Sync + 'static,
+) -> Result<IrqHandle, IrqError> {
+    let gsi = irq.get_global_system_interrupt();
+    let mut lines = LINES.lock();
+    let vector = match lines.get(&gsi.id()) {
+        Some(line) if line.handlers == 0 => line.vector,
+        Some(line) if line.irq == irq && irq.get_trigger_mode() == TriggerMode::Level => {
+            line.vector
+        }
+        Some(_) => return Err(IrqError::NotShareable),
+        None => match controller() {
+            InterruptController::Apic => distros_interrupt::alloc_vector()
+                .ok_or(IrqError::Dispatch(DispatchError::NoFreeVector))?,
+            InterruptController::Pic8259 => pic8259::vector(pic_line(irq)?),
+        },
+    };
+    let handler =
+        distros_interrupt::register_handler(vector, name, handler).map_err(IrqError::Dispatch)?;
+    let line = lines.entry(gsi.id()).or_insert(Line {
+        irq,
+        vector,
+        handlers: 0,
+    });
+    line.handlers += 1;
+    if line.handlers == 1 {
+        line.irq = irq;
+        if let Err(e) = route(irq, vector) {
+            line.handlers = 0;
+            distros_interrupt::unregister_handler(handler);
+            return Err(e);
+        }
+    }
+    Ok(IrqHandle { irq, handler })
+}
+
+/// Removes handler. The line is disabled when its last handler is removed, its vector is kept for
+/// the next [`request_irq`] of the line.
+pub fn free_irq(handle: IrqHandle) {
+    let gsi = handle.irq.get_global_system_interrupt();
+    let mut lines = LINES.lock();
+    if distros_interrupt::unregister_handler(handle.handler).is_none() {
+        return;
+    }
+    if let Some(line) = lines.get_mut(&gsi.id()) {
+        line.handlers -= 1;
+        if line.handlers == 0 {
+            match controller() {
+                InterruptController::Apic => {
+                    let _ = ioapic::disable(gsi);
+                }
+                InterruptController::Pic8259 => pic8259::mask(gsi.id() as u8),
+            }
+        }
+    }
+}
+
+/// Routes line `irq` to a raw `handler`, which has to acknowledge interrupts with
+/// [`crate::eoi`]. The line can't be shared with other handlers.
+pub fn set_irq_handler(irq: Irq, handler: HandlerFunc) -> Result<InterruptId, IrqError> {
+    let vector = match controller() {
+        InterruptController::Apic => distros_interrupt::alloc_handler(handler)
+            .ok_or(IrqError::Dispatch(DispatchError::NoFreeVector))?,
+        InterruptController::Pic8259 => {
+            let vector = pic8259::vector(pic_line(irq)?);
+            distros_interrupt::set_handler(vector, handler, OverrideMode::Override);
+            vector
+        }
+    };
+    route(irq, vector)?;
+    Ok(vector)
+}
+
+fn pic_line(irq: Irq) -> Result<u8, IrqError> {
+    let gsi = irq.get_global_system_interrupt();
+    if gsi.id() < 16 {
+        Ok(gsi.id() as u8)
+    } else {
+        Err(IrqError::NoSuchLine { gsi })
+    }
+}
+
+/// Delivers line `irq` to `vector` and enables it
+fn route(irq: Irq, vector: InterruptId) -> Result<(), IrqError> {
+    match controller() {
+        InterruptController::Apic => without_interrupts(|| {
+            ioapic::set_entry(irq, IrqDestination::Local, vector, IrqMode::Fixed)?;
+            ioapic::enable(irq.get_global_system_interrupt())
+        })
+        .map_err(IrqError::IoApic),
+        InterruptController::Pic8259 => {
+            pic8259::unmask(pic_line(irq)?);
+            Ok(())
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use distros_interrupt::OverrideMode;
-use distros_interrupt::{int_handler, InterruptId};
+use distros_interrupt::{int_handler, InterruptId, IrqAccounting};
 use log::{error, info};
-use x2apic::lapic::{LocalApic, LocalApicBuilder, TimerDivide, TimerMode};
+use x2apic::lapic::{TimerDivide, TimerMode};
 use x86_64::registers::model_specific::Msr;
 use x86_64::structures::idt::InterruptStackFrame;
 use x86_64::VirtAddr;
@@ -12,53 +12,182 @@ const INT_LAPIC_SPURIOUS: InterruptId = InterruptId::new(0xFF);
 
 static mut LAPIC: Option<LocalApic> = None;
 const IA32_TSC_DEADLINE_MSR: Msr = Msr::new(0x6E0);
+const IA32_APIC_BASE_MSR: Msr = Msr::new(0x1B);
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_XAPIC_ENABLE: u64 = 1 << 11;
 
+const REG_ID: u32 = 0x20;
+const REG_TPR: u32 = 0x80;
+const REG_EOI: u32 = 0xB0;
+const REG_SVR: u32 = 0xF0;
+const REG_ESR: u32 = 0x280;
+const REG_ICR_LOW: u32 = 0x300;
+const REG_ICR_HIGH: u32 = 0x310;
+const REG_LVT_TIMER: u32 = 0x320;
+const REG_LVT_PERFORMANCE_COUNTER: u32 = 0x340;
+const REG_LVT_ERROR: u32 = 0x370;
+const REG_TIMER_INITIAL: u32 = 0x380;
+const REG_TIMER_CURRENT: u32 = 0x390;
+const REG_TIMER_DIVIDE: u32 = 0x3E0;
+/// Whole 64-bit ICR in x2APIC mode
+const MSR_X2APIC_ICR: u32 = 0x830;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_MODE_SHIFT: u32 = 17;
+const LVT_TIMER_MODE_MASK: u32 = 0b11 << LVT_TIMER_MODE_SHIFT;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+pub(crate) const DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum LapicMode {
+    /// Registers are mapped to memory, 8-bit APIC ids
+    XApic,
+    /// Registers are MSRs, 32-bit APIC ids
+    X2Apic,
+}
+
+struct LocalApic {
+    mode: LapicMode,
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    /// Reads register at `offset` of the xAPIC MMIO page
+    unsafe fn read(&self, offset: u32) -> u32 {
+        match self.mode {
+            LapicMode::X2Apic => Msr::new(0x800 + (offset >> 4)).read() as u32,
+            LapicMode::XApic => ((self.base + offset as u64).as_ptr::<u32>()).read_volatile(),
+        }
+    }
+
+    /// Writes register at `offset` of the xAPIC MMIO page
+    unsafe fn write(&self, offset: u32, value: u32) {
+        match self.mode {
+            LapicMode::X2Apic => Msr::new(0x800 + (offset >> 4)).write(value as u64),
+            LapicMode::XApic => {
+                ((self.base + offset as u64).as_mut_ptr::<u32>()).write_volatile(value)
+            }
+        }
+    }
+
+    unsafe fn id(&self) -> u32 {
+        match self.mode {
+            LapicMode::X2Apic => self.read(REG_ID),
+            LapicMode::XApic => self.read(REG_ID) >> 24,
+        }
+    }
+
+    /// Sends an interrupt command. `command` is the low half of ICR.
+    unsafe fn write_icr(&self, destination: u32, command: u32) {
+        match self.mode {
+            LapicMode::X2Apic => {
+                Msr::new(MSR_X2APIC_ICR).write(((destination as u64) << 32) | command as u64)
+            }
+            LapicMode::XApic => {
+                self.wait_icr();
+                self.write(REG_ICR_HIGH, destination << 24);
+                self.write(REG_ICR_LOW, command);
+                self.wait_icr();
+            }
+        }
+    }
+
+    unsafe fn wait_icr(&self) {
+        while self.read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    unsafe fn error_flags(&self) -> u32 {
+        // ESR is latched on write
+        self.write(REG_ESR, 0);
+        self.read(REG_ESR)
+    }
+}
+
+/// Switches LAPIC into x2APIC mode if CPU supports it, otherwise uses xAPIC registers mapped at
+/// `address`
 pub fn init_lapic(address: VirtAddr) {
     unsafe {
         distros_interrupt::set_handler(INT_LAPIC_ERROR, lapic_error, OverrideMode::Panic);
         distros_interrupt::set_handler(INT_LAPIC_SPURIOUS, lapic_suprous, OverrideMode::Panic);
-        let mut apic = LocalApicBuilder::new()
-            .timer_vector(INT_LAPIC_TIMER.int() as usize)
-            .error_vector(INT_LAPIC_ERROR.int() as usize)
-            .spurious_vector(INT_LAPIC_SPURIOUS.int() as usize)
-            .set_xapic_base(address.as_u64())
-            .build()
-            .expect("Failed to get Local APIC");
-        apic.enable();
-        apic.disable_timer();
-        info!("LAPIC {} at 0x{:08x} enabled", apic.id(), address);
+        let base = IA32_APIC_BASE_MSR.read();
+        // x2APIC can't be left without a reset, so keep it if firmware enabled it
+        let mode = if distros_cpuid::get_feature_info().has_x2apic()
+            || base & APIC_BASE_X2APIC_ENABLE != 0
+        {
+            LapicMode::X2Apic
+        } else {
+            LapicMode::XApic
+        };
+        // xAPIC has to be enabled before switching to x2APIC
+        IA32_APIC_BASE_MSR.write(base | APIC_BASE_XAPIC_ENABLE);
+        if mode == LapicMode::X2Apic {
+            IA32_APIC_BASE_MSR.write(base | APIC_BASE_XAPIC_ENABLE | APIC_BASE_X2APIC_ENABLE);
+        }
+        let apic = LocalApic {
+            mode,
+            base: address,
+        };
+        apic.write(REG_SVR, SVR_ENABLE | INT_LAPIC_SPURIOUS.int() as u32);
+        apic.write(REG_LVT_ERROR, INT_LAPIC_ERROR.int() as u32);
+        apic.error_flags();
+        apic.write(REG_TPR, 0);
+        apic.write(REG_LVT_TIMER, LVT_MASKED | INT_LAPIC_TIMER.int() as u32);
+        match mode {
+            LapicMode::X2Apic => info!("LAPIC {} enabled in x2APIC mode", apic.id()),
+            LapicMode::XApic => info!("LAPIC {} at 0x{:08x} enabled", apic.id(), address),
+        }
         LAPIC = Some(apic);
+        distros_interrupt::set_eoi(eoi);
     }
 }
 
+#[inline]
+fn lapic() -> &'static LocalApic {
+    unsafe { LAPIC.as_ref().expect("Local APIC is not initialized") }
+}
+
+pub fn mode() -> LapicMode {
+    lapic().mode
+}
+
+/// APIC id of current CPU. Ids can be wider than 8 bits in x2APIC mode.
+pub fn id() -> u32 {
+    unsafe { lapic().id() }
+}
+
 pub fn eoi() {
-    unsafe {
-        LAPIC
-            .as_mut()
-            .expect("Local APIC is not initialized")
-            .end_of_interrupt();
-    }
+    unsafe { lapic().write(REG_EOI, 0) }
 }
 
 pub fn timer_set_mode(mode: TimerMode, timer_divide: TimerDivide) {
     unsafe {
-        let lapic = LAPIC.as_mut().expect("Local APIC is not initialized");
-        lapic.set_timer_mode(mode);
-        lapic.set_timer_divide(timer_divide);
+        let lapic = lapic();
+        let lvt = lapic.read(REG_LVT_TIMER) & !LVT_TIMER_MODE_MASK;
+        lapic.write(REG_LVT_TIMER, lvt | ((mode as u32) << LVT_TIMER_MODE_SHIFT));
+        lapic.write(REG_TIMER_DIVIDE, timer_divide as u32);
     }
 }
 
 pub fn timer_add_initial(initial: u32) {
     unsafe {
-        let lapic = LAPIC.as_mut().expect("Local APIC is not initialized");
-        lapic.set_timer_initial(
+        let lapic = lapic();
+        let current = lapic.read(REG_TIMER_CURRENT);
+        lapic.write(
+            REG_TIMER_INITIAL,
             initial
-                .checked_add(lapic.timer_current())
-                .unwrap_or_else(|| lapic.timer_current() - initial),
+                .checked_add(current)
+                .unwrap_or_else(|| current - initial),
         );
     }
 }
 
+pub fn timer_set_initial(initial: u32) {
+    unsafe { lapic().write(REG_TIMER_INITIAL, initial) }
+}
+
 pub fn timer_set_tsc_deadline(deadline: u64) {
     unsafe {
         IA32_TSC_DEADLINE_MSR.write(deadline);
@@ -67,34 +196,45 @@ pub fn timer_set_tsc_deadline(deadline: u64) {
 
 pub fn timer_enable() {
     unsafe {
-        let lapic = LAPIC.as_mut().expect("Local APIC is not initialized");
-        lapic.enable_timer();
+        let lapic = lapic();
+        let lvt = lapic.read(REG_LVT_TIMER);
+        lapic.write(REG_LVT_TIMER, lvt & !LVT_MASKED);
     }
 }
 
 pub fn timer_disable() {
     unsafe {
-        LAPIC
-            .as_mut()
-            .expect("Local APIC is not initialized")
-            .disable_timer();
+        let lapic = lapic();
+        let lvt = lapic.read(REG_LVT_TIMER);
+        lapic.write(REG_LVT_TIMER, lvt | LVT_MASKED);
+    }
+}
+
+/// Writes interrupt command register. `command` is the low half of ICR.
+pub(crate) fn write_icr(destination: u32, command: u32) {
+    unsafe { lapic().write_icr(destination, command) }
+}
+
+/// Delivers performance counter overflows as NMI. LAPIC masks the entry after every delivery, so
+/// it has to be called again from the handler.
+pub fn perf_counter_nmi() {
+    unsafe {
+        lapic().write(REG_LVT_PERFORMANCE_COUNTER, DELIVERY_MODE_NMI);
     }
 }
 
 int_handler!(
     lapic_error | stack_frame: InterruptStackFrame | {
-        let flags = unsafe {
-            LAPIC
-                .as_mut()
-                .expect("Local APIC is not initialized")
-                .error_flags()
-        };
-        error!("EXCEPTION: LAPIC ERROR {:?}\n{:#?}", flags, stack_frame);
+        let _accounting = IrqAccounting::start(INT_LAPIC_ERROR);
+        let flags = unsafe { lapic().error_flags() };
+        error!("EXCEPTION: LAPIC ERROR {:#x}\n{:#?}", flags, stack_frame);
     }
 );
 
 int_handler!(
     lapic_suprous | stack_frame: InterruptStackFrame | {
+        let _accounting = IrqAccounting::start(INT_LAPIC_SPURIOUS);
+        distros_interrupt::count_spurious(INT_LAPIC_SPURIOUS);
         error!("EXCEPTION: LAPIC SUPROUS\n{:#?}", stack_frame);
     }
 );
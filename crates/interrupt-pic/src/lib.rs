@@ -5,23 +5,29 @@
 extern crate alloc;
 
 use acpi::platform::interrupt::{Polarity, TriggerMode};
+use log::warn;
 use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
 use x86_64::{PhysAddr, VirtAddr};
 
 mod ioapic;
+mod ipi;
+mod irq;
 mod isa;
 mod lapic;
 mod pic8259;
 
 pub use ioapic::{
-    disable as ioapic_disable, enable as ioapic_enable, set_entry as ioapic_set_entry,
+    disable as ioapic_disable, enable as ioapic_enable, set_entry as ioapic_set_entry, IoApicError,
 };
+pub use ipi::{on_cpu, send_ipi, send_nmi, IpiError, IpiTarget};
+pub use irq::{free_irq, request_irq, set_irq_handler, IrqError, IrqHandle};
 pub use isa::IsaIrq;
 pub use lapic::{
-    eoi as lapic_eoi, timer_add_initial as lapic_timer_add_initial,
-    timer_disable as lapic_timer_disable, timer_enable as lapic_timer_enable,
+    eoi as lapic_eoi, mode as lapic_mode, perf_counter_nmi as lapic_perf_counter_nmi,
+    timer_add_initial as lapic_timer_add_initial, timer_disable as lapic_timer_disable,
+    timer_enable as lapic_timer_enable, timer_set_initial as lapic_timer_set_initial,
     timer_set_mode as lapic_timer_set_mode, timer_set_tsc_deadline as lapic_timer_set_tsc_deadline,
-    INT_LAPIC_TIMER,
+    LapicMode, INT_LAPIC_TIMER,
 };
 
 pub(crate) const APIC_ADDR_BASE: VirtAddr = VirtAddr::new_truncate(1024 * 1024 * 1024 * 500);
@@ -118,12 +124,48 @@ pub enum IrqMode {
     External = 0b111,
 }
 
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum InterruptController {
+    /// LAPIC with IOAPICs
+    Apic,
+    /// Legacy 8259 pair, only ISA IRQs 0..16 are available
+    Pic8259,
+}
+
+static mut CONTROLLER: Option<InterruptController> = None;
+
+pub fn controller() -> InterruptController {
+    unsafe { CONTROLLER.expect("Interrupt controller is not initialized") }
+}
+
+/// APIC id of the current CPU, `0` on machines without LAPIC
+pub fn lapic_id() -> u32 {
+    match unsafe { CONTROLLER } {
+        Some(InterruptController::Apic) => lapic::id(),
+        _ => 0,
+    }
+}
+
+/// Acknowledges current interrupt. Only raw handlers need it: handlers registered with
+/// [`request_irq`] are acknowledged by the dispatcher.
+pub fn eoi() {
+    match controller() {
+        InterruptController::Apic => lapic::eoi(),
+        InterruptController::Pic8259 => pic8259::eoi(),
+    }
+}
+
 pub fn init() {
+    let apic = match distros_acpi::apic() {
+        Some(apic) if distros_cpuid::get_feature_info().has_apic() => apic,
+        _ => {
+            warn!("APIC is not available, falling back to PIC8259");
+            pic8259::init();
+            unsafe { CONTROLLER = Some(InterruptController::Pic8259) };
+            return;
+        }
+    };
     pic8259::disable();
-    if !distros_cpuid::get_feature_info().has_apic() {
-        panic!("Hardware does not have APIC")
-    }
-    let apic = distros_acpi::apic();
     let addr = PhysAddr::new(apic.local_apic_address);
     distros_memory::map(
         PhysFrame::<Size4KiB>::containing_address(addr),
@@ -135,6 +177,8 @@ pub fn init() {
     )
     .unwrap();
     lapic::init_lapic(APIC_ADDR_BASE);
+    unsafe { CONTROLLER = Some(InterruptController::Apic) };
+    ipi::init();
     isa::setup_overrides(&apic.interrupt_source_overrides);
     ioapic::init(&apic.io_apics)
 }
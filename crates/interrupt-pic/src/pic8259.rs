@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use distros_interrupt::InterruptId;
 use lazy_static::lazy_static;
 use log::info;
 use spin::Mutex;
@@ -5,6 +7,17 @@ use x86_64::instructions::port::Port;
 
 const PIC1: u16 = 0x20;
 const PIC2: u16 = 0xA0;
+/// Vectors of IRQ 0..16. Kept away from the vectors used by LAPIC and the allocator's first picks.
+pub const PIC_OFFSET: u8 = 0xE0;
+/// Slave PIC is connected to this line of the master
+const CASCADE_LINE: u8 = 2;
+
+const ICW1_INIT: u8 = 0x10;
+const ICW1_ICW4: u8 = 0x01;
+const ICW4_8086: u8 = 0x01;
+const OCW2_EOI: u8 = 0x20;
+const OCW3_READ_ISR: u8 = 0x0B;
+
 lazy_static! {
     static ref PIC1_COMMAND: Mutex<Port<u8>> = Mutex::new(Port::new(PIC1));
     static ref PIC2_COMMAND: Mutex<Port<u8>> = Mutex::new(Port::new(PIC2));
@@ -12,32 +25,123 @@ lazy_static! {
     static ref PIC2_DATA: Mutex<Port<u8>> = Mutex::new(Port::new(PIC2 + 1));
 }
 
-fn set_mask(line: u8) {
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+/// Gives the PIC time to process a command on old hardware
+fn io_wait() {
+    unsafe { Port::<u8>::new(0x80).write(0) }
+}
+
+#[inline]
+pub fn vector(line: u8) -> InterruptId {
+    InterruptId::new(PIC_OFFSET + line)
+}
+
+/// Moves IRQs to [`PIC_OFFSET`] and masks all lines but the cascade
+fn remap() {
+    let mut cmd1 = PIC1_COMMAND.lock();
+    let mut cmd2 = PIC2_COMMAND.lock();
+    let mut data1 = PIC1_DATA.lock();
+    let mut data2 = PIC2_DATA.lock();
+    unsafe {
+        cmd1.write(ICW1_INIT | ICW1_ICW4);
+        io_wait();
+        cmd2.write(ICW1_INIT | ICW1_ICW4);
+        io_wait();
+        data1.write(PIC_OFFSET);
+        io_wait();
+        data2.write(PIC_OFFSET + 8);
+        io_wait();
+        data1.write(1 << CASCADE_LINE);
+        io_wait();
+        data2.write(CASCADE_LINE);
+        io_wait();
+        data1.write(ICW4_8086);
+        io_wait();
+        data2.write(ICW4_8086);
+        io_wait();
+        data1.write(!(1 << CASCADE_LINE));
+        data2.write(0xff);
+    }
+}
+
+pub fn init() {
+    remap();
+    for line in 0..16 {
+        distros_interrupt::reserve_vector(vector(line))
+            .expect("Vector of PIC8259 line is already taken");
+    }
+    distros_interrupt::set_eoi(eoi);
+    info!("PIC8259 remapped to 0x{:02x}", PIC_OFFSET);
+}
+
+pub fn mask(line: u8) {
     let mut port = if line < 8 {
         PIC1_DATA.lock()
     } else {
         PIC2_DATA.lock()
     };
-    let line = if line >= 8 { line - 8 } else { line };
     unsafe {
-        let val = port.read() | (1 << line);
+        let val = port.read() | (1 << (line % 8));
         port.write(val)
     }
 }
 
-fn disable_pic() {
-    let mut pic1 = PIC1_DATA.lock();
-    let mut pic2 = PIC2_DATA.lock();
+pub fn unmask(line: u8) {
+    let mut port = if line < 8 {
+        PIC1_DATA.lock()
+    } else {
+        PIC2_DATA.lock()
+    };
     unsafe {
-        pic1.write(0xff);
-        pic2.write(0xff);
+        let val = port.read() & !(1 << (line % 8));
+        port.write(val)
+    }
+}
+
+fn read_isr(command: &mut Port<u8>) -> u8 {
+    unsafe {
+        command.write(OCW3_READ_ISR);
+        command.read()
     }
 }
 
+/// Acknowledges the interrupt in service. Lines in service are found from ISR, as an interrupt with
+/// higher priority can't be in service under the current one. Spurious IRQ 7 and 15 don't set
+/// their ISR bit and are not acknowledged, except for the cascade line of spurious IRQ 15.
+pub fn eoi() {
+    let mut cmd1 = PIC1_COMMAND.lock();
+    let master = read_isr(&mut cmd1);
+    if master == 0 {
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+        distros_interrupt::count_spurious(vector(7));
+        return;
+    }
+    if master.trailing_zeros() == CASCADE_LINE as u32 {
+        let mut cmd2 = PIC2_COMMAND.lock();
+        if read_isr(&mut cmd2) == 0 {
+            SPURIOUS.fetch_add(1, Ordering::Relaxed);
+            distros_interrupt::count_spurious(vector(15));
+        } else {
+            unsafe { cmd2.write(OCW2_EOI) }
+        }
+    }
+    unsafe { cmd1.write(OCW2_EOI) }
+}
+
+/// Number of spurious IRQs
+pub fn spurious() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/// Remaps the PIC out of the exception vectors and masks all lines
 pub fn disable() {
-    for irq in 0..16 {
-        set_mask(irq);
+    remap();
+    let mut pic1 = PIC1_DATA.lock();
+    let mut pic2 = PIC2_DATA.lock();
+    unsafe {
+        pic1.write(0xff);
+        pic2.write(0xff);
     }
-    disable_pic();
     info!("PIC8259 disabled");
 }
@@ -0,0 +1,209 @@
+//! Shared interrupt vectors with closure handlers.
+//!
+//! Every dispatch vector has a common stub that runs all handlers registered on the vector, in
+//! registration order, and then signals EOI. Several devices can share one vector, each handler
+//! reports whether the interrupt came from its device.
+//!
+//! Chains are copied on write, so the stub runs handlers on a snapshot without holding a lock.
+use crate::idt::{alloc_with, install_with};
+use crate::stats::{count_spurious, IrqAccounting};
+use crate::InterruptId;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::RwLock;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::{HandlerFunc, InterruptStackFrame};
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum IrqReturn {
+    /// The interrupt came from the device of the handler
+    Handled,
+    /// Not our interrupt, the next handler on the vector is tried
+    Unhandled,
+}
+
+pub struct IrqContext<'a> {
+    pub vector: InterruptId,
+    pub frame: &'a InterruptStackFrame,
+}
+
+/// Handlers run with interrupts disabled. They can register and unregister handlers, which takes
+/// effect from the next interrupt.
+pub type IrqHandler = Box<dyn Fn(&IrqContext) -> IrqReturn + Send + Sync>;
+
+/// Registered handler, see [`unregister_handler`]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct HandlerId {
+    vector: InterruptId,
+    id: u64,
+}
+
+impl HandlerId {
+    pub fn vector(&self) -> InterruptId {
+        self.vector
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum DispatchError {
+    /// Vector has a raw handler from [`set_handler`](crate::set_handler)
+    VectorTaken,
+    NoFreeVector,
+}
+
+struct Entry {
+    id: u64,
+    name: &'static str,
+    handler: IrqHandler,
+}
+
+static CHAINS: [RwLock<Option<Arc<Vec<Arc<Entry>>>>>; 256] = [const { RwLock::new(None) }; 256];
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+static mut EOI: fn() = || {};
+
+/// Sets function that signals end of interrupt to the interrupt controller
+pub fn set_eoi(fun: fn()) {
+    unsafe {
+        EOI = fun;
+    }
+}
+
+extern "x86-interrupt" fn stub<const V: u8>(frame: InterruptStackFrame) {
+    dispatch(V, &frame);
+}
+
+macro_rules! stub_row {
+    ($base:literal) => {
+        [
+            stub::<{ $base }>,
+            stub::<{ $base + 1 }>,
+            stub::<{ $base + 2 }>,
+            stub::<{ $base + 3 }>,
+            stub::<{ $base + 4 }>,
+            stub::<{ $base + 5 }>,
+            stub::<{ $base + 6 }>,
+            stub::<{ $base + 7 }>,
+            stub::<{ $base + 8 }>,
+            stub::<{ $base + 9 }>,
+            stub::<{ $base + 10 }>,
+            stub::<{ $base + 11 }>,
+            stub::<{ $base + 12 }>,
+            stub::<{ $base + 13 }>,
+            stub::<{ $base + 14 }>,
+            stub::<{ $base + 15 }>,
+        ]
+    };
+}
+
+static STUBS: [[HandlerFunc; 16]; 16] = [
+    stub_row!(0),
+    stub_row!(16),
+    stub_row!(32),
+    stub_row!(48),
+    stub_row!(64),
+    stub_row!(80),
+    stub_row!(96),
+    stub_row!(112),
+    stub_row!(128),
+    stub_row!(144),
+    stub_row!(160),
+    stub_row!(176),
+    stub_row!(192),
+    stub_row!(208),
+    stub_row!(224),
+    stub_row!(240),
+];
+
+fn stub_for(vector: u8) -> HandlerFunc {
+    STUBS[vector as usize / 16][vector as usize % 16]
+}
+
+fn dispatch(vector: u8, frame: &InterruptStackFrame) {
+    let _accounting = IrqAccounting::start(InterruptId::new(vector));
+    let context = IrqContext {
+        vector: InterruptId::new(vector),
+        frame,
+    };
+    let mut handled = false;
+    let chain = CHAINS[vector as usize].read().clone();
+    for entry in chain.iter().flat_map(|chain| chain.iter()) {
+        crate::__trace::irq_enter(entry.name);
+        handled |= (entry.handler)(&context) == IrqReturn::Handled;
+        crate::__trace::irq_exit(entry.name);
+    }
+    if !handled {
+        count_spurious(InterruptId::new(vector));
+    }
+    unsafe { EOI() };
+}
+
+/// Allocates a free vector for dispatch handlers
+pub fn alloc_vector() -> Option<InterruptId> {
+    alloc_with(stub_for, true)
+}
+
+/// Installs the common stub into `vector` without handlers, so that the vector is never allocated.
+/// Interrupts on it are acknowledged and ignored until a handler is registered.
+pub fn reserve_vector(vector: InterruptId) -> Result<(), DispatchError> {
+    if install_with(vector, stub_for) {
+        Ok(())
+    } else {
+        Err(DispatchError::VectorTaken)
+    }
+}
+
+/// Adds `handler` to the chain of `vector`, installing the common stub if the vector is free
+pub fn register_handler(
+    vector: InterruptId,
+    name: &'static str,
+    handler: impl Fn(&IrqContext) -> IrqReturn + Send + Sync + 'static,
+) -> Result<HandlerId, DispatchError> {
+    if !install_with(vector, stub_for) {
+        return Err(DispatchError::VectorTaken);
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let entry = Arc::new(Entry {
+        id,
+        name,
+        handler: Box::new(handler),
+    });
+    without_interrupts(|| {
+        let mut chain = CHAINS[vector.int() as usize].write();
+        let mut entries = chain.as_deref().cloned().unwrap_or_default();
+        entries.push(entry);
+        *chain = Some(Arc::new(entries));
+    });
+    Ok(HandlerId { vector, id })
+}
+
+/// Removes handler from its vector. Returns number of handlers left on the vector, or `None` if
+/// the handler was not registered. The vector stays allocated even if it has no handlers left.
+pub fn unregister_handler(handler: HandlerId) -> Option<usize> {
+    let removed = without_interrupts(|| {
+        let mut chain = CHAINS[handler.vector.int() as usize].write();
+        let mut entries = chain.as_deref().cloned()?;
+        let index = entries.iter().position(|e| e.id == handler.id)?;
+        let entry = entries.remove(index);
+        let left = entries.len();
+        *chain = Some(Arc::new(entries));
+        Some((entry, left))
+    });
+    // handler can own resources that should not be freed with interrupts disabled. If it is
+    // running now, the last snapshot that holds it frees it instead
+    removed.map(|(entry, left)| {
+        drop(entry);
+        left
+    })
+}
+
+/// Names of handlers registered on `vector`
+pub(crate) fn handler_names(vector: InterruptId) -> Vec<&'static str> {
+    let chain = without_interrupts(|| CHAINS[vector.int() as usize].read().clone());
+    chain
+        .iter()
+        .flat_map(|chain| chain.iter())
+        .map(|e| e.name)
+        .collect()
+}
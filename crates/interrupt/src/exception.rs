@@ -0,0 +1,550 @@
+//! CPU exceptions.
+//!
+//! Every exception enters through a stub that saves all general purpose registers into
+//! [`Registers`] and calls [`dispatch`]. An exception is resolved, in order, by an exception table
+//! fixup, by handlers registered with [`register_exception_handler`], or by terminating the faulting
+//! task with the hook from [`set_fault_isolation`]. Anything else panics with a full register dump.
+use crate::stats::IrqAccounting;
+use crate::{gdt, InterruptId};
+use core::arch::naked_asm;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use distros_backtrace::{stack_bounds, Backtrace};
+use log::warn;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode, SelectorErrorCode};
+use x86_64::VirtAddr;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum ExceptionKind {
+    /// Reported before the faulting instruction, returning re-executes it
+    Fault,
+    /// Reported after the instruction, returning continues execution
+    Trap,
+    /// Can't be resumed
+    Abort,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+#[repr(u8)]
+pub enum Exception {
+    DivideError = 0,
+    Debug = 1,
+    Breakpoint = 3,
+    Overflow = 4,
+    BoundRangeExceeded = 5,
+    InvalidOpcode = 6,
+    DoubleFault = 8,
+    InvalidTss = 10,
+    SegmentNotPresent = 11,
+    StackSegmentFault = 12,
+    GeneralProtectionFault = 13,
+    PageFault = 14,
+    X87FloatingPoint = 16,
+    AlignmentCheck = 17,
+    MachineCheck = 18,
+    SimdFloatingPoint = 19,
+    ControlProtection = 21,
+}
+
+impl Exception {
+    pub fn from_vector(vector: u8) -> Option<Exception> {
+        Some(match vector {
+            0 => Exception::DivideError,
+            1 => Exception::Debug,
+            3 => Exception::Breakpoint,
+            4 => Exception::Overflow,
+            5 => Exception::BoundRangeExceeded,
+            6 => Exception::InvalidOpcode,
+            8 => Exception::DoubleFault,
+            10 => Exception::InvalidTss,
+            11 => Exception::SegmentNotPresent,
+            12 => Exception::StackSegmentFault,
+            13 => Exception::GeneralProtectionFault,
+            14 => Exception::PageFault,
+            16 => Exception::X87FloatingPoint,
+            17 => Exception::AlignmentCheck,
+            18 => Exception::MachineCheck,
+            19 => Exception::SimdFloatingPoint,
+            21 => Exception::ControlProtection,
+            _ => return None,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Exception::DivideError => "DIVIDE ERROR",
+            Exception::Debug => "DEBUG",
+            Exception::Breakpoint => "BREAKPOINT",
+            Exception::Overflow => "OVERFLOW",
+            Exception::BoundRangeExceeded => "BOUND RANGE EXCEEDED",
+            Exception::InvalidOpcode => "INVALID OPCODE",
+            Exception::DoubleFault => "DOUBLE FAULT",
+            Exception::InvalidTss => "INVALID TSS",
+            Exception::SegmentNotPresent => "SEGMENT NOT PRESENT",
+            Exception::StackSegmentFault => "STACK SEGMENT FAULT",
+            Exception::GeneralProtectionFault => "GENERAL PROTECTION FAULT",
+            Exception::PageFault => "PAGE FAULT",
+            Exception::X87FloatingPoint => "X87 FLOATING POINT",
+            Exception::AlignmentCheck => "ALIGNMENT CHECK",
+            Exception::MachineCheck => "MACHINE CHECK",
+            Exception::SimdFloatingPoint => "SIMD FLOATING POINT",
+            Exception::ControlProtection => "CONTROL PROTECTION",
+        }
+    }
+
+    pub fn kind(&self) -> ExceptionKind {
+        match self {
+            Exception::Debug | Exception::Breakpoint | Exception::Overflow => ExceptionKind::Trap,
+            Exception::DoubleFault | Exception::MachineCheck => ExceptionKind::Abort,
+            _ => ExceptionKind::Fault,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ErrorCode {
+    None,
+    /// Segment selector that caused the exception, or null
+    Selector(SelectorErrorCode),
+    PageFault(PageFaultErrorCode),
+    Raw(u64),
+}
+
+/// Interrupted state, in the order the entry stub pushes it
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Zero for exceptions without error code
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+pub struct ExceptionContext<'a> {
+    pub exception: Exception,
+    /// Changes are applied when the handler returns
+    pub registers: &'a mut Registers,
+    pub cr0: u64,
+    /// Faulting address of a page fault
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl ExceptionContext<'_> {
+    pub fn error_code(&self) -> ErrorCode {
+        let code = self.registers.error_code;
+        match self.exception {
+            Exception::InvalidTss
+            | Exception::SegmentNotPresent
+            | Exception::StackSegmentFault
+            | Exception::GeneralProtectionFault => {
+                ErrorCode::Selector(SelectorErrorCode::new_truncate(code))
+            }
+            Exception::PageFault => {
+                ErrorCode::PageFault(PageFaultErrorCode::from_bits_truncate(code))
+            }
+            Exception::DoubleFault | Exception::AlignmentCheck | Exception::ControlProtection => {
+                ErrorCode::Raw(code)
+            }
+            _ => ErrorCode::None,
+        }
+    }
+
+    /// Whether the exception was raised by user code
+    #[inline]
+    pub fn from_user(&self) -> bool {
+        self.registers.cs & 0b11 != 0
+    }
+
+    /// Bounds of the stack whose guard page was hit by a page fault or a double fault
+    pub fn stack_overflow(&self) -> Option<(u64, u64)> {
+        if !matches!(
+            self.exception,
+            Exception::PageFault | Exception::DoubleFault
+        ) || stack_bounds(self.cr2).is_some()
+        {
+            return None;
+        }
+        stack_bounds(self.cr2.wrapping_add(4096)).filter(|(start, _)| self.cr2 < *start)
+    }
+
+    /// Whether the interrupted code ran with interrupts enabled, so it did not hold locks shared
+    /// with interrupt handlers
+    #[inline]
+    pub fn interrupts_enabled(&self) -> bool {
+        self.registers.rflags & (1 << 9) != 0
+    }
+}
+
+impl fmt::Display for ExceptionContext<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let r = &self.registers;
+        writeln!(
+            f,
+            "EXCEPTION: {} (vector {}) at 0x{:016x}",
+            self.exception.name(),
+            r.vector,
+            r.rip
+        )?;
+        match self.error_code() {
+            ErrorCode::None => {}
+            ErrorCode::Selector(code) if code.is_null() => writeln!(f, "Error code: 0")?,
+            ErrorCode::Selector(code) => writeln!(
+                f,
+                "Error code: {:#x}, selector {:?}[{}], external: {}",
+                r.error_code,
+                code.descriptor_table(),
+                code.index(),
+                code.external()
+            )?,
+            ErrorCode::PageFault(code) => writeln!(
+                f,
+                "Error code: {:?}, accessed address: 0x{:016x}",
+                code, self.cr2
+            )?,
+            ErrorCode::Raw(code) => writeln!(f, "Error code: {:#x}", code)?,
+        }
+        if let Some((start, end)) = self.stack_overflow() {
+            writeln!(
+                f,
+                "Kernel stack overflow: accessed 0x{:016x} below stack 0x{:016x}-0x{:016x}",
+                self.cr2, start, end
+            )?;
+        }
+        writeln!(
+            f,
+            "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}",
+            r.rax, r.rbx, r.rcx, r.rdx
+        )?;
+        writeln!(
+            f,
+            "RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}",
+            r.rsi, r.rdi, r.rbp, r.rsp
+        )?;
+        writeln!(
+            f,
+            "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}",
+            r.r8, r.r9, r.r10, r.r11
+        )?;
+        writeln!(
+            f,
+            "R12={:016x} R13={:016x} R14={:016x} R15={:016x}",
+            r.r12, r.r13, r.r14, r.r15
+        )?;
+        writeln!(
+            f,
+            "RIP={:016x} RFLAGS={:016x} CS={:04x} SS={:04x}",
+            r.rip, r.rflags, r.cs, r.ss
+        )?;
+        writeln!(
+            f,
+            "CR0={:016x} CR2={:016x} CR3={:016x} CR4={:016x}",
+            self.cr0, self.cr2, self.cr3, self.cr4
+        )?;
+        write!(f, "{}", Backtrace::from_interrupt(r.rip, r.rbp))
+    }
+}
+
+/// Exception handler. Returns `true` if it resolved the exception and execution can continue with
+/// the (possibly modified) registers.
+pub type ExceptionHandler = fn(&mut ExceptionContext) -> bool;
+
+/// Terminates the task that raised `context`, does not return if it did
+pub type FaultIsolation = fn(&ExceptionContext);
+
+const MAX_EXCEPTION_HANDLERS: usize = 4;
+static HANDLERS: [[AtomicUsize; MAX_EXCEPTION_HANDLERS]; 32] =
+    [const { [const { AtomicUsize::new(0) }; MAX_EXCEPTION_HANDLERS] }; 32];
+static FAULT_ISOLATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Adds handler for `exception`. Returns `false` if there are too many handlers for it.
+pub fn register_exception_handler(exception: Exception, handler: ExceptionHandler) -> bool {
+    HANDLERS[exception as usize].iter().any(|slot| {
+        slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    })
+}
+
+/// Sets hook that terminates tasks on faults no handler resolved
+pub fn set_fault_isolation(isolation: FaultIsolation) {
+    FAULT_ISOLATION.store(isolation as usize, Ordering::Release);
+}
+
+/// Entry of the exception table, offsets are relative to the field
+#[repr(C)]
+struct FixupEntry {
+    instruction: i32,
+    fixup: i32,
+}
+
+/// Adds an exception table entry: a page fault or general protection fault on instruction
+/// `$instruction` continues at `$fixup`. Both are labels of the surrounding `asm!`.
+///
+/// ```ignore
+/// asm!("2: mov al, byte ptr [rsi]", exception_fixup!("2b", "3f"), "3:", ...)
+/// ```
+#[macro_export]
+macro_rules! exception_fixup {
+    ($instruction:literal, $fixup:literal) => {
+        concat!(
+            ".pushsection ex_table, \"a\"\n",
+            ".balign 4\n",
+            ".long ",
+            $instruction,
+            " - .\n",
+            ".long ",
+            $fixup,
+            " - .\n",
+            ".popsection"
+        )
+    };
+}
+
+// keeps the table and its bounds defined when nothing uses fixups
+core::arch::global_asm!(
+    ".pushsection ex_table, \"a\"",
+    ".balign 4",
+    ".long 0, 0",
+    ".popsection"
+);
+
+extern "C" {
+    static __start_ex_table: FixupEntry;
+    static __stop_ex_table: FixupEntry;
+}
+
+fn search_fixup(rip: u64) -> Option<u64> {
+    let mut entry = unsafe { &raw const __start_ex_table };
+    let end = unsafe { &raw const __stop_ex_table };
+    while entry < end {
+        let instruction = unsafe { &raw const (*entry).instruction };
+        let fixup = unsafe { &raw const (*entry).fixup };
+        let offset = unsafe { *instruction };
+        if offset != 0 && (instruction as u64).wrapping_add_signed(offset as i64) == rip {
+            return Some((fixup as u64).wrapping_add_signed(unsafe { *fixup } as i64));
+        }
+        entry = unsafe { entry.add(1) };
+    }
+    None
+}
+
+/// Called by the entry stub with the saved registers
+extern "C" fn dispatch(registers: &mut Registers) {
+    let accounting = IrqAccounting::start(InterruptId::new(registers.vector as u8));
+    let exception = Exception::from_vector(registers.vector as u8).expect("unexpected vector");
+    let mut context = ExceptionContext {
+        exception,
+        registers,
+        cr0: Cr0::read_raw(),
+        cr2: Cr2::read_raw(),
+        cr3: Cr3::read_raw().0.start_address().as_u64(),
+        cr4: Cr4::read_raw(),
+    };
+
+    crate::__trace::irq_enter(exception.name());
+    let resolved = resolve(&mut context);
+    // isolation doesn't return to this frame, so the exception is closed before it
+    crate::__trace::irq_exit(exception.name());
+    drop(accounting);
+    if resolved {
+        return;
+    }
+
+    if exception.kind() == ExceptionKind::Fault {
+        let isolation = FAULT_ISOLATION.load(Ordering::Acquire);
+        if isolation != 0 {
+            let isolation: FaultIsolation = unsafe { core::mem::transmute(isolation) };
+            isolation(&context);
+        }
+    }
+
+    panic!("{}", context);
+}
+
+/// Tries fixups and handlers. Returns `true` if execution can continue.
+fn resolve(context: &mut ExceptionContext) -> bool {
+    let exception = context.exception;
+    if matches!(
+        exception,
+        Exception::PageFault | Exception::GeneralProtectionFault
+    ) && !context.from_user()
+    {
+        if let Some(fixup) = search_fixup(context.registers.rip) {
+            context.registers.rip = fixup;
+            return true;
+        }
+    }
+
+    for slot in HANDLERS[exception as usize].iter() {
+        let handler = slot.load(Ordering::Acquire);
+        if handler != 0 {
+            let handler: ExceptionHandler = unsafe { core::mem::transmute(handler) };
+            if handler(context) {
+                return true;
+            }
+        }
+    }
+
+    if exception.kind() == ExceptionKind::Trap {
+        warn!(
+            "EXCEPTION: {} at 0x{:016x}",
+            exception.name(),
+            context.registers.rip
+        );
+        return true;
+    }
+    false
+}
+
+/// Saves general purpose registers below the vector pushed by the stub and calls [`dispatch`]
+#[unsafe(naked)]
+extern "C" fn common_entry() -> ! {
+    unsafe {
+        naked_asm!(
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "cld",
+        "call {dispatch}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        // vector and error code
+        "add rsp, 16",
+        "iretq",
+        dispatch = sym dispatch,
+        )
+    }
+}
+
+macro_rules! entry {
+    ($name:ident, $vector:literal) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() -> ! {
+            unsafe {
+                naked_asm!(
+                    "push 0",
+                    "push {vector}",
+                    "jmp {entry}",
+                    vector = const $vector,
+                    entry = sym common_entry,
+                )
+            }
+        }
+    };
+    ($name:ident, $vector:literal, error_code) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() -> ! {
+            unsafe {
+                naked_asm!(
+                    "push {vector}",
+                    "jmp {entry}",
+                    vector = const $vector,
+                    entry = sym common_entry,
+                )
+            }
+        }
+    };
+}
+
+entry!(divide_error, 0);
+entry!(debug, 1);
+entry!(breakpoint, 3);
+entry!(overflow, 4);
+entry!(bound_range_exceeded, 5);
+entry!(invalid_opcode, 6);
+entry!(double_fault, 8, error_code);
+entry!(invalid_tss, 10, error_code);
+entry!(segment_not_present, 11, error_code);
+entry!(stack_segment_fault, 12, error_code);
+entry!(general_protection_fault, 13, error_code);
+entry!(page_fault, 14, error_code);
+entry!(x87_floating_point, 16);
+entry!(alignment_check, 17, error_code);
+entry!(machine_check, 18);
+entry!(simd_floating_point, 19);
+entry!(control_protection, 21, error_code);
+
+fn addr(entry: extern "C" fn() -> !) -> VirtAddr {
+    VirtAddr::new(entry as usize as u64)
+}
+
+/// Points exception entries of `idt` to the stubs
+pub(crate) fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error.set_handler_addr(addr(divide_error));
+        idt.debug.set_handler_addr(addr(debug));
+        idt.breakpoint.set_handler_addr(addr(breakpoint));
+        idt.overflow.set_handler_addr(addr(overflow));
+        idt.bound_range_exceeded
+            .set_handler_addr(addr(bound_range_exceeded));
+        idt.invalid_opcode.set_handler_addr(addr(invalid_opcode));
+        idt.double_fault
+            .set_handler_addr(addr(double_fault))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(addr(invalid_tss));
+        idt.segment_not_present
+            .set_handler_addr(addr(segment_not_present));
+        idt.stack_segment_fault
+            .set_handler_addr(addr(stack_segment_fault));
+        idt.general_protection_fault
+            .set_handler_addr(addr(general_protection_fault));
+        idt.page_fault.set_handler_addr(addr(page_fault));
+        idt.x87_floating_point
+            .set_handler_addr(addr(x87_floating_point));
+        idt.alignment_check.set_handler_addr(addr(alignment_check));
+        idt.machine_check
+            .set_handler_addr(addr(machine_check))
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point
+            .set_handler_addr(addr(simd_floating_point));
+        idt.cp_protection_exception
+            .set_handler_addr(addr(control_protection));
+    }
+}
@@ -1,11 +1,30 @@
+use alloc::boxed::Box;
+use core::ptr::{addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use log::{debug, info};
 use x86_64::registers::segmentation::{Segment, CS, DS, SS};
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::paging::{Page, PageSize, PageTableFlags, Size4KiB};
 use x86_64::structures::tss::TaskStateSegment;
-use x86_64::{PrivilegeLevel, VirtAddr};
+use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+const IST_STACKS: usize = 3;
+
+/// Size of every IST stack, without the guard page
+pub const IST_STACK_SIZE: u64 = 4096 * 5;
+/// Guarded IST stacks are mapped one after another starting from here, each with an unmapped guard
+/// page below
+const IST_ADDR_BASE: VirtAddr = VirtAddr::new_truncate(1024 * 1024 * 1024 * 640);
+static NEXT_IST: AtomicU64 = AtomicU64::new(0);
+
+/// IST stacks of the bootstrap CPU until memory is initialized and [`init_ist`] replaces them
+static mut BOOT_STACKS: [[u8; IST_STACK_SIZE as usize]; IST_STACKS] =
+    [[0; IST_STACK_SIZE as usize]; IST_STACKS];
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 struct Selectors {
     code_selector: SegmentSelector,
@@ -14,43 +33,87 @@ struct Selectors {
 }
 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            stack_start + STACK_SIZE as u64
-        };
-        tss
-    };
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.append(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
-        let data_selector = gdt.append(Descriptor::kernel_data_segment());
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-                data_selector,
-            },
-        )
-    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = tables(unsafe { &*addr_of!(TSS) });
 }
 
-pub fn init_gdt() {
+fn tables(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.append(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.append(Descriptor::tss_segment(tss));
+    let data_selector = gdt.append(Descriptor::kernel_data_segment());
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            tss_selector,
+            data_selector,
+        },
+    )
+}
+
+fn load(tables: &'static (GlobalDescriptorTable, Selectors)) {
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    tables.0.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        SS::set_reg(GDT.1.data_selector);
-        DS::set_reg(GDT.1.data_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(tables.1.code_selector);
+        SS::set_reg(tables.1.data_selector);
+        DS::set_reg(tables.1.data_selector);
+        load_tss(tables.1.tss_selector);
         debug!("TSS loaded");
     }
-    info!("GDT and TSS table loaded, double fault IST ready");
+}
+
+pub fn init_gdt() {
+    unsafe {
+        let tss = &mut *addr_of_mut!(TSS);
+        let stacks = &*addr_of!(BOOT_STACKS);
+        for (i, stack) in stacks.iter().enumerate() {
+            tss.interrupt_stack_table[i] = VirtAddr::from_ptr(stack) + IST_STACK_SIZE;
+        }
+    }
+    load(&GDT);
+    info!("GDT and TSS table loaded, boot IST stacks ready");
+}
+
+/// Maps new IST stack with a guard page below it. Returns end of the stack.
+fn alloc_stack() -> VirtAddr {
+    let slot = NEXT_IST.fetch_add(IST_STACK_SIZE + Size4KiB::SIZE, Ordering::Relaxed);
+    let start = IST_ADDR_BASE + slot + Size4KiB::SIZE;
+    for offset in (0..IST_STACK_SIZE).step_by(Size4KiB::SIZE as usize) {
+        let frame = distros_memory::arena::arena_alloc()
+            .allocate(Size4KiB::SIZE as usize)
+            .expect("No memory for IST stack")
+            .into();
+        distros_memory::map(
+            frame,
+            Page::<Size4KiB>::containing_address(start + offset),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )
+        .expect("Failed to map IST stack");
+    }
+    let end = start + IST_STACK_SIZE;
+    distros_backtrace::register_stack(start, end);
+    end
+}
+
+/// Replaces boot IST stacks of the bootstrap CPU with guarded ones. Requires memory to be
+/// initialized.
+pub fn init_ist() {
+    for i in 0..IST_STACKS {
+        let end = alloc_stack();
+        unsafe {
+            (*addr_of_mut!(TSS)).interrupt_stack_table[i] = end;
+        }
+    }
+    info!("Guarded IST stacks ready");
+}
+
+/// Loads GDT and TSS with guarded IST stacks on an application processor
+pub fn init_ap() {
+    let tss = Box::leak(Box::new(TaskStateSegment::new()));
+    for i in 0..IST_STACKS {
+        tss.interrupt_stack_table[i] = alloc_stack();
+    }
+    load(Box::leak(Box::new(tables(tss))));
 }
@@ -1,39 +1,30 @@
-use crate::nmi::nmi_status;
+use crate::exception;
+use crate::nmi::{dispatch_nmi, nmi_status, StatusB};
+use crate::stats::IrqAccounting;
 use crate::{gdt, InterruptId};
 use fixedbitset::FixedBitSet;
 use lazy_static::lazy_static;
 use log::{error, info};
 use spin::mutex::Mutex;
-use x86_64::structures::idt::{
-    HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
-};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
 
 lazy_static! {
     static ref IDT: Mutex<InterruptDescriptorTable> = Mutex::new({
         let mut idt = InterruptDescriptorTable::new();
-        idt.divide_error.set_handler_fn(divide_error_handler);
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.overflow.set_handler_fn(overflow_handler);
-        idt.bound_range_exceeded.set_handler_fn(bound_handler);
-        idt.invalid_opcode.set_handler_fn(invalid_opcode);
+        exception::install(&mut idt);
         idt.device_not_available
             .set_handler_fn(device_not_available);
-        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
-        idt.simd_floating_point.set_handler_fn(fpa_handler);
-        idt.machine_check.set_handler_fn(machine_check_handler);
-        idt.general_protection_fault
-            .set_handler_fn(general_protection_fault);
-        idt.segment_not_present
-            .set_handler_fn(segment_not_present_handler);
         unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt
+                .set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
         }
         idt
     });
     static ref SET_INTS: Mutex<FixedBitSet> = Mutex::new(FixedBitSet::with_capacity(256));
+    /// Vectors with the common dispatch stub, see [`crate::dispatch`]
+    static ref DISPATCH_INTS: Mutex<FixedBitSet> = Mutex::new(FixedBitSet::with_capacity(256));
 }
 
 pub fn init_idt() {
@@ -59,6 +50,7 @@ pub fn set_handler(int: InterruptId, func: HandlerFunc, override_mode: OverrideM
         }
     }
     set_ints.insert(int.int() as usize);
+    DISPATCH_INTS.lock().set(int.int() as usize, false);
     unsafe {
         idt[int.int()].set_handler_fn(func);
         idt.load_unsafe();
@@ -72,116 +64,75 @@ pub fn has_handler(int: InterruptId) -> bool {
 
 /// Allocates handler somewhere in table. Return allocated interrupt
 pub fn alloc_handler(func: HandlerFunc) -> Option<InterruptId> {
-    let mut idt = IDT.lock();
-    let mut set_ints = SET_INTS.lock();
-    for i in 33..=253 {
+    alloc_with(|_| func, false)
+}
+
+/// Allocates free vector for handler returned by `func`. `dispatch` marks the handler as the
+/// common dispatch stub.
+pub(crate) fn alloc_with(
+    func: impl FnOnce(u8) -> HandlerFunc,
+    dispatch: bool,
+) -> Option<InterruptId> {
+    without_interrupts(|| {
+        let mut idt = IDT.lock();
+        let mut set_ints = SET_INTS.lock();
         // 32, 254, 255 reserved for LAPIC
+        let i = (33..=253).find(|i| !set_ints.contains(*i))?;
+        set_ints.insert(i);
+        DISPATCH_INTS.lock().set(i, dispatch);
+        idt[i as u8].set_handler_fn(func(i as u8));
+        unsafe {
+            idt.load_unsafe();
+        }
+        Some(InterruptId::new(i as u8))
+    })
+}
+
+/// Installs dispatch stub returned by `stub` into `int`, unless it is already installed. Returns
+/// `false` if the vector has another handler.
+pub(crate) fn install_with(int: InterruptId, stub: impl FnOnce(u8) -> HandlerFunc) -> bool {
+    without_interrupts(|| {
+        let mut idt = IDT.lock();
+        let mut set_ints = SET_INTS.lock();
+        let mut dispatch_ints = DISPATCH_INTS.lock();
+        let i = int.int() as usize;
+        if dispatch_ints.contains(i) {
+            return true;
+        }
         if set_ints.contains(i) {
-            continue;
+            return false;
         }
         set_ints.insert(i);
-        idt[i as u8].set_handler_fn(func);
+        dispatch_ints.insert(i);
+        idt[int.int()].set_handler_fn(stub(int.int()));
         unsafe {
             idt.load_unsafe();
         }
-        return Some(InterruptId::new(i as u8));
-    }
-    None
+        true
+    })
 }
 
-int_handler!(
-    fpa_handler | stack_frame: InterruptStackFrame | {
-        error!("EXCEPTION: SIMD FPA\n{:#?}", stack_frame);
-    }
-);
-
-int_handler!(
-    breakpoint_handler | stack_frame: InterruptStackFrame | {
-        error!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-    }
-);
-
-int_handler!(
-    divide_error_handler | stack_frame: InterruptStackFrame | {
-        error!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
-    }
-);
-
-int_handler!(
-    overflow_handler | stack_frame: InterruptStackFrame | {
-        error!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
-    }
-);
-
-int_handler!(
-    bound_handler | stack_frame: InterruptStackFrame | {
-        error!("EXCEPTION: BOUND RANGE EXCEEDED\n{:#?}", stack_frame);
-    }
-);
-
-int_handler!(
-    invalid_opcode | stack_frame: InterruptStackFrame | {
-        error!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
-    }
-);
-
 int_handler!(
     device_not_available | stack_frame: InterruptStackFrame | {
-        error!("EXCEPTION: FPU NOT AVAILABLE\n{:#?}", stack_frame);
+        let _accounting = IrqAccounting::start(InterruptId::new(7));
+        // raised by the first FPU instruction after a task switch, see `distros_fpu::switch_to`
+        if !distros_fpu::device_not_available() {
+            error!("EXCEPTION: FPU NOT AVAILABLE\n{:#?}", stack_frame);
+        }
     }
 );
 
 int_handler!(
     nmi_handler | stack_frame: InterruptStackFrame | {
+        let _accounting = IrqAccounting::start(InterruptId::new(2));
         let status = nmi_status();
+        let hardware_error = status.1.intersects(StatusB::PARITY_CHECK | StatusB::CHANNEL_CHECK);
+        if !hardware_error && dispatch_nmi(&stack_frame) {
+            return;
+        }
         panic!(
             "NMI: A = {:?}, B = {:?}\n{:#?}",
             status.0, status.1, stack_frame
         );
     }
 );
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) -> ! {
-    panic!(
-        "EXCEPTION: DOUBLE FAULT[{}]\n{:#?} => ",
-        error_code, stack_frame
-    );
-}
-
-extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
-    panic!("HARDWARE FAULT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn segment_not_present_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
-    error!("SEGMENT_NOT_PRESENT: {}\n{:#?}", error_code, stack_frame);
-}
-
-extern "x86-interrupt" fn general_protection_fault(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
-    error!(
-        "EXCEPTION: GENERAL PROTECTION FAULT[{:?}]\n{:#?}",
-        error_code, stack_frame
-    );
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-
-    panic!(
-        "EXCEPTION: PAGE FAULT\nAccessed Address: {:?}\nError Code: {:?}\n{:#?}",
-        Cr2::read(),
-        error_code,
-        stack_frame
-    );
-}
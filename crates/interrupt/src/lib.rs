@@ -1,15 +1,37 @@
 #![no_std]
 #![feature(asm_const)]
 #![feature(abi_x86_interrupt)]
+#![feature(inline_const)]
+#![feature(naked_functions)]
+
+extern crate alloc;
 
 #[macro_use]
 mod macros;
+mod dispatch;
+mod exception;
 mod gdt;
 mod idt;
 mod nmi;
+mod stats;
 
+pub use dispatch::{
+    alloc_vector, register_handler, reserve_vector, set_eoi, unregister_handler, DispatchError,
+    HandlerId, IrqContext, IrqHandler, IrqReturn,
+};
+#[doc(hidden)]
+pub use distros_trace as __trace;
+pub use exception::{
+    register_exception_handler, set_fault_isolation, ErrorCode, Exception, ExceptionContext,
+    ExceptionHandler, ExceptionKind, FaultIsolation, Registers,
+};
+pub use gdt::{init_ap as init_ap_tables, init_ist as init_ist_stacks, IST_STACK_SIZE};
 pub use idt::{alloc_handler, has_handler, set_handler, OverrideMode};
-pub use nmi::without_nmi;
+pub use nmi::{register_nmi_handler, without_nmi, NmiHandler};
+pub use stats::{
+    count_interrupt, count_spurious, cpu as cpu_irq_stats, total as irq_stats,
+    write_summary as write_irq_summary, IrqAccounting, IrqStats,
+};
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug, Ord, PartialOrd)]
 #[repr(transparent)]
@@ -3,20 +3,26 @@ macro_rules! int_handler {
     (pub noint $name:ident $body:expr) => {
         pub extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
             x86_64::instructions::interrupts::without_interrupts(|| {
+                $crate::__trace::irq_enter(stringify!($name));
                 $body(stack_frame);
+                $crate::__trace::irq_exit(stringify!($name));
             })
         }
     };
     (noint $name:ident $body:expr) => {
         extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
             x86_64::instructions::interrupts::without_interrupts(|| {
+                $crate::__trace::irq_enter(stringify!($name));
                 $body(stack_frame);
+                $crate::__trace::irq_exit(stringify!($name));
             })
         }
     };
     ($name:ident $body:expr) => {
         extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
-            $body(stack_frame)
+            $crate::__trace::irq_enter(stringify!($name));
+            $body(stack_frame);
+            $crate::__trace::irq_exit(stringify!($name));
         }
     };
 }
@@ -1,9 +1,10 @@
 use bitflags::{bitflags, Flags};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use log::info;
 use spin::Mutex;
 use x86_64::instructions::port::{Port, PortReadOnly};
+use x86_64::structures::idt::InterruptStackFrame;
 
 bitflags! {
     #[derive(Debug)]
@@ -39,6 +40,37 @@ lazy_static! {
 
 static ENABLED: AtomicBool = AtomicBool::new(false);
 
+/// NMI handler. Returns `true` if it recognized the NMI as its own.
+///
+/// Handlers run in NMI context: they must not take locks that can be held by interrupted code.
+pub type NmiHandler = fn(&InterruptStackFrame) -> bool;
+
+const MAX_NMI_HANDLERS: usize = 8;
+static NMI_HANDLERS: [AtomicUsize; MAX_NMI_HANDLERS] =
+    [const { AtomicUsize::new(0) }; MAX_NMI_HANDLERS];
+
+/// Adds handler to NMI chain. Returns `false` if the chain is full
+pub fn register_nmi_handler(handler: NmiHandler) -> bool {
+    NMI_HANDLERS.iter().any(|slot| {
+        slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    })
+}
+
+/// Runs every registered handler, because several NMI sources can be collapsed into one NMI
+pub(crate) fn dispatch_nmi(frame: &InterruptStackFrame) -> bool {
+    let mut handled = false;
+    for slot in NMI_HANDLERS.iter() {
+        let handler = slot.load(Ordering::Acquire);
+        if handler == 0 {
+            continue;
+        }
+        let handler: NmiHandler = unsafe { core::mem::transmute(handler) };
+        handled |= handler(frame);
+    }
+    handled
+}
+
 #[inline]
 pub fn nmi_enable() {
     if ENABLED
@@ -0,0 +1,162 @@
+//! Per-CPU interrupt statistics.
+//!
+//! Dispatch vectors, exceptions and NMIs are accounted automatically. Raw handlers installed with
+//! [`set_handler`](crate::set_handler) account themselves with [`IrqAccounting`] or
+//! [`count_interrupt`].
+use crate::exception::Exception;
+use crate::InterruptId;
+use alloc::format;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use distros_cpuid::{cpu_index, MAX_CPUS};
+use distros_timer_tsc::tsc;
+
+struct Counters {
+    count: AtomicU64,
+    cycles: AtomicU64,
+    max_cycles: AtomicU64,
+    spurious: AtomicU64,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            count: AtomicU64::new(0),
+            cycles: AtomicU64::new(0),
+            max_cycles: AtomicU64::new(0),
+            spurious: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Statistics of one vector, see [`total`] and [`cpu`]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct IrqStats {
+    pub count: u64,
+    /// Time spent in handlers, in TSC cycles
+    pub cycles: u64,
+    /// Longest handler run, in TSC cycles
+    pub max_cycles: u64,
+    /// Interrupts no handler claimed
+    pub spurious: u64,
+}
+
+static STATS: [[Counters; 256]; MAX_CPUS] = [const { [const { Counters::new() }; 256] }; MAX_CPUS];
+
+fn counters(vector: u8) -> &'static Counters {
+    &STATS[cpu_index()][vector as usize]
+}
+
+/// Accounts one interrupt with the time until it is dropped
+pub struct IrqAccounting {
+    vector: u8,
+    start: u64,
+}
+
+impl IrqAccounting {
+    #[inline]
+    pub fn start(vector: InterruptId) -> Self {
+        IrqAccounting {
+            vector: vector.int(),
+            start: tsc(),
+        }
+    }
+}
+
+impl Drop for IrqAccounting {
+    fn drop(&mut self) {
+        let cycles = tsc().saturating_sub(self.start);
+        let counters = counters(self.vector);
+        counters.count.fetch_add(1, Ordering::Relaxed);
+        counters.cycles.fetch_add(cycles, Ordering::Relaxed);
+        counters.max_cycles.fetch_max(cycles, Ordering::Relaxed);
+    }
+}
+
+/// Accounts interrupt without measuring its handler
+pub fn count_interrupt(vector: InterruptId) {
+    counters(vector.int()).count.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn count_spurious(vector: InterruptId) {
+    counters(vector.int())
+        .spurious
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Statistics of `vector` on the CPU with index `cpu`, empty if there is no such CPU
+pub fn cpu(cpu: usize, vector: InterruptId) -> IrqStats {
+    let Some(stats) = STATS.get(cpu) else {
+        return IrqStats::default();
+    };
+    let counters = &stats[vector.int() as usize];
+    IrqStats {
+        count: counters.count.load(Ordering::Relaxed),
+        cycles: counters.cycles.load(Ordering::Relaxed),
+        max_cycles: counters.max_cycles.load(Ordering::Relaxed),
+        spurious: counters.spurious.load(Ordering::Relaxed),
+    }
+}
+
+/// Statistics of `vector` on all CPUs
+pub fn total(vector: InterruptId) -> IrqStats {
+    (0..MAX_CPUS).fold(IrqStats::default(), |total, n| {
+        let stats = cpu(n, vector);
+        IrqStats {
+            count: total.count + stats.count,
+            cycles: total.cycles + stats.cycles,
+            max_cycles: total.max_cycles.max(stats.max_cycles),
+            spurious: total.spurious + stats.spurious,
+        }
+    })
+}
+
+/// Writes `/proc/interrupts`-like table: a row for every vector that fired or has a handler, with
+/// counts of every CPU that took an interrupt, average and longest handler time and handler names
+pub fn write_summary(w: &mut dyn fmt::Write) -> fmt::Result {
+    let cpus = (0..MAX_CPUS)
+        .filter(|n| {
+            STATS[*n]
+                .iter()
+                .any(|c| c.count.load(Ordering::Relaxed) != 0)
+        })
+        .collect::<Vec<_>>();
+    write!(w, "{:>4}", "")?;
+    for n in cpus.iter() {
+        write!(w, " {:>10}", format!("CPU{}", n))?;
+    }
+    writeln!(
+        w,
+        " {:>10} {:>10} {:>10}  handlers",
+        "avg", "max", "spurious"
+    )?;
+    for vector in 0..=255u8 {
+        let id = InterruptId::new(vector);
+        let stats = total(id);
+        let names = crate::dispatch::handler_names(id);
+        if stats.count == 0 && stats.spurious == 0 && !crate::has_handler(id) {
+            continue;
+        }
+        write!(w, "{:>3}:", vector)?;
+        for n in cpus.iter() {
+            write!(w, " {:>10}", cpu(*n, id).count)?;
+        }
+        let avg = stats.cycles.checked_div(stats.count).unwrap_or(0);
+        write!(
+            w,
+            " {:>10} {:>10} {:>10} ",
+            avg, stats.max_cycles, stats.spurious
+        )?;
+        match Exception::from_vector(vector) {
+            Some(exception) => write!(w, " {}", exception.name())?,
+            None if vector == 2 => write!(w, " NMI")?,
+            None => {}
+        }
+        for name in names {
+            write!(w, " {}", name)?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
@@ -1,6 +1,6 @@
 #![no_std]
 
-use core::fmt::Write;
+use core::fmt::{Display, Write};
 use core::mem;
 use core::panic::PanicInfo;
 use log::{LevelFilter, Log, Metadata, Record};
@@ -32,7 +32,7 @@ impl<T: Write + Send + Sync + 'static> Logger<T> {
         }
     }
 
-    pub fn panic(info: &PanicInfo) {
+    pub fn panic(info: &PanicInfo, backtrace: impl Display) {
         let logger: &Logger<T> = unsafe { &*(log::logger() as *const _ as *const Logger<T>) };
         if logger.writer.is_locked() {
             unsafe {
@@ -40,7 +40,7 @@ impl<T: Write + Send + Sync + 'static> Logger<T> {
             }
         }
         let mut w = logger.writer.lock();
-        write!(w, "PANIC! {}", info).unwrap();
+        write!(w, "PANIC! {}\n{}", info, backtrace).unwrap();
     }
 }
 
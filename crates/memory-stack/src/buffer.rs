@@ -53,6 +53,20 @@ pub fn find_buffer<I: Into<u64>>(esp: VirtAddr, external_id: I) -> Option<StackB
     buffers.get(&external_id.into()).cloned()
 }
 
+/// Bounds of the stack that contains `addr`. Returns `None` for unknown addresses and when the
+/// registry is locked, so it is safe to call from panics and exceptions.
+pub fn stack_bounds(addr: VirtAddr) -> Option<(VirtAddr, VirtAddr)> {
+    if addr >= KERNEL_STACK_BASE && addr <= (KERNEL_STACK_BASE + KERNEL_STACK_SIZE) {
+        return Some((KERNEL_STACK_BASE, KERNEL_STACK_BASE + KERNEL_STACK_SIZE));
+    }
+    let buffers = BUFFERS.try_read()?;
+    buffers.values().find_map(|buffer| {
+        let start = buffer.start();
+        let end = start + buffer.capacity() as u64;
+        (addr >= start && addr < end).then_some((start, end))
+    })
+}
+
 pub fn new_buffer<I: Into<u64>>(external_id: I, size: usize) -> (StackBuffer, StackBufferHandle) {
     let buffer = StackBuffer::Heap(Arc::new(Vec::with_capacity(size)));
     let mut buffers = BUFFERS.write();
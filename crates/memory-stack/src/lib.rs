@@ -10,4 +10,6 @@ pub const KERNEL_STACK_BASE_GUARD: VirtAddr = VirtAddr::new_truncate(0xf_0000_00
 pub const KERNEL_STACK_BASE: VirtAddr = VirtAddr::new_truncate(0xf_0000_0000 + 0x1000u64);
 pub const KERNEL_STACK_SIZE: u64 = 80 * 1024; // 80 KiB
 
-pub use buffer::{find_buffer, make_copy, new_buffer, StackBuffer, StackBufferHandle};
+pub use buffer::{
+    find_buffer, make_copy, new_buffer, stack_bounds, StackBuffer, StackBufferHandle,
+};
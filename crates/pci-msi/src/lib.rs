@@ -0,0 +1,89 @@
+//! Message signalled interrupts of PCI functions.
+//!
+//! [`Msi`] drives the MSI capability with a single message, [`MsiX`] maps the MSI-X table and
+//! programs its entries one by one. Messages are delivered in fixed mode to a local APIC, either to
+//! a vector of the caller or to a new dispatch vector with the handler registered on it.
+#![no_std]
+
+mod msi;
+mod msix;
+
+use distros_interrupt::{DispatchError, InterruptId};
+use pci_types::{ConfigRegionAccess, PciAddress};
+
+pub use msi::Msi;
+pub use msix::MsiX;
+
+const CAPABILITY_MSI: u8 = 0x05;
+const CAPABILITY_MSIX: u8 = 0x11;
+
+const COMMAND_MEMORY_SPACE: u32 = 1 << 1;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+const COMMAND_INTX_DISABLE: u32 = 1 << 10;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum MsiError {
+    /// Function does not have the capability
+    NoCapability,
+    NoFreeVector,
+    Dispatch(DispatchError),
+    /// Function can not mask messages
+    MaskingUnsupported,
+    /// MSI-X table entry is out of the table
+    InvalidEntry {
+        entry: u16,
+        size: u16,
+    },
+    /// BAR of the MSI-X table is not a memory BAR
+    InvalidBar {
+        bar: u8,
+    },
+    /// MSI-X table can not be mapped
+    MapFailed,
+}
+
+/// Message address and data that deliver `vector` to the local APIC `apic_id`
+pub fn message(vector: InterruptId, apic_id: u32) -> (u64, u32) {
+    let address = 0xFEE0_0000 | ((apic_id as u64 & 0xFF) << 12);
+    // fixed delivery mode, edge-triggered
+    (address, vector.int() as u32)
+}
+
+fn read(address: PciAddress, offset: u16) -> u32 {
+    unsafe { distros_pci_access::access().read(address, offset) }
+}
+
+fn write(address: PciAddress, offset: u16, value: u32) {
+    unsafe { distros_pci_access::access().write(address, offset, value) }
+}
+
+/// Finds offset of the capability `id` in the configuration space of the function
+fn find_capability(address: PciAddress, id: u8) -> Option<u16> {
+    // status register: capabilities list
+    if read(address, 0x04) & (1 << 20) == 0 {
+        return None;
+    }
+    let mut pointer = (read(address, 0x34) & 0xFC) as u16;
+    // bounds a broken list
+    for _ in 0..48 {
+        if pointer == 0 {
+            return None;
+        }
+        let header = read(address, pointer);
+        if header as u8 == id {
+            return Some(pointer);
+        }
+        pointer = ((header >> 8) & 0xFC) as u16;
+    }
+    None
+}
+
+/// Lets the function write messages and stops legacy INTx interrupts
+fn enable_function(address: PciAddress) {
+    let command = read(address, 0x04) & 0xFFFF;
+    write(
+        address,
+        0x04,
+        command | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER | COMMAND_INTX_DISABLE,
+    );
+}
@@ -0,0 +1,110 @@
+use crate::{enable_function, find_capability, message, read, write, MsiError, CAPABILITY_MSI};
+use distros_interrupt::{HandlerId, InterruptId, IrqContext, IrqReturn};
+use pci_types::PciAddress;
+
+const CONTROL_ENABLE: u32 = 1 << 16;
+const CONTROL_MULTIPLE_ENABLE: u32 = 0b111 << 20;
+const CONTROL_64BIT: u32 = 1 << 23;
+const CONTROL_PER_VECTOR_MASK: u32 = 1 << 24;
+
+/// MSI capability of a PCI function. Only one message is enabled: multiple messages need a block
+/// of contiguous aligned vectors.
+pub struct Msi {
+    address: PciAddress,
+    capability: u16,
+    is_64bit: bool,
+    maskable: bool,
+}
+
+impl Msi {
+    pub fn new(address: PciAddress) -> Result<Msi, MsiError> {
+        let capability = find_capability(address, CAPABILITY_MSI).ok_or(MsiError::NoCapability)?;
+        let control = read(address, capability);
+        Ok(Msi {
+            address,
+            capability,
+            is_64bit: control & CONTROL_64BIT != 0,
+            maskable: control & CONTROL_PER_VECTOR_MASK != 0,
+        })
+    }
+
+    #[inline]
+    pub fn address(&self) -> PciAddress {
+        self.address
+    }
+
+    /// Whether the function supports masking of its message
+    #[inline]
+    pub fn maskable(&self) -> bool {
+        self.maskable
+    }
+
+    /// Allocates a vector, registers `handler` on it and targets the message at the current CPU
+    pub fn allocate(
+        &mut self,
+        name: &'static str,
+        handler: impl Fn(&IrqContext) -> IrqReturn + Send + Sync + 'static,
+    ) -> Result<HandlerId, MsiError> {
+        let vector = distros_interrupt::alloc_vector().ok_or(MsiError::NoFreeVector)?;
+        let handler = distros_interrupt::register_handler(vector, name, handler)
+            .map_err(MsiError::Dispatch)?;
+        self.set_vector(vector, distros_interrupt_pic::lapic_id());
+        Ok(handler)
+    }
+
+    /// Targets the message at `vector` of the local APIC `apic_id`
+    pub fn set_vector(&mut self, vector: InterruptId, apic_id: u32) {
+        let (address, data) = message(vector, apic_id);
+        write(self.address, self.capability + 0x04, address as u32);
+        if self.is_64bit {
+            write(self.address, self.capability + 0x08, (address >> 32) as u32);
+        }
+        write(self.address, self.data_offset(), data);
+    }
+
+    pub fn enable(&mut self) {
+        enable_function(self.address);
+        let control = read(self.address, self.capability);
+        write(
+            self.address,
+            self.capability,
+            (control & !CONTROL_MULTIPLE_ENABLE) | CONTROL_ENABLE,
+        );
+    }
+
+    pub fn disable(&mut self) {
+        let control = read(self.address, self.capability);
+        write(self.address, self.capability, control & !CONTROL_ENABLE);
+    }
+
+    pub fn mask(&mut self) -> Result<(), MsiError> {
+        self.set_masked(true)
+    }
+
+    pub fn unmask(&mut self) -> Result<(), MsiError> {
+        self.set_masked(false)
+    }
+
+    fn set_masked(&mut self, masked: bool) -> Result<(), MsiError> {
+        if !self.maskable {
+            return Err(MsiError::MaskingUnsupported);
+        }
+        let offset = self.data_offset() + 0x04;
+        let bits = read(self.address, offset);
+        write(
+            self.address,
+            offset,
+            if masked { bits | 1 } else { bits & !1 },
+        );
+        Ok(())
+    }
+
+    #[inline]
+    fn data_offset(&self) -> u16 {
+        if self.is_64bit {
+            self.capability + 0x0C
+        } else {
+            self.capability + 0x08
+        }
+    }
+}
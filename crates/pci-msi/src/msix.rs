@@ -0,0 +1,185 @@
+use crate::{enable_function, find_capability, message, read, write, MsiError, CAPABILITY_MSIX};
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, Ordering};
+use distros_interrupt::{HandlerId, InterruptId, IrqContext, IrqReturn};
+use pci_types::PciAddress;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// MSI-X tables are mapped one after another starting from here
+const TABLE_ADDR_BASE: VirtAddr = VirtAddr::new_truncate(1024 * 1024 * 1024 * 768);
+static NEXT_TABLE: AtomicU64 = AtomicU64::new(0);
+
+const CONTROL_FUNCTION_MASK: u32 = 1 << 30;
+const CONTROL_ENABLE: u32 = 1 << 31;
+
+const ENTRY_SIZE: u64 = 16;
+const ENTRY_MASKED: u32 = 1;
+
+/// MSI-X capability of a PCI function with its mapped table
+pub struct MsiX {
+    address: PciAddress,
+    capability: u16,
+    table: VirtAddr,
+    size: u16,
+}
+
+impl MsiX {
+    /// Finds the capability and maps the table. All entries are masked until
+    /// [`MsiX::unmask`].
+    pub fn new(address: PciAddress) -> Result<MsiX, MsiError> {
+        let capability = find_capability(address, CAPABILITY_MSIX).ok_or(MsiError::NoCapability)?;
+        let size = ((read(address, capability) >> 16) & 0x7FF) as u16 + 1;
+        let table = read(address, capability + 0x04);
+        let bar = (table & 0b111) as u8;
+        let phys = bar_address(address, bar)? + (table & !0b111) as u64;
+        let msix = MsiX {
+            address,
+            capability,
+            table: map_table(phys, size as u64 * ENTRY_SIZE)?,
+            size,
+        };
+        for entry in 0..size {
+            msix.write_entry(entry, 3, ENTRY_MASKED);
+        }
+        Ok(msix)
+    }
+
+    #[inline]
+    pub fn address(&self) -> PciAddress {
+        self.address
+    }
+
+    /// Number of table entries
+    #[inline]
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Allocates a vector, registers `handler` on it and targets `entry` at the current CPU. The
+    /// entry stays masked.
+    pub fn allocate(
+        &mut self,
+        entry: u16,
+        name: &'static str,
+        handler: impl Fn(&IrqContext) -> IrqReturn + Send + Sync + 'static,
+    ) -> Result<HandlerId, MsiError> {
+        self.check(entry)?;
+        let vector = distros_interrupt::alloc_vector().ok_or(MsiError::NoFreeVector)?;
+        let handler = distros_interrupt::register_handler(vector, name, handler)
+            .map_err(MsiError::Dispatch)?;
+        self.set_vector(entry, vector, distros_interrupt_pic::lapic_id())?;
+        Ok(handler)
+    }
+
+    /// Targets `entry` at `vector` of the local APIC `apic_id`. The entry is masked while it is
+    /// being rewritten and keeps its previous mask state.
+    pub fn set_vector(
+        &mut self,
+        entry: u16,
+        vector: InterruptId,
+        apic_id: u32,
+    ) -> Result<(), MsiError> {
+        self.check(entry)?;
+        let control = self.read_entry(entry, 3);
+        self.write_entry(entry, 3, control | ENTRY_MASKED);
+        let (address, data) = message(vector, apic_id);
+        self.write_entry(entry, 0, address as u32);
+        self.write_entry(entry, 1, (address >> 32) as u32);
+        self.write_entry(entry, 2, data);
+        self.write_entry(entry, 3, control);
+        Ok(())
+    }
+
+    pub fn mask(&mut self, entry: u16) -> Result<(), MsiError> {
+        self.check(entry)?;
+        let control = self.read_entry(entry, 3);
+        self.write_entry(entry, 3, control | ENTRY_MASKED);
+        Ok(())
+    }
+
+    pub fn unmask(&mut self, entry: u16) -> Result<(), MsiError> {
+        self.check(entry)?;
+        let control = self.read_entry(entry, 3);
+        self.write_entry(entry, 3, control & !ENTRY_MASKED);
+        Ok(())
+    }
+
+    pub fn is_masked(&self, entry: u16) -> Result<bool, MsiError> {
+        self.check(entry)?;
+        Ok(self.read_entry(entry, 3) & ENTRY_MASKED != 0)
+    }
+
+    pub fn enable(&mut self) {
+        enable_function(self.address);
+        let control = read(self.address, self.capability);
+        write(
+            self.address,
+            self.capability,
+            (control | CONTROL_ENABLE) & !CONTROL_FUNCTION_MASK,
+        );
+    }
+
+    pub fn disable(&mut self) {
+        let control = read(self.address, self.capability);
+        write(self.address, self.capability, control & !CONTROL_ENABLE);
+    }
+
+    fn check(&self, entry: u16) -> Result<(), MsiError> {
+        if entry < self.size {
+            Ok(())
+        } else {
+            Err(MsiError::InvalidEntry {
+                entry,
+                size: self.size,
+            })
+        }
+    }
+
+    fn read_entry(&self, entry: u16, dword: u64) -> u32 {
+        let ptr = self.table + entry as u64 * ENTRY_SIZE + dword * 4;
+        unsafe { read_volatile(ptr.as_ptr::<u32>()) }
+    }
+
+    fn write_entry(&self, entry: u16, dword: u64, value: u32) {
+        let ptr = self.table + entry as u64 * ENTRY_SIZE + dword * 4;
+        unsafe { write_volatile(ptr.as_mut_ptr::<u32>(), value) }
+    }
+}
+
+fn bar_address(address: PciAddress, bar: u8) -> Result<PhysAddr, MsiError> {
+    if bar > 5 {
+        return Err(MsiError::InvalidBar { bar });
+    }
+    let offset = 0x10 + bar as u16 * 4;
+    let low = read(address, offset);
+    // I/O space BAR
+    if low & 1 != 0 {
+        return Err(MsiError::InvalidBar { bar });
+    }
+    let high = match (low >> 1) & 0b11 {
+        0b00 => 0,
+        0b10 if bar < 5 => read(address, offset + 4),
+        _ => return Err(MsiError::InvalidBar { bar }),
+    };
+    Ok(PhysAddr::new(((high as u64) << 32) | (low & !0xF) as u64))
+}
+
+fn map_table(phys: PhysAddr, len: u64) -> Result<VirtAddr, MsiError> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys + (len - 1));
+    let pages = last - first + 1;
+    let start = TABLE_ADDR_BASE + NEXT_TABLE.fetch_add(pages * 4096, Ordering::Relaxed);
+    for (i, frame) in PhysFrame::range_inclusive(first, last).enumerate() {
+        distros_memory::map(
+            frame,
+            Page::containing_address(start + i as u64 * 4096),
+            PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::NO_CACHE
+                | PageTableFlags::NO_EXECUTE,
+        )
+        .map_err(|_| MsiError::MapFailed)?;
+    }
+    Ok(start + (phys - first.start_address()))
+}
@@ -0,0 +1,82 @@
+use crate::{cpu_id, evtsel, pmc, set_global_enable, supports, Event, PmuError, MAX_CPUS};
+use core::sync::atomic::{AtomicU32, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Allocated general-purpose counters of every CPU
+static USED: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+
+/// Takes a free general-purpose counter of the current CPU
+pub(crate) fn alloc(counters: u8) -> Result<u8, PmuError> {
+    let used = &USED[cpu_id()];
+    (0..counters)
+        .find(|index| used.fetch_or(1 << index, Ordering::AcqRel) & (1 << index) == 0)
+        .ok_or(PmuError::NoFreeCounter)
+}
+
+pub(crate) fn free(cpu: usize, index: u8) {
+    USED[cpu].fetch_and(!(1 << index), Ordering::AcqRel);
+}
+
+/// Counts an event on the CPU that created it, in kernel and user mode. The counter is released
+/// when dropped, which must happen on the same CPU.
+#[derive(Debug)]
+pub struct Counter {
+    cpu: usize,
+    index: u8,
+    event: Event,
+    mask: u64,
+}
+
+impl Counter {
+    pub fn new(event: Event) -> Result<Counter, PmuError> {
+        let info = supports(event)?;
+        without_interrupts(|| {
+            let index = alloc(info.counters)?;
+            unsafe {
+                evtsel(index).write(0);
+                pmc(index).write(0);
+                evtsel(index).write(event.evtsel());
+                set_global_enable(index, true);
+            }
+            Ok(Counter {
+                cpu: cpu_id(),
+                index,
+                event,
+                mask: (1u64 << info.counter_width.min(63)) - 1,
+            })
+        })
+    }
+
+    pub fn event(&self) -> Event {
+        self.event
+    }
+
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+
+    /// Events counted since creation or the last [`reset`](Counter::reset). `None` when called on
+    /// another CPU.
+    pub fn read(&self) -> Option<u64> {
+        if cpu_id() != self.cpu {
+            return None;
+        }
+        Some(unsafe { pmc(self.index).read() } & self.mask)
+    }
+
+    pub fn reset(&self) {
+        if cpu_id() == self.cpu {
+            unsafe { pmc(self.index).write(0) };
+        }
+    }
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        without_interrupts(|| unsafe {
+            set_global_enable(self.index, false);
+            evtsel(self.index).write(0);
+        });
+        free(self.cpu, self.index);
+    }
+}
@@ -0,0 +1,196 @@
+//! Architectural performance monitoring counters.
+//!
+//! Counters are per CPU: a [`Counter`] counts events of the CPU that created it, and sampling runs
+//! on the CPU that started it. In sampling mode a counter overflows every `period` events and
+//! raises an NMI, whose handler records the interrupted instruction pointer and task. Recorded
+//! samples are aggregated into a flat [`Profile`].
+#![no_std]
+
+extern crate alloc;
+
+mod counter;
+mod profile;
+mod sampling;
+
+use distros_cpuid::{get_performance_monitoring_info, MAX_CPUS};
+use log::{info, warn};
+use x86_64::registers::model_specific::Msr;
+
+pub use counter::Counter;
+pub use profile::{profile, set_symbolizer, Profile, ProfileEntry, Symbolizer};
+pub use sampling::{sampling, start_sampling, stop_sampling, Sample, SAMPLES_PER_CPU};
+
+const IA32_PERFEVTSEL0: u32 = 0x186;
+const IA32_PMC0: u32 = 0xC1;
+const IA32_PERF_GLOBAL_STATUS: u32 = 0x38E;
+const IA32_PERF_GLOBAL_CTRL: u32 = 0x38F;
+const IA32_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+const EVTSEL_USR: u64 = 1 << 16;
+const EVTSEL_OS: u64 = 1 << 17;
+const EVTSEL_INT: u64 = 1 << 20;
+const EVTSEL_EN: u64 = 1 << 22;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Event {
+    Cycles,
+    Instructions,
+    /// Cycles at the TSC rate, independent of frequency scaling
+    RefCycles,
+    CacheReferences,
+    /// Last level cache misses
+    CacheMisses,
+    Branches,
+    BranchMisses,
+    /// Model-specific event select and unit mask
+    Raw {
+        event: u8,
+        umask: u8,
+    },
+}
+
+impl Event {
+    fn select(&self) -> (u8, u8) {
+        match *self {
+            Event::Cycles => (0x3C, 0x00),
+            Event::Instructions => (0xC0, 0x00),
+            Event::RefCycles => (0x3C, 0x01),
+            Event::CacheReferences => (0x2E, 0x4F),
+            Event::CacheMisses => (0x2E, 0x41),
+            Event::Branches => (0xC4, 0x00),
+            Event::BranchMisses => (0xC5, 0x00),
+            Event::Raw { event, umask } => (event, umask),
+        }
+    }
+
+    /// Bit of the event in the unavailable events mask of CPUID leaf 0xA
+    fn unavailable_bit(&self) -> Option<u32> {
+        Some(match self {
+            Event::Cycles => 0,
+            Event::Instructions => 1,
+            Event::RefCycles => 2,
+            Event::CacheReferences => 3,
+            Event::CacheMisses => 4,
+            Event::Branches => 5,
+            Event::BranchMisses => 6,
+            Event::Raw { .. } => return None,
+        })
+    }
+
+    fn evtsel(&self) -> u64 {
+        let (event, umask) = self.select();
+        event as u64 | (umask as u64) << 8 | EVTSEL_USR | EVTSEL_OS | EVTSEL_EN
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum PmuError {
+    /// CPU has no architectural performance monitoring
+    Unsupported,
+    EventUnavailable,
+    /// All general-purpose counters of the CPU are in use
+    NoFreeCounter,
+    InvalidPeriod,
+    AlreadySampling,
+    NotSampling,
+}
+
+/// Performance monitoring capabilities from CPUID leaf 0xA
+#[derive(Clone, Copy, Debug)]
+pub struct PmuInfo {
+    pub version: u8,
+    /// General-purpose counters per CPU
+    pub counters: u8,
+    pub counter_width: u8,
+    /// Mask of architectural events the CPU cannot count, in leaf 0xA order
+    unavailable: u32,
+}
+
+static mut INFO: Option<PmuInfo> = None;
+static mut TASK_ID: fn() -> u64 = || 0;
+
+pub fn init() {
+    let info = match get_performance_monitoring_info() {
+        Some(info) if info.version_id() > 0 && info.number_of_counters() > 0 => {
+            let flags = [
+                info.is_core_cyc_ev_unavailable(),
+                info.is_inst_ret_ev_unavailable(),
+                info.is_ref_cycle_ev_unavailable(),
+                info.is_cache_ref_ev_unavailable(),
+                info.is_ll_cache_miss_ev_unavailable(),
+                info.is_branch_inst_ret_ev_unavailable(),
+                info.is_branch_midpred_ev_unavailable(),
+            ];
+            // events past the reported length of the mask are not available either
+            let unavailable = flags
+                .iter()
+                .enumerate()
+                .filter(|(bit, flag)| **flag || *bit >= info.ebx_length() as usize)
+                .fold(0, |mask, (bit, _)| mask | 1 << bit);
+            PmuInfo {
+                version: info.version_id(),
+                counters: info.number_of_counters().min(32),
+                counter_width: info.counter_bit_width(),
+                unavailable,
+            }
+        }
+        _ => {
+            warn!("CPU does not have architectural performance monitoring");
+            return;
+        }
+    };
+    unsafe {
+        INFO = Some(info);
+    }
+    if info.version >= 2 && !distros_interrupt::register_nmi_handler(sampling::overflow) {
+        warn!("Failed to register PMU overflow NMI handler");
+    }
+    info!("PMU ready: {:?}", info);
+}
+
+/// `None` if the CPU has no architectural performance monitoring or [`init`] was not called
+pub fn info() -> Option<PmuInfo> {
+    unsafe { INFO }
+}
+
+fn supports(event: Event) -> Result<PmuInfo, PmuError> {
+    let info = info().ok_or(PmuError::Unsupported)?;
+    match event.unavailable_bit() {
+        Some(bit) if info.unavailable & (1 << bit) != 0 => Err(PmuError::EventUnavailable),
+        _ => Ok(info),
+    }
+}
+
+/// Sets function that returns id of the running task, or `0` outside of tasks. It is called from
+/// NMI, so it must not take locks.
+pub fn set_task_id(fun: fn() -> u64) {
+    unsafe {
+        TASK_ID = fun;
+    }
+}
+
+fn cpu_id() -> usize {
+    distros_cpuid::cpu_index()
+}
+
+fn evtsel(index: u8) -> Msr {
+    Msr::new(IA32_PERFEVTSEL0 + index as u32)
+}
+
+fn pmc(index: u8) -> Msr {
+    Msr::new(IA32_PMC0 + index as u32)
+}
+
+/// Enables or disables general-purpose counter `index` in `IA32_PERF_GLOBAL_CTRL`
+unsafe fn set_global_enable(index: u8, enabled: bool) {
+    if info().map_or(true, |i| i.version < 2) {
+        return;
+    }
+    let mut ctrl = Msr::new(IA32_PERF_GLOBAL_CTRL);
+    let value = ctrl.read();
+    ctrl.write(if enabled {
+        value | 1 << index
+    } else {
+        value & !(1 << index)
+    });
+}
@@ -0,0 +1,125 @@
+//! Flat profile of recorded samples
+use crate::sampling::samples;
+use crate::MAX_CPUS;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+/// Resolves an address into the name and start address of the function containing it
+pub type Symbolizer = fn(u64) -> Option<(&'static str, u64)>;
+
+static mut SYMBOLIZER: Option<Symbolizer> = None;
+
+/// Sets kernel symbol table lookup used by [`profile`]. Without it the profile has raw addresses.
+pub fn set_symbolizer(symbolizer: Symbolizer) {
+    unsafe {
+        SYMBOLIZER = Some(symbolizer);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ProfileEntry {
+    /// Function name, `None` if the address was not resolved
+    pub function: Option<&'static str>,
+    /// Start of the function, or the sampled address if it was not resolved
+    pub address: u64,
+    pub samples: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub samples: u64,
+    /// Samples lost because buffers were full
+    pub dropped: u64,
+    /// Sorted by sample count, highest first
+    pub functions: Vec<ProfileEntry>,
+    /// Task ids with their sample counts, highest first. Task `0` collects samples outside of
+    /// tasks.
+    pub tasks: Vec<(u64, u64)>,
+}
+
+/// Aggregates samples recorded on all CPUs
+pub fn profile() -> Profile {
+    let symbolizer = unsafe { SYMBOLIZER };
+    let mut functions = BTreeMap::new();
+    let mut tasks = BTreeMap::new();
+    let mut total = 0;
+    let mut dropped = 0;
+    for cpu in 0..MAX_CPUS {
+        let (samples, cpu_dropped) = samples(cpu);
+        dropped += cpu_dropped;
+        for sample in samples {
+            total += 1;
+            let key = symbolizer
+                .and_then(|s| s(sample.rip))
+                .map_or((sample.rip, None), |(name, start)| (start, Some(name)));
+            *functions.entry(key).or_insert(0u64) += 1;
+            *tasks.entry(sample.task).or_insert(0u64) += 1;
+        }
+    }
+    let mut functions = functions
+        .into_iter()
+        .map(|((address, function), samples)| ProfileEntry {
+            function,
+            address,
+            samples,
+        })
+        .collect::<Vec<_>>();
+    functions.sort_by(|a, b| b.samples.cmp(&a.samples));
+    let mut tasks = tasks.into_iter().collect::<Vec<_>>();
+    tasks.sort_by(|a, b| b.1.cmp(&a.1));
+    Profile {
+        samples: total,
+        dropped,
+        functions,
+        tasks,
+    }
+}
+
+impl Profile {
+    /// Writes `limit` hottest functions, then sample counts of tasks named by `task_name`
+    pub fn write<W: Write>(
+        &self,
+        w: &mut W,
+        limit: usize,
+        task_name: impl Fn(u64) -> Option<String>,
+    ) -> core::fmt::Result {
+        writeln!(
+            w,
+            "# distros-pmu profile samples={} dropped={}",
+            self.samples, self.dropped
+        )?;
+        // hundredths of a percent
+        let share = |samples: u64| samples * 10000 / self.samples.max(1);
+        for entry in self.functions.iter().take(limit) {
+            let share = share(entry.samples);
+            write!(
+                w,
+                "{:4}.{:02}% {:8} ",
+                share / 100,
+                share % 100,
+                entry.samples
+            )?;
+            match entry.function {
+                Some(name) => writeln!(w, "{}", name)?,
+                None => writeln!(w, "{:#x}", entry.address)?,
+            }
+        }
+        writeln!(w, "# tasks")?;
+        for (task, samples) in self.tasks.iter() {
+            let name = task_name(*task);
+            let share = share(*samples);
+            writeln!(
+                w,
+                "{:4}.{:02}% {:8} {} {}",
+                share / 100,
+                share % 100,
+                samples,
+                task,
+                name.as_deref().unwrap_or("")
+            )?;
+        }
+        writeln!(w, "# distros-pmu end")
+    }
+}
@@ -0,0 +1,181 @@
+//! Sampling mode: counter overflow raises an NMI that records a [`Sample`]
+use crate::counter::{alloc, free};
+use crate::{
+    cpu_id, evtsel, info, pmc, set_global_enable, supports, Event, PmuError, EVTSEL_INT,
+    IA32_PERF_GLOBAL_OVF_CTRL, IA32_PERF_GLOBAL_STATUS, MAX_CPUS, TASK_ID,
+};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// Samples kept per CPU, later samples of a session are dropped
+pub const SAMPLES_PER_CPU: usize = 16384;
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Sample {
+    pub rip: u64,
+    /// Task id, `0` outside of tasks
+    pub task: u64,
+}
+
+struct SampleBuffer {
+    len: AtomicUsize,
+    dropped: AtomicU64,
+    samples: Box<[UnsafeCell<Sample>]>,
+}
+
+unsafe impl Sync for SampleBuffer {}
+
+impl SampleBuffer {
+    fn new() -> Self {
+        SampleBuffer {
+            len: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+            samples: (0..SAMPLES_PER_CPU)
+                .map(|_| UnsafeCell::new(Sample::default()))
+                .collect(),
+        }
+    }
+
+    /// Only the NMI handler of the owning CPU pushes, so pushes never race
+    fn push(&self, sample: Sample) {
+        let len = self.len.load(Ordering::Acquire);
+        match self.samples.get(len) {
+            Some(slot) => {
+                unsafe { slot.get().write_volatile(sample) };
+                self.len.store(len + 1, Ordering::Release);
+            }
+            None => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+struct CpuSampling {
+    /// Index of the sampling counter plus one, `0` if the CPU is not sampling
+    counter: AtomicUsize,
+    /// Value written to the counter after every overflow
+    reload: AtomicU64,
+    buffer: AtomicPtr<SampleBuffer>,
+}
+
+static CPUS: [CpuSampling; MAX_CPUS] = [const {
+    CpuSampling {
+        counter: AtomicUsize::new(0),
+        reload: AtomicU64::new(0),
+        buffer: AtomicPtr::new(ptr::null_mut()),
+    }
+}; MAX_CPUS];
+
+/// Starts sampling `event` on the current CPU: a sample is recorded every `period` events.
+/// Samples of the previous session of the CPU are discarded.
+pub fn start_sampling(event: Event, period: u64) -> Result<(), PmuError> {
+    let info = supports(event)?;
+    if info.version < 2 {
+        return Err(PmuError::Unsupported);
+    }
+    // counters are written as sign-extended 32-bit values
+    if period == 0 || period > i32::MAX as u64 {
+        return Err(PmuError::InvalidPeriod);
+    }
+    without_interrupts(|| {
+        let cpu = &CPUS[cpu_id()];
+        if cpu.counter.load(Ordering::Acquire) != 0 {
+            return Err(PmuError::AlreadySampling);
+        }
+        let index = alloc(info.counters)?;
+
+        let mut buffer = cpu.buffer.load(Ordering::Acquire);
+        if buffer.is_null() {
+            buffer = Box::into_raw(Box::new(SampleBuffer::new()));
+            cpu.buffer.store(buffer, Ordering::Release);
+        }
+        let buffer = unsafe { &*buffer };
+        buffer.len.store(0, Ordering::Release);
+        buffer.dropped.store(0, Ordering::Release);
+
+        let reload = period.wrapping_neg();
+        cpu.reload.store(reload, Ordering::Release);
+        cpu.counter.store(index as usize + 1, Ordering::Release);
+        unsafe {
+            evtsel(index).write(0);
+            pmc(index).write(reload);
+            distros_interrupt_pic::lapic_perf_counter_nmi();
+            evtsel(index).write(event.evtsel() | EVTSEL_INT);
+            set_global_enable(index, true);
+        }
+        Ok(())
+    })
+}
+
+/// Stops sampling on the current CPU, recorded samples are kept
+pub fn stop_sampling() -> Result<(), PmuError> {
+    without_interrupts(|| {
+        let cpu_index = cpu_id();
+        let cpu = &CPUS[cpu_index];
+        let index = match cpu.counter.load(Ordering::Acquire) {
+            0 => return Err(PmuError::NotSampling),
+            counter => (counter - 1) as u8,
+        };
+        unsafe {
+            set_global_enable(index, false);
+            evtsel(index).write(0);
+        }
+        cpu.counter.store(0, Ordering::Release);
+        free(cpu_index, index);
+        Ok(())
+    })
+}
+
+/// Whether the current CPU is sampling
+pub fn sampling() -> bool {
+    CPUS[cpu_id()].counter.load(Ordering::Acquire) != 0
+}
+
+/// Copies samples recorded on `cpu`, and returns them with the number of dropped samples
+pub(crate) fn samples(cpu: usize) -> (Vec<Sample>, u64) {
+    let Some(buffer) = (unsafe { CPUS[cpu].buffer.load(Ordering::Acquire).as_ref() }) else {
+        return (Vec::new(), 0);
+    };
+    let len = buffer.len.load(Ordering::Acquire);
+    let samples = buffer.samples[..len]
+        .iter()
+        .map(|s| unsafe { s.get().read_volatile() })
+        .collect();
+    (samples, buffer.dropped.load(Ordering::Relaxed))
+}
+
+/// NMI handler, records a sample if the sampling counter of the CPU overflowed
+pub(crate) fn overflow(frame: &InterruptStackFrame) -> bool {
+    if info().is_none() {
+        return false;
+    }
+    let cpu = &CPUS[cpu_id()];
+    let index = match cpu.counter.load(Ordering::Acquire) {
+        0 => return false,
+        counter => (counter - 1) as u8,
+    };
+    let status = unsafe { Msr::new(IA32_PERF_GLOBAL_STATUS).read() };
+    if status & (1 << index) == 0 {
+        return false;
+    }
+    if let Some(buffer) = unsafe { cpu.buffer.load(Ordering::Acquire).as_ref() } {
+        buffer.push(Sample {
+            rip: frame.instruction_pointer.as_u64(),
+            task: unsafe { TASK_ID() },
+        });
+    }
+    unsafe {
+        pmc(index).write(cpu.reload.load(Ordering::Acquire));
+        Msr::new(IA32_PERF_GLOBAL_OVF_CTRL).write(1 << index);
+        // LAPIC masks the entry after every delivery
+        distros_interrupt_pic::lapic_perf_counter_nmi();
+    }
+    true
+}
@@ -0,0 +1,78 @@
+use core::fmt::{Debug, Formatter};
+
+pub use distros_cpuid::MAX_CPUS;
+
+/// Set of CPUs a task is allowed to run on
+#[derive(Copy, Clone, Eq, PartialEq, Hash)]
+#[repr(transparent)]
+pub struct CpuSet(u64);
+
+impl CpuSet {
+    pub const fn empty() -> Self {
+        CpuSet(0)
+    }
+
+    pub const fn all() -> Self {
+        CpuSet(u64::MAX)
+    }
+
+    /// Set with only `cpu`, empty if `cpu` is not below [`MAX_CPUS`]
+    pub const fn single(cpu: usize) -> Self {
+        if cpu < MAX_CPUS {
+            CpuSet(1 << cpu)
+        } else {
+            CpuSet(0)
+        }
+    }
+
+    pub const fn from_bits(bits: u64) -> Self {
+        CpuSet(bits)
+    }
+
+    #[inline]
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Adds `cpu`, nothing is added if it is not below [`MAX_CPUS`]
+    pub fn insert(&mut self, cpu: usize) {
+        self.0 |= CpuSet::single(cpu).0;
+    }
+
+    pub fn remove(&mut self, cpu: usize) {
+        self.0 &= !CpuSet::single(cpu).0;
+    }
+
+    #[inline]
+    pub const fn contains(&self, cpu: usize) -> bool {
+        cpu < MAX_CPUS && self.0 & (1 << cpu) != 0
+    }
+
+    #[inline]
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> {
+        let bits = self.0;
+        (0..MAX_CPUS).filter(move |cpu| bits & (1 << cpu) != 0)
+    }
+}
+
+impl Default for CpuSet {
+    fn default() -> Self {
+        CpuSet::all()
+    }
+}
+
+impl Debug for CpuSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+/// Index of the CPU this code runs on
+#[inline]
+pub fn current_cpu() -> usize {
+    distros_cpuid::cpu_index()
+}
@@ -0,0 +1,400 @@
+//! Hierarchical task groups.
+//!
+//! Every task belongs to a group, and groups form a tree under [`GroupId::ROOT`]. CPU time of a
+//! task is charged to its group and all ancestors. Each group has a weight: groups that used more
+//! weighted time than their active siblings get their tasks queued later, so busy subsystems do
+//! not starve each other. A group can also have a quota: once it runs for `quota` within the
+//! current `period`, its tasks are not picked until the period ends.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::time::Duration;
+use distros_timer_tsc::{tsc, tsc_cycles, tsc_duration};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub const DEFAULT_WEIGHT: u32 = 100;
+pub const MAX_WEIGHT: u32 = 10000;
+/// Queue delay a group can get for running more than its siblings
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+#[repr(transparent)]
+pub struct GroupId(u64);
+
+impl GroupId {
+    pub const ROOT: GroupId = GroupId(0);
+
+    #[inline]
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// CPU limit: the group can run for `quota` in every `period`
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Quota {
+    pub quota: Duration,
+    pub period: Duration,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum GroupError {
+    NotFound,
+    TaskNotFound,
+    /// Group still has tasks or child groups
+    NotEmpty,
+    /// Sibling group with the same name already exists
+    NameTaken,
+    InvalidWeight,
+    InvalidQuota,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum GroupEvent {
+    Created(GroupId),
+    Removed(GroupId),
+}
+
+/// Snapshot of group information, see [`info`]
+#[derive(Clone, Debug)]
+pub struct GroupInfo {
+    pub id: GroupId,
+    pub name: String,
+    /// `None` for the root group
+    pub parent: Option<GroupId>,
+    pub weight: u32,
+    pub quota: Option<Quota>,
+    /// CPU time used by tasks of the group and its children
+    pub usage: Duration,
+    /// How many times the group ran out of quota
+    pub throttled: u64,
+    /// Alive tasks in the group, without tasks of child groups
+    pub tasks: usize,
+}
+
+struct Group {
+    name: String,
+    parent: Option<GroupId>,
+    weight: u32,
+    quota: Option<Quota>,
+    /// Tasks in the group
+    tasks: usize,
+    /// Runnable tasks in the group and its children, parked tasks don't count
+    load: usize,
+    /// Used CPU time scaled by weight, in TSC cycles
+    vruntime: u64,
+    usage: u64,
+    period_start: u64,
+    period_used: u64,
+    throttled: bool,
+    throttle_count: u64,
+}
+
+impl Group {
+    fn new(name: String, parent: Option<GroupId>) -> Self {
+        Group {
+            name,
+            parent,
+            weight: DEFAULT_WEIGHT,
+            quota: None,
+            tasks: 0,
+            load: 0,
+            vruntime: 0,
+            usage: 0,
+            period_start: tsc(),
+            period_used: 0,
+            throttled: false,
+            throttle_count: 0,
+        }
+    }
+
+    /// Starts a new quota period if the current one is over
+    fn refill(&mut self, now: u64) {
+        if let Some(quota) = self.quota {
+            if now >= self.period_start + tsc_cycles(quota.period) {
+                self.period_start = now;
+                self.period_used = 0;
+                self.throttled = false;
+            }
+        }
+    }
+
+    fn charge(&mut self, cycles: u64, now: u64) {
+        self.usage += cycles;
+        self.vruntime += cycles * DEFAULT_WEIGHT as u64 / self.weight as u64;
+        self.refill(now);
+        if let Some(quota) = self.quota {
+            self.period_used += cycles;
+            if !self.throttled && self.period_used >= tsc_cycles(quota.quota) {
+                self.throttled = true;
+                self.throttle_count += 1;
+            }
+        }
+    }
+}
+
+struct Groups {
+    groups: BTreeMap<GroupId, Group>,
+    next_id: u64,
+}
+
+impl Groups {
+    fn min_sibling_vruntime(&self, parent: Option<GroupId>, except: GroupId) -> Option<u64> {
+        self.groups
+            .iter()
+            .filter(|(id, g)| **id != except && g.parent == parent && g.load > 0)
+            .map(|(_, g)| g.vruntime)
+            .min()
+    }
+}
+
+static GROUPS: Mutex<Option<Groups>> = Mutex::new(None);
+static mut GROUP_HOOK: Option<fn(GroupEvent)> = None;
+
+fn with_groups<R>(f: impl FnOnce(&mut Groups) -> R) -> R {
+    without_interrupts(|| {
+        let mut groups = GROUPS.lock();
+        f(groups.as_mut().expect("Task groups not initialized"))
+    })
+}
+
+pub(crate) fn init() {
+    let mut groups = BTreeMap::new();
+    groups.insert(GroupId::ROOT, Group::new(String::from("root"), None));
+    without_interrupts(|| {
+        *GROUPS.lock() = Some(Groups { groups, next_id: 1 });
+    });
+}
+
+/// Sets function that is called on every group creation and removal.
+///
+/// Hook can be called from interrupt context, so it must not block.
+pub fn set_hook(hook: fn(GroupEvent)) {
+    unsafe {
+        GROUP_HOOK = Some(hook);
+    }
+}
+
+fn notify_hook(event: GroupEvent) {
+    if let Some(hook) = unsafe { GROUP_HOOK } {
+        hook(event);
+    }
+}
+
+/// Creates a child group of `parent`
+pub fn create(parent: GroupId, name: impl Into<String>) -> Result<GroupId, GroupError> {
+    let name = name.into();
+    let id = with_groups(|groups| {
+        if !groups.groups.contains_key(&parent) {
+            return Err(GroupError::NotFound);
+        }
+        if groups
+            .groups
+            .values()
+            .any(|g| g.parent == Some(parent) && g.name == name)
+        {
+            return Err(GroupError::NameTaken);
+        }
+        let id = GroupId(groups.next_id);
+        groups.next_id += 1;
+        groups.groups.insert(id, Group::new(name, Some(parent)));
+        Ok(id)
+    })?;
+    notify_hook(GroupEvent::Created(id));
+    Ok(id)
+}
+
+/// Removes group without tasks and child groups
+pub fn remove(group: GroupId) -> Result<(), GroupError> {
+    if group == GroupId::ROOT {
+        return Err(GroupError::NotEmpty);
+    }
+    with_groups(|groups| {
+        let g = groups.groups.get(&group).ok_or(GroupError::NotFound)?;
+        if g.tasks > 0 || groups.groups.values().any(|g| g.parent == Some(group)) {
+            return Err(GroupError::NotEmpty);
+        }
+        groups.groups.remove(&group);
+        Ok(())
+    })?;
+    notify_hook(GroupEvent::Removed(group));
+    Ok(())
+}
+
+pub fn set_weight(group: GroupId, weight: u32) -> Result<(), GroupError> {
+    if weight == 0 || weight > MAX_WEIGHT {
+        return Err(GroupError::InvalidWeight);
+    }
+    with_groups(|groups| {
+        let g = groups.groups.get_mut(&group).ok_or(GroupError::NotFound)?;
+        g.weight = weight;
+        Ok(())
+    })
+}
+
+/// Sets CPU limit of the group, `None` removes it
+pub fn set_quota(group: GroupId, quota: Option<Quota>) -> Result<(), GroupError> {
+    if let Some(q) = quota {
+        if q.period.is_zero() || q.quota.is_zero() {
+            return Err(GroupError::InvalidQuota);
+        }
+    }
+    with_groups(|groups| {
+        let g = groups.groups.get_mut(&group).ok_or(GroupError::NotFound)?;
+        g.quota = quota;
+        g.period_start = tsc();
+        g.period_used = 0;
+        g.throttled = false;
+        Ok(())
+    })
+}
+
+pub fn groups() -> Vec<GroupId> {
+    with_groups(|groups| groups.groups.keys().copied().collect())
+}
+
+pub fn info(group: GroupId) -> Option<GroupInfo> {
+    with_groups(|groups| {
+        let g = groups.groups.get(&group)?;
+        Some(GroupInfo {
+            id: group,
+            name: g.name.clone(),
+            parent: g.parent,
+            weight: g.weight,
+            quota: g.quota,
+            usage: tsc_duration(g.usage),
+            throttled: g.throttle_count,
+            tasks: g.tasks,
+        })
+    })
+}
+
+/// Adds task to the group accounting. Returns `false` if the group does not exist.
+///
+/// The task does not count as load until it becomes runnable, see [`enqueue`].
+pub(crate) fn attach(group: GroupId) -> bool {
+    with_groups(|groups| match groups.groups.get_mut(&group) {
+        Some(g) => {
+            g.tasks += 1;
+            true
+        }
+        None => false,
+    })
+}
+
+/// Removes task from the group accounting. A runnable task must be [`park`]ed first.
+pub(crate) fn detach(group: GroupId) {
+    with_groups(|groups| {
+        if let Some(g) = groups.groups.get_mut(&group) {
+            g.tasks = g.tasks.saturating_sub(1);
+        }
+    })
+}
+
+/// Task of `group` became runnable
+pub(crate) fn enqueue(group: GroupId) {
+    with_groups(|groups| {
+        let mut current = Some(group);
+        while let Some(id) = current {
+            let Some(g) = groups.groups.get(&id) else {
+                break;
+            };
+            let parent = g.parent;
+            // a group that wakes up after being idle must not get all the time it missed
+            let placement = if g.load == 0 {
+                groups.min_sibling_vruntime(parent, id)
+            } else {
+                None
+            };
+            let g = groups.groups.get_mut(&id).unwrap();
+            if let Some(placement) = placement {
+                g.vruntime = g.vruntime.max(placement);
+            }
+            g.load += 1;
+            current = parent;
+        }
+    })
+}
+
+/// Task of `group` stopped being runnable: parked or exited
+pub(crate) fn park(group: GroupId) {
+    with_groups(|groups| {
+        let mut current = Some(group);
+        while let Some(g) = current.and_then(|id| groups.groups.get_mut(&id)) {
+            g.load = g.load.saturating_sub(1);
+            current = g.parent;
+        }
+    })
+}
+
+/// Charges CPU time used by a task of `group`
+pub(crate) fn charge(group: GroupId, cycles: u64) {
+    let now = tsc();
+    with_groups(|groups| {
+        let mut current = Some(group);
+        while let Some(g) = current.and_then(|id| groups.groups.get_mut(&id)) {
+            g.charge(cycles, now);
+            current = g.parent;
+        }
+    })
+}
+
+/// Whether tasks of `group` can run now: neither the group nor its ancestors are throttled
+pub(crate) fn runnable(group: GroupId, now: u64) -> bool {
+    with_groups(|groups| {
+        let mut current = Some(group);
+        while let Some(g) = current.and_then(|id| groups.groups.get_mut(&id)) {
+            g.refill(now);
+            if g.throttled {
+                return false;
+            }
+            current = g.parent;
+        }
+        true
+    })
+}
+
+/// CPU time left in the current period of the most limited group in the chain, in TSC cycles
+pub(crate) fn remaining(group: GroupId) -> Option<u64> {
+    with_groups(|groups| {
+        let mut remaining = None::<u64>;
+        let mut current = Some(group);
+        while let Some(g) = current.and_then(|id| groups.groups.get(&id)) {
+            if let Some(q) = g.quota {
+                let left = tsc_cycles(q.quota).saturating_sub(g.period_used);
+                remaining = Some(remaining.map_or(left, |r| r.min(left)));
+            }
+            current = g.parent;
+        }
+        remaining
+    })
+}
+
+/// TSC of the closest end of period of a throttled group
+pub(crate) fn next_refill() -> Option<u64> {
+    with_groups(|groups| {
+        groups
+            .groups
+            .values()
+            .filter(|g| g.throttled)
+            .filter_map(|g| g.quota.map(|q| g.period_start + tsc_cycles(q.period)))
+            .min()
+    })
+}
+
+/// How much later than now tasks of `group` should be queued, in TSC cycles. Grows with the
+/// weighted time the group (or its ancestors) used over their active siblings.
+pub(crate) fn lag(group: GroupId) -> u64 {
+    with_groups(|groups| {
+        let mut lag = 0;
+        let mut current = Some(group);
+        while let Some((id, g)) = current.and_then(|id| Some((id, groups.groups.get(&id)?))) {
+            if let Some(min) = groups.min_sibling_vruntime(g.parent, id) {
+                lag = lag.max(g.vruntime.saturating_sub(min));
+            }
+            current = g.parent;
+        }
+        lag.min(tsc_cycles(MAX_LAG))
+    })
+}
@@ -0,0 +1,270 @@
+//! Idle loop power management.
+//!
+//! When the run queue is empty the CPU programs a wakeup for the next timer deadline and enters
+//! the deepest C-state that is worth its exit latency. MONITOR/MWAIT is used when CPUID advertises
+//! it, otherwise the CPU just halts. Without ARAT the LAPIC timer may stop in deep C-states, so
+//! the wakeup is delivered by an HPET one-shot comparator instead.
+use crate::cpu::{current_cpu, MAX_CPUS};
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use distros_interrupt::{IrqContext, IrqReturn};
+use distros_timer_tsc::{tsc, tsc_duration};
+use log::{debug, warn};
+use x86_64::instructions::interrupts;
+
+pub const MAX_CSTATES: usize = 8;
+
+/// Expected idle time that justifies entering C-state `n`. CPUID does not report exit latencies,
+/// so these are conservative estimates.
+const TARGET_RESIDENCY: [Duration; MAX_CSTATES] = [
+    Duration::ZERO,
+    Duration::ZERO,
+    Duration::from_micros(20),
+    Duration::from_micros(100),
+    Duration::from_micros(300),
+    Duration::from_micros(600),
+    Duration::from_millis(1),
+    Duration::from_millis(2),
+];
+
+#[derive(Copy, Clone, Debug)]
+struct IdleState {
+    cstate: u8,
+    /// MWAIT hint: target C-state minus one in bits 7:4, sub-state in bits 3:0
+    hint: u32,
+}
+
+struct IdleConfig {
+    mwait: bool,
+    /// Available states, from the shallowest to the deepest
+    states: Vec<IdleState>,
+    lapic_stops: bool,
+    hpet_timer: Option<u8>,
+}
+
+impl IdleConfig {
+    fn select(&self, expected: Option<Duration>) -> IdleState {
+        let max_cstate = if self.lapic_stops && self.hpet_timer.is_none() && expected.is_some() {
+            1
+        } else {
+            u8::MAX
+        };
+        self.states
+            .iter()
+            .rev()
+            .filter(|s| s.cstate <= max_cstate)
+            .find(|s| expected.map_or(true, |e| TARGET_RESIDENCY[s.cstate as usize] <= e))
+            .copied()
+            .unwrap_or(self.states[0])
+    }
+}
+
+struct CpuIdle {
+    in_idle: AtomicBool,
+    residency: AtomicU64,
+    entries: AtomicU64,
+    state_residency: [AtomicU64; MAX_CSTATES],
+    state_entries: [AtomicU64; MAX_CSTATES],
+}
+
+impl CpuIdle {
+    const fn new() -> Self {
+        CpuIdle {
+            in_idle: AtomicBool::new(false),
+            residency: AtomicU64::new(0),
+            entries: AtomicU64::new(0),
+            state_residency: [const { AtomicU64::new(0) }; MAX_CSTATES],
+            state_entries: [const { AtomicU64::new(0) }; MAX_CSTATES],
+        }
+    }
+}
+
+/// MONITOR arms the whole cache line, so the wake counter gets one for itself
+#[repr(align(64))]
+struct WakeLine(AtomicU64);
+
+static mut CONFIG: Option<IdleConfig> = None;
+static CPUS: [CpuIdle; MAX_CPUS] = [const { CpuIdle::new() }; MAX_CPUS];
+static WAKE_SEQ: WakeLine = WakeLine(AtomicU64::new(0));
+
+#[derive(Copy, Clone, Debug)]
+pub struct CStateStats {
+    /// C-state number, `1` for C1
+    pub state: u8,
+    pub residency: Duration,
+    pub entries: u64,
+}
+
+/// Idle statistics of a CPU, see [`idle_stats`]
+#[derive(Clone, Debug)]
+pub struct IdleStats {
+    pub residency: Duration,
+    pub entries: u64,
+    pub states: Vec<CStateStats>,
+}
+
+fn config() -> &'static IdleConfig {
+    unsafe { CONFIG.as_ref().expect("Idle not initialized") }
+}
+
+fn probe_states() -> (bool, Vec<IdleState>) {
+    let c1 = IdleState { cstate: 1, hint: 0 };
+    if !distros_cpuid::get_feature_info().has_monitor_mwait() {
+        return (false, alloc::vec![c1]);
+    }
+    let info = match distros_cpuid::get_monitor_mwait_info() {
+        Some(info) if info.extensions_supported() => info,
+        _ => return (true, alloc::vec![c1]),
+    };
+    let substates = [
+        info.supported_c1_states(),
+        info.supported_c2_states(),
+        info.supported_c3_states(),
+        info.supported_c4_states(),
+        info.supported_c5_states(),
+        info.supported_c6_states(),
+        info.supported_c7_states(),
+    ];
+    let mut states: Vec<IdleState> = substates
+        .iter()
+        .enumerate()
+        .filter(|(_, count)| **count > 0)
+        .map(|(i, _)| IdleState {
+            cstate: i as u8 + 1,
+            hint: (i as u32) << 4,
+        })
+        .collect();
+    if states.first().map_or(true, |s| s.cstate != 1) {
+        states.insert(0, c1);
+    }
+    (true, states)
+}
+
+pub(crate) fn init() {
+    let (mwait, states) = probe_states();
+    let lapic_stops = !distros_cpuid::get_thermal_power_info()
+        .map(|i| i.has_arat())
+        .unwrap_or(false);
+    let hpet_timer = if lapic_stops && states.len() > 1 {
+        let vector = distros_interrupt::alloc_vector().expect("Failed to alloc new interrupt");
+        distros_interrupt::register_handler(vector, "idle-wakeup", idle_wakeup)
+            .expect("Failed to register idle wakeup handler");
+        match distros_timer_hpet::alloc_oneshot(vector) {
+            Ok(timer) => Some(timer),
+            Err(e) => {
+                warn!("No HPET wakeup timer for deep C-states: {:?}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    debug!(
+        "Idle: mwait = {}, states = {:?}, LAPIC stops in C-states = {}",
+        mwait, states, lapic_stops
+    );
+    unsafe {
+        CONFIG = Some(IdleConfig {
+            mwait,
+            states,
+            lapic_stops,
+            hpet_timer,
+        });
+    }
+}
+
+fn idle_wakeup(_: &IrqContext) -> IrqReturn {
+    // timers are fired by the idle loop after wakeup
+    IrqReturn::Handled
+}
+
+/// Wakes CPUs sleeping in MWAIT. Called when a task becomes runnable.
+pub(crate) fn notify() {
+    WAKE_SEQ.0.fetch_add(1, Ordering::Release);
+}
+
+/// Whether current CPU is inside [`enter`]. The scheduler interrupt that arrives there is only
+/// a wakeup and must not reschedule.
+pub(crate) fn in_idle() -> bool {
+    CPUS[current_cpu()].in_idle.load(Ordering::Acquire)
+}
+
+/// Sleeps until an interrupt arrives, programming a wakeup at TSC `deadline` if it is set.
+/// `arm_lapic` programs the LAPIC timer.
+///
+/// Must be called with interrupts disabled and returns with interrupts disabled.
+pub(crate) fn enter(deadline: Option<u64>, arm_lapic: impl FnOnce(u64)) {
+    let config = config();
+    let cpu = &CPUS[current_cpu()];
+    let seq = WAKE_SEQ.0.load(Ordering::Acquire);
+    let start = tsc();
+    let expected = deadline.map(|d| tsc_duration(d.saturating_sub(start)));
+    let state = config.select(expected);
+    if let Some(deadline) = deadline {
+        match config.hpet_timer {
+            Some(timer) if config.lapic_stops && state.cstate > 1 => {
+                distros_timer_hpet::arm_oneshot(timer, expected.unwrap_or_default())
+            }
+            _ => arm_lapic(deadline),
+        }
+    }
+
+    cpu.in_idle.store(true, Ordering::Release);
+    if config.mwait {
+        unsafe {
+            monitor(&WAKE_SEQ.0);
+            // a task could have been queued by another CPU before the monitor was armed
+            if WAKE_SEQ.0.load(Ordering::Acquire) == seq {
+                sti_mwait(state.hint);
+            }
+        }
+    } else {
+        interrupts::enable_and_hlt();
+    }
+    interrupts::disable();
+    cpu.in_idle.store(false, Ordering::Release);
+
+    let cycles = tsc() - start;
+    let index = state.cstate as usize;
+    cpu.residency.fetch_add(cycles, Ordering::Relaxed);
+    cpu.entries.fetch_add(1, Ordering::Relaxed);
+    cpu.state_residency[index].fetch_add(cycles, Ordering::Relaxed);
+    cpu.state_entries[index].fetch_add(1, Ordering::Relaxed);
+}
+
+unsafe fn monitor(addr: *const AtomicU64) {
+    asm!("monitor", in("rax") addr, in("ecx") 0, in("edx") 0, options(nostack, preserves_flags));
+}
+
+/// `sti` delays interrupts until the next instruction, so a wakeup interrupt cannot be lost
+/// between enabling interrupts and entering MWAIT
+unsafe fn sti_mwait(hint: u32) {
+    asm!("sti", "mwait", in("eax") hint, in("ecx") 0, options(nomem, nostack));
+}
+
+/// C-states the idle loop can enter, `1` for C1
+pub fn states() -> Vec<u8> {
+    config().states.iter().map(|s| s.cstate).collect()
+}
+
+pub fn stats(cpu: usize) -> Option<IdleStats> {
+    let idle = CPUS.get(cpu)?;
+    let states = config()
+        .states
+        .iter()
+        .map(|s| CStateStats {
+            state: s.cstate,
+            residency: tsc_duration(
+                idle.state_residency[s.cstate as usize].load(Ordering::Relaxed),
+            ),
+            entries: idle.state_entries[s.cstate as usize].load(Ordering::Relaxed),
+        })
+        .collect();
+    Some(IdleStats {
+        residency: tsc_duration(idle.residency.load(Ordering::Relaxed)),
+        entries: idle.entries.load(Ordering::Relaxed),
+        states,
+    })
+}
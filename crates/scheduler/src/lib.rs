@@ -4,22 +4,48 @@
 
 extern crate alloc;
 
+mod cpu;
+mod group;
+mod idle;
+mod local;
 mod nice;
+mod panic;
 mod registry;
 mod scheduler;
+mod thread;
+mod timer;
+mod watchdog;
+mod work;
 
-use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+pub use cpu::{current_cpu, CpuSet, MAX_CPUS};
+pub use group::{
+    create as create_group, groups, info as group_info, remove as remove_group,
+    set_hook as set_group_hook, set_quota as set_group_quota, set_weight as set_group_weight,
+    GroupError, GroupEvent, GroupId, GroupInfo, Quota, DEFAULT_WEIGHT, MAX_WEIGHT,
+};
+pub use idle::{states as idle_states, stats as idle_stats, CStateStats, IdleStats, MAX_CSTATES};
+pub use local::{AccessError, LocalKey};
 pub use nice::NiceLevel;
+pub use panic::{isolate_panic, set_panic_isolation};
 pub use registry::TaskBuilder;
+pub use scheduler::kick_cpu;
 pub use scheduler::start as sched_start;
+pub use scheduler::TaskState;
 use spin::{Mutex, RwLock};
+pub use thread::{park, sleep as thread_sleep, unpark, yield_now};
+pub use timer::{sleep, sleep_until, Sleep};
+pub use watchdog::{init as watchdog_init, WatchdogAction, WatchdogConfig};
+pub use work::WorkQueue;
+use x86_64::instructions::interrupts::without_interrupts;
 
 use crate::registry::TaskRegistry;
-use crate::scheduler::TaskState;
 
 static mut REGISTRY: Option<RwLock<TaskRegistry>> = None;
+static mut TASK_HOOK: Option<fn(TaskEvent)> = None;
 
 #[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 #[repr(transparent)]
@@ -27,12 +53,49 @@ pub struct TaskId(u64);
 
 impl TaskId {
     const EMPTY: TaskId = TaskId(0);
+
+    #[inline]
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum TaskEvent {
+    Spawned(TaskId),
+    Exited(TaskId),
+}
+
+/// Snapshot of task information, see [`task_info`]
+#[derive(Clone, Debug)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: Option<String>,
+    pub nice: NiceLevel,
+    pub state: TaskState,
+    pub cpu_time: Duration,
+    /// Deepest stack usage observed when the task was preempted, in bytes. `None` for tasks that
+    /// were never preempted on their own stack.
+    pub stack_usage: Option<u64>,
+    pub wakeups: u64,
+    pub affinity: CpuSet,
+    pub group: GroupId,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum AffinityError {
+    TaskNotFound,
+    EmptySet,
+    /// None of the CPUs in the set is online
+    NoSuchCpu,
 }
 
 bitflags::bitflags! {
     #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     pub struct TaskFlags: u32 {
         const NOPREEMPT = 0b00000001;
+        /// Panic in the task is never isolated, see [`isolate_panic`]
+        const CRITICAL = 0b00000010;
     }
 }
 
@@ -40,52 +103,132 @@ pub fn init() {
     unsafe {
         REGISTRY = Some(RwLock::new(TaskRegistry::new()));
     }
+    group::init();
+    panic::init();
     scheduler::init();
+    work::init();
+    work::init_cpu(current_cpu());
+}
+
+/// Registry is shared with interrupt handlers (tasks are spawned from IRQs), so it must only be
+/// touched with interrupts disabled
+fn with_registry<R>(f: impl FnOnce(&mut TaskRegistry) -> R) -> R {
+    without_interrupts(|| {
+        let mut registry = unsafe {
+            REGISTRY
+                .as_ref()
+                .expect("Task registry not initialized")
+                .write()
+        };
+        f(&mut registry)
+    })
+}
+
+/// Read-only counterpart of [`with_registry`] for getters, so they don't serialize each other
+fn read_registry<R>(f: impl FnOnce(&TaskRegistry) -> R) -> R {
+    without_interrupts(|| {
+        let registry = unsafe {
+            REGISTRY
+                .as_ref()
+                .expect("Task registry not initialized")
+                .read()
+        };
+        f(&registry)
+    })
 }
 
 pub fn spawn(task: TaskBuilder) -> TaskId {
-    let mut registry = unsafe {
-        REGISTRY
-            .as_mut()
-            .expect("Task registry not initialized")
-            .write()
-    };
     let task_id: Arc<Mutex<TaskId>> = Arc::new(Mutex::new(TaskId::EMPTY));
     let task_id_1 = task_id.clone();
-    let tid = registry.spawn(task.wrap_executable(|future| {
-        Box::pin(async move {
-            future.await;
-            unsafe {
-                if let Some(reg) = REGISTRY.as_ref() {
-                    let mut reg = reg.write();
-                    let tid = task_id_1.lock();
-                    reg.remove(*tid);
-                }
-            }
-        })
-    }));
-    *task_id.lock() = tid;
-    tid
+    let task = task.on_exit(move || task_exited(*task_id_1.lock()));
+    // the task must not complete before its id is known, so keep interrupts off until then
+    without_interrupts(|| {
+        let tid = with_registry(|reg| reg.spawn(task));
+        *task_id.lock() = tid;
+        notify_hook(TaskEvent::Spawned(tid));
+        tid
+    })
+}
+
+/// Releases everything the task owns outside of the scheduler
+fn task_exited(task: TaskId) {
+    local::clear(task);
+    with_registry(|reg| reg.remove(task));
+    notify_hook(TaskEvent::Exited(task));
+}
+
+/// Sets function that is called on every task spawn and exit.
+///
+/// Hook can be called from interrupt context with interrupts disabled, so it must not block.
+pub fn set_task_hook(hook: fn(TaskEvent)) {
+    unsafe {
+        TASK_HOOK = Some(hook);
+    }
+}
+
+fn notify_hook(event: TaskEvent) {
+    if let Some(hook) = unsafe { TASK_HOOK } {
+        hook(event);
+    }
+}
+
+/// Lists all alive tasks
+pub fn tasks() -> Vec<TaskId> {
+    let mut tasks = read_registry(|reg| reg.ids().collect::<Vec<_>>());
+    tasks.sort();
+    tasks
+}
+
+pub fn task_info(task: TaskId) -> Option<TaskInfo> {
+    let (name, nice) = read_registry(|reg| Some((reg.get_name(task)?, reg.get_nice(task)?)))?;
+    let status = scheduler::get_status(task)?;
+    Some(TaskInfo {
+        id: task,
+        name,
+        nice,
+        state: status.state,
+        cpu_time: distros_timer_tsc::tsc_duration(status.cpu_time),
+        stack_usage: status.stack_usage,
+        wakeups: status.wakeups,
+        affinity: status.affinity,
+        group: status.group,
+    })
+}
+
+/// Changes CPUs the task can run on. A running task is moved on its next preemption.
+pub fn set_affinity(task: TaskId, affinity: CpuSet) -> Result<(), AffinityError> {
+    if affinity.is_empty() {
+        return Err(AffinityError::EmptySet);
+    }
+    if affinity.iter().all(|cpu| cpu >= distros_cpuid::cpu_count()) {
+        return Err(AffinityError::NoSuchCpu);
+    }
+    if !with_registry(|reg| reg.set_affinity(task, affinity)) {
+        return Err(AffinityError::TaskNotFound);
+    }
+    scheduler::set_affinity(task, affinity);
+    Ok(())
+}
+
+/// Moves task to another group. Quota and weight of the new group apply from the next time slice.
+pub fn move_task(task: TaskId, group: GroupId) -> Result<(), GroupError> {
+    scheduler::move_task(task, group)
+}
+
+pub fn task_group(task: TaskId) -> Option<GroupId> {
+    scheduler::get_status(task).map(|s| s.group)
+}
+
+pub fn get_affinity(task: TaskId) -> Option<CpuSet> {
+    read_registry(|reg| reg.get_affinity(task))
 }
 
 pub fn get_name(task: TaskId) -> Option<Option<String>> {
-    let registry = unsafe {
-        REGISTRY
-            .as_mut()
-            .expect("Task registry not initialized")
-            .read()
-    };
-    registry.get_name(task)
+    read_registry(|reg| reg.get_name(task))
 }
 
 pub fn get_nice(task: TaskId) -> Option<NiceLevel> {
-    let registry = unsafe {
-        REGISTRY
-            .as_mut()
-            .expect("Task registry not initialized")
-            .read()
-    };
-    registry.get_nice(task)
+    read_registry(|reg| reg.get_nice(task))
 }
 
 pub fn get_state(task: TaskId) -> Option<TaskState> {
@@ -97,11 +240,5 @@ pub fn current_task() -> TaskId {
 }
 
 pub fn set_name(task: TaskId, name: impl Into<String>) {
-    let mut registry = unsafe {
-        REGISTRY
-            .as_mut()
-            .expect("Task registry not initialized")
-            .write()
-    };
-    registry.set_name(task, name);
+    with_registry(|reg| reg.set_name(task, name))
 }
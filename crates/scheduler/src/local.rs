@@ -0,0 +1,109 @@
+//! Task-local storage, see [`task_local!`](crate::task_local)
+use crate::TaskId;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::any::Any;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Values of every task, keyed by the address of their [`LocalKey`]
+struct Locals(BTreeMap<TaskId, BTreeMap<usize, Box<dyn Any>>>);
+
+// values are only accessed by their own task, the map itself is guarded by the mutex
+unsafe impl Send for Locals {}
+
+static LOCALS: Mutex<Locals> = Mutex::new(Locals(BTreeMap::new()));
+
+/// Declares task-local variables. Every task gets its own value, lazily initialized on the first
+/// access and dropped when the task exits.
+///
+/// ```ignore
+/// task_local! {
+///     static REQUESTS: Cell<u64> = Cell::new(0);
+/// }
+///
+/// REQUESTS.with(|r| r.set(r.get() + 1));
+/// ```
+#[macro_export]
+macro_rules! task_local {
+    () => {};
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => {
+        $(#[$attr])*
+        $vis static $name: $crate::LocalKey<$t> = $crate::LocalKey::new({
+            fn __init() -> $t {
+                $init
+            }
+            __init
+        });
+        $crate::task_local!($($rest)*);
+    };
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct AccessError;
+
+pub struct LocalKey<T: 'static> {
+    init: fn() -> T,
+}
+
+impl<T: 'static> LocalKey<T> {
+    #[doc(hidden)]
+    pub const fn new(init: fn() -> T) -> Self {
+        LocalKey { init }
+    }
+
+    /// Calls `f` with the value of current task.
+    ///
+    /// Panics if called outside of a task.
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        self.try_with(f)
+            .expect("Task-local value accessed outside of a task")
+    }
+
+    pub fn try_with<R>(&'static self, f: impl FnOnce(&T) -> R) -> Result<R, AccessError> {
+        let task = crate::scheduler::current().ok_or(AccessError)?;
+        let value = self.get_or_init(task);
+        // the value lives until the task exits, which cannot happen while it is running `f`
+        Ok(f(unsafe { &*value }))
+    }
+
+    fn key(&'static self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn get(&'static self, task: TaskId) -> Option<*const T> {
+        without_interrupts(|| {
+            let locals = LOCALS.lock();
+            let value = locals.0.get(&task)?.get(&self.key())?;
+            value.downcast_ref::<T>().map(|v| v as *const T)
+        })
+    }
+
+    fn get_or_init(&'static self, task: TaskId) -> *const T {
+        if let Some(value) = self.get(task) {
+            return value;
+        }
+        // initializer can use other task-locals, so it runs without the lock
+        let value: Box<dyn Any> = Box::new((self.init)());
+        let rejected = without_interrupts(|| {
+            let mut locals = LOCALS.lock();
+            let values = locals.0.entry(task).or_default();
+            match values.get(&self.key()) {
+                Some(_) => Some(value),
+                None => {
+                    values.insert(self.key(), value);
+                    None
+                }
+            }
+        });
+        // the initializer itself could have initialized the value, the first one wins
+        drop(rejected);
+        self.get(task).expect("Task-local value not initialized")
+    }
+}
+
+/// Drops all task-local values of `task`
+pub(crate) fn clear(task: TaskId) {
+    let values = without_interrupts(|| LOCALS.lock().0.remove(&task));
+    drop(values);
+}
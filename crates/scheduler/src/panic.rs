@@ -0,0 +1,177 @@
+//! Panic isolation.
+//!
+//! The kernel does not unwind, so a panicking task is terminated by abandoning its stack: a thread
+//! is switched out for good, and an async task jumps back to the recovery point the scheduler set
+//! right before polling it. Task-local values, the task stack and scheduler state are released,
+//! while values owned by the abandoned frames are leaked. The future of an async task is dropped
+//! in a separate guarded call, and is leaked only if its drop panics as well.
+use crate::scheduler::SwitchRequest;
+use crate::TaskFlags;
+use core::arch::naked_asm;
+use core::fmt;
+use core::panic::PanicInfo;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, Ordering};
+use distros_interrupt::ExceptionContext;
+use log::error;
+use x86_64::instructions::interrupts;
+
+static ISOLATION: AtomicBool = AtomicBool::new(false);
+
+/// Callee-saved registers, stack pointer and return address of a [`guarded`] call
+#[repr(C)]
+#[derive(Default)]
+pub(crate) struct RecoveryPoint {
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rsp: u64,
+    rip: u64,
+}
+
+/// Saves recovery point into `rdi` and calls `rsi(rdx)`. Returns `0`, or `1` if the call was
+/// abandoned with [`resume`].
+#[unsafe(naked)]
+extern "C" fn call_guarded(
+    point: *mut RecoveryPoint,
+    f: extern "C" fn(*mut u8),
+    data: *mut u8,
+) -> u64 {
+    unsafe {
+        naked_asm!(
+            "mov     qword ptr [rdi], rbx",
+            "mov     qword ptr [rdi + 8], rbp",
+            "mov     qword ptr [rdi + 16], r12",
+            "mov     qword ptr [rdi + 24], r13",
+            "mov     qword ptr [rdi + 32], r14",
+            "mov     qword ptr [rdi + 40], r15",
+            // stack pointer of the caller after return
+            "lea     rax, [rsp + 8]",
+            "mov     qword ptr [rdi + 48], rax",
+            "mov     rax, qword ptr [rsp]",
+            "mov     qword ptr [rdi + 56], rax",
+            "mov     rdi, rdx",
+            "sub     rsp, 8",
+            "call    rsi",
+            "add     rsp, 8",
+            "xor     eax, eax",
+            "ret",
+        )
+    }
+}
+
+/// Returns from the [`call_guarded`] that saved `rdi` with `1`
+#[unsafe(naked)]
+extern "C" fn resume(point: *const RecoveryPoint) -> ! {
+    unsafe {
+        naked_asm!(
+            "mov     rbx, qword ptr [rdi]",
+            "mov     rbp, qword ptr [rdi + 8]",
+            "mov     r12, qword ptr [rdi + 16]",
+            "mov     r13, qword ptr [rdi + 24]",
+            "mov     r14, qword ptr [rdi + 32]",
+            "mov     r15, qword ptr [rdi + 40]",
+            "mov     rsp, qword ptr [rdi + 48]",
+            "mov     eax, 1",
+            "jmp     qword ptr [rdi + 56]",
+        )
+    }
+}
+
+/// Runs `f`, returning `false` if it was abandoned by [`isolate_panic`] through `point`.
+///
+/// `point` must stay valid until `f` returns.
+pub(crate) unsafe fn guarded<F: FnOnce()>(point: NonNull<RecoveryPoint>, f: F) -> bool {
+    extern "C" fn call<F: FnOnce()>(data: *mut u8) {
+        if let Some(f) = unsafe { (*(data as *mut Option<F>)).take() } {
+            f();
+        }
+    }
+    let mut f = Some(f);
+    call_guarded(
+        point.as_ptr(),
+        call::<F>,
+        &mut f as *mut Option<F> as *mut u8,
+    ) == 0
+}
+
+/// Enables or disables panic isolation. Disabled by default.
+pub fn set_panic_isolation(enabled: bool) {
+    ISOLATION.store(enabled, Ordering::Release);
+}
+
+/// Terminates current task if its panic can be isolated, otherwise returns and the panic handler
+/// should stop the kernel.
+///
+/// A panic is not isolated when isolation is disabled, outside of tasks, in
+/// [`critical`](crate::TaskBuilder::critical) tasks and with interrupts disabled: all locks shared
+/// with interrupt handlers are taken with interrupts disabled, and they would stay locked forever.
+/// This also makes a panic during the isolation itself fatal. Must only be called from
+/// `#[panic_handler]`.
+pub fn isolate_panic(info: &PanicInfo, backtrace: &dyn fmt::Display) {
+    if interrupts::are_enabled() {
+        terminate_current(format_args!(
+            "panicked and was terminated: {}\n{}",
+            info, backtrace
+        ));
+    }
+}
+
+/// Terminates current task on a CPU exception no handler resolved, under the same conditions as
+/// [`isolate_panic`]. Installed with [`distros_interrupt::set_fault_isolation`].
+fn isolate_exception(context: &ExceptionContext) {
+    if context.interrupts_enabled() {
+        terminate_current(format_args!("was terminated on exception\n{}", context));
+    }
+}
+
+pub(crate) fn init() {
+    distros_interrupt::set_fault_isolation(isolate_exception);
+}
+
+fn terminate_current(reason: fmt::Arguments) {
+    if !ISOLATION.load(Ordering::Acquire) {
+        return;
+    }
+    interrupts::disable();
+    let task = match crate::scheduler::current_running() {
+        Some(task) if !task.flags.contains(TaskFlags::CRITICAL) => task,
+        _ => return,
+    };
+    if task.recovery.is_none() && !task.thread {
+        return;
+    }
+
+    if task.exited {
+        // the name is gone with the task
+        error!(
+            "Future of terminated task {} is leaked, its drop {}",
+            task.id.as_u64(),
+            reason
+        );
+        resume(
+            task.recovery
+                .expect("Exited task without recovery")
+                .as_ptr(),
+        );
+    }
+    let name = crate::get_name(task.id).flatten();
+    error!(
+        "Task {} ({}) {}",
+        task.id.as_u64(),
+        name.as_deref().unwrap_or("unnamed"),
+        reason
+    );
+    crate::task_exited(task.id);
+    match task.recovery {
+        // the scheduler releases the rest after the poll is abandoned
+        Some(point) => resume(point.as_ptr()),
+        None => {
+            crate::scheduler::switch_current(SwitchRequest::Exit);
+            unreachable!("Exited thread was resumed");
+        }
+    }
+}
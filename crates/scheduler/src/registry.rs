@@ -1,34 +1,71 @@
-use crate::{NiceLevel, TaskFlags, TaskId};
+use crate::group::GroupId;
+use crate::{CpuSet, NiceLevel, TaskFlags, TaskId};
 use alloc::borrow::ToOwned;
 use alloc::boxed::Box;
 use alloc::string::String;
 use core::future::Future;
 use core::pin::Pin;
+use distros_memory_stack::KERNEL_STACK_SIZE;
 use hashbrown::HashMap;
 
 struct Task {
     id: TaskId,
     name: Option<String>,
     nice: NiceLevel,
+    affinity: CpuSet,
+}
+
+pub(crate) enum Executable {
+    Future(Pin<Box<dyn Future<Output = ()>>>),
+    Thread {
+        body: Box<dyn FnOnce() + Send>,
+        stack_size: usize,
+    },
 }
 
 pub struct TaskBuilder {
     name: Option<String>,
     nice: NiceLevel,
     flags: TaskFlags,
-    executable: Pin<Box<dyn Future<Output = ()>>>,
+    affinity: CpuSet,
+    group: Option<GroupId>,
+    executable: Executable,
 }
 
 impl TaskBuilder {
-    pub fn kernel(task: impl Future<Output = ()> + 'static) -> Self {
+    fn new(executable: Executable) -> Self {
         TaskBuilder {
             nice: NiceLevel::default(),
             name: None,
             flags: TaskFlags::empty(),
-            executable: Box::pin(task),
+            affinity: CpuSet::all(),
+            group: None,
+            executable,
         }
     }
 
+    pub fn kernel(task: impl Future<Output = ()> + 'static) -> Self {
+        Self::new(Executable::Future(Box::pin(task)))
+    }
+
+    /// Kernel thread with its own stack. Thread body can block: it is preempted by the scheduler
+    /// timer and can use [`park`](crate::park), [`yield_now`](crate::yield_now) and
+    /// [`thread_sleep`](crate::thread_sleep).
+    pub fn thread(body: impl FnOnce() + Send + 'static) -> Self {
+        Self::new(Executable::Thread {
+            body: Box::new(body),
+            stack_size: KERNEL_STACK_SIZE as usize,
+        })
+    }
+
+    /// Sets stack size of a thread. Does nothing for async tasks.
+    pub fn stack_size(mut self, size: usize) -> Self {
+        if let Executable::Thread { stack_size, .. } = &mut self.executable {
+            *stack_size = size;
+        }
+        self
+    }
+
     pub fn name(mut self, name: impl Into<String>) -> Self {
         self.name = Some(name.into());
         self
@@ -44,11 +81,39 @@ impl TaskBuilder {
         self
     }
 
-    pub fn wrap_executable(
-        mut self,
-        wrapper: impl FnOnce(Pin<Box<dyn Future<Output = ()>>>) -> Pin<Box<dyn Future<Output = ()>>>,
-    ) -> Self {
-        self.executable = wrapper(self.executable);
+    /// Panic in the task stops the kernel even if panic isolation is enabled
+    pub fn critical(mut self) -> Self {
+        self.flags.set(TaskFlags::CRITICAL, true);
+        self
+    }
+
+    /// Restricts CPUs the task can run on
+    pub fn affinity(mut self, affinity: CpuSet) -> Self {
+        self.affinity = affinity;
+        self
+    }
+
+    /// Puts the task into `group`. By default the task inherits the group of its spawner.
+    pub fn group(mut self, group: GroupId) -> Self {
+        self.group = Some(group);
+        self
+    }
+
+    /// Runs `hook` after the task body finishes
+    pub(crate) fn on_exit(mut self, hook: impl FnOnce() + Send + 'static) -> Self {
+        self.executable = match self.executable {
+            Executable::Future(future) => Executable::Future(Box::pin(async move {
+                future.await;
+                hook();
+            })),
+            Executable::Thread { body, stack_size } => Executable::Thread {
+                body: Box::new(move || {
+                    body();
+                    hook();
+                }),
+                stack_size,
+            },
+        };
         self
     }
 }
@@ -65,13 +130,21 @@ impl TaskRegistry {
     }
 
     pub fn spawn(&mut self, task: TaskBuilder) -> TaskId {
-        let id = crate::scheduler::add(task.executable, task.nice, task.flags);
+        let group = task.group.unwrap_or_else(|| {
+            crate::scheduler::current()
+                .and_then(crate::scheduler::get_status)
+                .map(|s| s.group)
+                .unwrap_or(GroupId::ROOT)
+        });
+        let id =
+            crate::scheduler::add(task.executable, task.nice, task.flags, task.affinity, group);
         self.tasks.insert(
             id,
             Task {
                 id,
                 name: task.name,
                 nice: task.nice,
+                affinity: task.affinity,
             },
         );
         id
@@ -81,6 +154,10 @@ impl TaskRegistry {
         self.tasks.remove(&task);
     }
 
+    pub fn ids(&self) -> impl Iterator<Item = TaskId> + '_ {
+        self.tasks.keys().copied()
+    }
+
     pub fn get_name(&self, task_id: TaskId) -> Option<Option<String>> {
         self.tasks.get(&task_id).map(|s| s.name.to_owned())
     }
@@ -89,6 +166,20 @@ impl TaskRegistry {
         self.tasks.get(&task_id).map(|s| s.nice)
     }
 
+    pub fn get_affinity(&self, task_id: TaskId) -> Option<CpuSet> {
+        self.tasks.get(&task_id).map(|s| s.affinity)
+    }
+
+    pub fn set_affinity(&mut self, task_id: TaskId, affinity: CpuSet) -> bool {
+        match self.tasks.get_mut(&task_id) {
+            Some(task) => {
+                task.affinity = affinity;
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn set_name(&mut self, task_id: TaskId, name: impl Into<String>) {
         self.tasks.entry(task_id).and_modify(move |x| {
             x.name = Some(name.into());
@@ -1,4 +1,7 @@
+use alloc::boxed::Box;
 use distros_fpu::FpuState;
+use x86_64::instructions::segmentation::{Segment, CS, SS};
+use x86_64::registers::rflags::RFlags;
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::idt::{InterruptStackFrame, InterruptStackFrameValue};
 use x86_64::VirtAddr;
@@ -86,9 +89,9 @@ impl Regs {
 #[derive(Clone)]
 pub struct TaskContext {
     pub regs: Regs,
-    pub fpu: FpuState,
     pub instruction_pointer: VirtAddr,
     pub stack_pointer: VirtAddr,
+    pub cpu_flags: RFlags,
     pub code_segment: u16,
     pub stack_segment: u16,
 }
@@ -97,31 +100,65 @@ impl TaskContext {
     pub const fn new() -> Self {
         TaskContext {
             regs: Regs::new(),
-            fpu: FpuState::new(),
             instruction_pointer: VirtAddr::new_truncate(0),
             stack_pointer: VirtAddr::new_truncate(0),
+            cpu_flags: RFlags::empty(),
             code_segment: 0,
             stack_segment: 0,
         }
     }
 
+    /// Context that starts executing `entry(arg)` on a fresh stack with interrupts enabled
+    pub unsafe fn new_thread(entry: VirtAddr, arg: u64, stack_top: VirtAddr) -> TaskContext {
+        let mut ctx = TaskContext::new();
+        ctx.instruction_pointer = entry;
+        // as if `entry` was called, the return address slot keeps the stack 16-byte aligned
+        ctx.stack_pointer = stack_top.align_down(16u64) - 8u64;
+        ctx.cpu_flags = RFlags::INTERRUPT_FLAG;
+        ctx.code_segment = CS::get_reg().0;
+        ctx.stack_segment = SS::get_reg().0;
+        ctx.regs.rdi = arg;
+        ctx
+    }
+
     pub unsafe fn fill_from(frame: &InterruptStackFrame, regs: &Regs) -> TaskContext {
         let mut ctx = TaskContext::new();
         ctx.stack_pointer = frame.stack_pointer;
         ctx.instruction_pointer = frame.instruction_pointer;
+        ctx.cpu_flags = frame.cpu_flags;
         ctx.stack_segment = frame.stack_segment.0;
         ctx.code_segment = frame.code_segment.0;
-        ctx.fpu.save();
         ctx.regs.take_from(regs);
         ctx
     }
 
     pub unsafe fn save_info(&self, frame: &mut InterruptStackFrameValue, regs: &mut Regs) {
-        self.fpu.restore();
         self.regs.put_into(regs);
         frame.instruction_pointer = self.instruction_pointer;
         frame.stack_pointer = self.stack_pointer;
+        frame.cpu_flags = self.cpu_flags;
         frame.code_segment = SegmentSelector(self.code_segment);
         frame.stack_segment = SegmentSelector(self.stack_segment);
     }
 }
+
+/// FPU state of a task. Registers are switched lazily by `distros_fpu`, which keeps a pointer to
+/// the state, so it is boxed and released on drop.
+pub struct TaskFpu(Box<FpuState>);
+
+impl TaskFpu {
+    pub fn new() -> Self {
+        TaskFpu(Box::new(FpuState::initial()))
+    }
+
+    /// Makes this state current on the CPU, must be called with interrupts disabled
+    pub fn activate(&mut self) {
+        unsafe { distros_fpu::switch_to(&mut *self.0) }
+    }
+}
+
+impl Drop for TaskFpu {
+    fn drop(&mut self) {
+        distros_fpu::release(&mut *self.0);
+    }
+}
@@ -1,5 +1,11 @@
-use crate::scheduler::context::{Regs, TaskContext};
-use crate::scheduler::TaskState;
+use crate::cpu::{current_cpu, CpuSet};
+use crate::group::{self, GroupError, GroupId};
+use crate::panic::{guarded, RecoveryPoint};
+use crate::registry::Executable;
+use crate::scheduler::context::{Regs, TaskContext, TaskFpu};
+use crate::scheduler::{reschedule, tick, SwitchRequest, TaskState};
+use crate::thread::thread_entry;
+use crate::{idle, timer, watchdog};
 use crate::{NiceLevel, TaskFlags, TaskId};
 use alloc::boxed::Box;
 use alloc::rc::Rc;
@@ -8,24 +14,28 @@ use alloc::sync::Arc;
 use alloc::task::Wake;
 use core::future::Future;
 use core::pin::Pin;
+use core::ptr::NonNull;
 use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use core::task::{Context, Poll};
+use core::task::{Context, Poll, Waker};
 use core::time::Duration;
 use distros_memory_stack::{
-    find_buffer, new_buffer, StackBuffer, StackBufferHandle, KERNEL_STACK_SIZE,
+    find_buffer, new_buffer, StackBuffer, StackBufferHandle, KERNEL_STACK_BASE, KERNEL_STACK_SIZE,
 };
-use distros_timer_tsc::tsc;
+use distros_timer_tsc::{tsc, tsc_cycles};
+use distros_trace::TraceEvent;
 use hashbrown::HashMap;
 use intrusive_collections::{intrusive_adapter, KeyAdapter, RBTree, RBTreeLink};
-use log::warn;
 use spin::Mutex;
-use x2apic::lapic::TimerDivide;
 use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::segmentation::{Segment, CS, SS};
+use x86_64::registers::rflags::RFlags;
 use x86_64::structures::idt::InterruptStackFrame;
 use x86_64::VirtAddr;
 
 const DEADLINE: Duration = Duration::from_millis(1000);
 const NICE_PERIOD: Duration = Duration::from_nanos(500 * 1000);
+/// Gap left below the lowest live frame on the kernel stack when scheduling moves back to it
+const ASYNC_STACK_GAP: u64 = 1024;
 
 enum WaitingTaskState {
     Paused(TaskContext),
@@ -43,6 +53,9 @@ struct WaitingTask {
     nice: NiceLevel,
     flags: TaskFlags,
     buffer_handle: Option<StackBufferHandle>,
+    thread: bool,
+    recovery: Option<NonNull<RecoveryPoint>>,
+    fpu: TaskFpu,
 }
 
 struct RunningTask {
@@ -50,6 +63,31 @@ struct RunningTask {
     nice: NiceLevel,
     flags: TaskFlags,
     stack_handle: Option<StackBufferHandle>,
+    /// TSC value when the task was switched in
+    started: u64,
+    thread: bool,
+    switch: Option<SwitchRequest>,
+    /// Recovery point of the poll an async task runs in. It is on the kernel stack in the frame
+    /// of the scheduler interrupt, which stays in place while the task is preempted.
+    recovery: Option<NonNull<RecoveryPoint>>,
+    /// The task was already terminated, only its future is being dropped
+    exited: bool,
+    fpu: TaskFpu,
+}
+
+/// Task that runs on the CPU, see [`Scheduler::current_running`]
+pub(crate) struct CurrentTask {
+    pub id: TaskId,
+    pub flags: TaskFlags,
+    pub thread: bool,
+    pub recovery: Option<NonNull<RecoveryPoint>>,
+    pub exited: bool,
+}
+
+enum ParkSlot {
+    /// Thread was unparked before it parked, so the next park returns immediately
+    Token,
+    Parked(Box<WaitingTask>),
 }
 
 intrusive_adapter!(WaitingTaskAdapter = Box<WaitingTask>: WaitingTask { link: RBTreeLink });
@@ -60,9 +98,46 @@ impl<'a> KeyAdapter<'a> for WaitingTaskAdapter {
     }
 }
 
+#[derive(Copy, Clone, Debug)]
+pub struct TaskStatus {
+    pub state: TaskState,
+    /// TSC cycles spent running
+    pub cpu_time: u64,
+    pub wakeups: u64,
+    /// Deepest stack usage observed on preemption, in bytes. `None` until the task is preempted
+    /// on its own stack, async tasks polled on the kernel stack never are.
+    pub stack_usage: Option<u64>,
+    pub affinity: CpuSet,
+    pub group: GroupId,
+}
+
+impl TaskStatus {
+    fn new(state: TaskState, affinity: CpuSet, group: GroupId) -> Self {
+        TaskStatus {
+            state,
+            cpu_time: 0,
+            wakeups: 0,
+            stack_usage: None,
+            affinity,
+            group,
+        }
+    }
+}
+
+/// Whether a task in `state` counts as load of its group
+fn runnable(state: TaskState) -> bool {
+    state != TaskState::Parked
+}
+
+/// Queue key of a task that is queued now. Tasks of groups that ran more than their siblings are
+/// queued later.
+fn queue_key(group: GroupId) -> u64 {
+    tsc() + group::lag(group)
+}
+
 #[derive(Clone)]
 struct TaskStates {
-    task_states: Arc<Mutex<HashMap<TaskId, TaskState>>>,
+    task_states: Arc<Mutex<HashMap<TaskId, TaskStatus>>>,
 }
 
 unsafe impl Sync for TaskStates {}
@@ -76,48 +151,172 @@ impl TaskStates {
     }
 
     fn get_state(&self, task: TaskId) -> Option<TaskState> {
+        self.get_status(task).map(|s| s.state)
+    }
+
+    fn get_status(&self, task: TaskId) -> Option<TaskStatus> {
         without_interrupts(|| {
             let states = self.task_states.lock();
             states.get(&task).copied()
         })
     }
 
+    /// Adds a new runnable task of `group`, which must be already attached
+    fn insert(&self, task: TaskId, affinity: CpuSet, group: GroupId) {
+        without_interrupts(|| {
+            let mut states = self.task_states.lock();
+            states.insert(task, TaskStatus::new(TaskState::Waiting, affinity, group));
+            group::enqueue(group);
+        })
+    }
+
     fn set_state(&self, task: TaskId, state: TaskState) {
-        let mut states = self.task_states.lock();
-        states.insert(task, state);
+        self.modify(task, |status| {
+            if runnable(status.state) != runnable(state) {
+                if runnable(state) {
+                    group::enqueue(status.group);
+                } else {
+                    group::park(status.group);
+                }
+            }
+            status.state = state;
+        });
+    }
+
+    fn add_cpu_time(&self, task: TaskId, cycles: u64) {
+        let mut group = None;
+        self.modify(task, |status| {
+            status.cpu_time += cycles;
+            group = Some(status.group);
+        });
+        if let Some(group) = group {
+            group::charge(group, cycles);
+        }
+    }
+
+    fn group(&self, task: TaskId) -> GroupId {
+        self.get_status(task)
+            .map(|s| s.group)
+            .unwrap_or(GroupId::ROOT)
+    }
+
+    /// Moves task to `group`, which must be already attached. Returns the previous group.
+    fn set_group(&self, task: TaskId, group: GroupId) -> Option<GroupId> {
+        let mut previous = None;
+        self.modify(task, |status| {
+            previous = Some(status.group);
+            if runnable(status.state) {
+                group::park(status.group);
+                group::enqueue(group);
+            }
+            status.group = group;
+        });
+        previous
+    }
+
+    fn add_wakeup(&self, task: TaskId) {
+        self.modify(task, |status| status.wakeups += 1);
+    }
+
+    fn record_stack_usage(&self, task: TaskId, bytes: u64) {
+        self.modify(task, |status| {
+            status.stack_usage = Some(status.stack_usage.map_or(bytes, |b| b.max(bytes)))
+        });
+    }
+
+    /// Picks the first task from the queue that is allowed to run on `cpu` and whose group is
+    /// not throttled
+    fn pop_runnable(
+        &self,
+        queue: &mut RBTree<WaitingTaskAdapter>,
+        cpu: usize,
+    ) -> Option<Box<WaitingTask>> {
+        let now = tsc();
+        let states = self.task_states.lock();
+        let mut cursor = queue.front_mut();
+        while let Some(task) = cursor.get() {
+            let allowed = states
+                .get(&task.id)
+                .map(|s| s.affinity.contains(cpu) && group::runnable(s.group, now))
+                .unwrap_or(true);
+            if allowed {
+                return cursor.remove();
+            }
+            cursor.move_next();
+        }
+        None
+    }
+
+    fn set_affinity(&self, task: TaskId, affinity: CpuSet) -> bool {
+        self.modify(task, |status| status.affinity = affinity)
+    }
+
+    fn modify(&self, task: TaskId, f: impl FnOnce(&mut TaskStatus)) -> bool {
+        without_interrupts(|| {
+            let mut states = self.task_states.lock();
+            match states.get_mut(&task) {
+                Some(status) => {
+                    f(status);
+                    true
+                }
+                None => false,
+            }
+        })
     }
 
     fn remove_state(&self, task: TaskId) {
-        let mut states = self.task_states.lock();
-        states.remove(&task);
+        without_interrupts(|| {
+            let mut states = self.task_states.lock();
+            if let Some(status) = states.remove(&task) {
+                if runnable(status.state) {
+                    group::park(status.group);
+                }
+                group::detach(status.group);
+            }
+        })
     }
 }
 
 struct TaskWaker {
+    id: TaskId,
     task: Mutex<Option<WaitingTask>>,
     wake_called: AtomicBool,
     queue: Arc<Mutex<RBTree<WaitingTaskAdapter>>>,
+    states: TaskStates,
 }
 
 impl TaskWaker {
-    pub fn new(queue: &Arc<Mutex<RBTree<WaitingTaskAdapter>>>) -> Self {
+    fn new(
+        id: TaskId,
+        queue: &Arc<Mutex<RBTree<WaitingTaskAdapter>>>,
+        states: &TaskStates,
+    ) -> Self {
         TaskWaker {
+            id,
             task: Mutex::new(None),
             wake_called: AtomicBool::new(false),
             queue: queue.clone(),
+            states: states.clone(),
         }
     }
 }
 
 impl Wake for TaskWaker {
     fn wake(self: Arc<Self>) {
-        let mut task = self.task.lock();
-        if let Some(task) = task.take() {
-            let mut queue = self.queue.lock();
-            queue.insert(Box::new(task));
-        } else {
-            self.wake_called.store(true, Ordering::SeqCst);
-        }
+        // the queue is also locked by the scheduler interrupt
+        without_interrupts(|| {
+            let mut task = self.task.lock();
+            distros_trace::record(TraceEvent::Wake, self.id.0);
+            if let Some(task) = task.take() {
+                self.states.add_wakeup(self.id);
+                self.states.set_state(self.id, TaskState::Waiting);
+                let mut queue = self.queue.lock();
+                queue.insert(Box::new(task));
+                idle::notify();
+            } else {
+                self.wake_called.store(true, Ordering::SeqCst);
+            }
+        })
     }
 }
 
@@ -128,83 +327,265 @@ unsafe impl Sync for TaskWaker {}
 pub struct Scheduler {
     task_states: TaskStates,
     waiting_tasks: Arc<Mutex<RBTree<WaitingTaskAdapter>>>,
+    parked: Mutex<HashMap<TaskId, ParkSlot>>,
     current_task: Option<RunningTask>,
     id_counter: AtomicU64,
-    tsc_deadline: bool,
-    lapic_freq: u64,
+    /// Stack pointer of [`crate::scheduler::start`], everything below it on the kernel stack
+    /// belongs to the scheduler and async tasks
+    async_base: VirtAddr,
+    /// Stack of an exited thread, freed once the scheduler no longer runs on it
+    dead_stack: Option<StackBufferHandle>,
 }
 
 impl Scheduler {
-    pub const DIVIDER: u64 = 32;
-    pub const LAPIC_DIVIDER: TimerDivide = TimerDivide::Div32;
-
-    pub fn new(tsc_deadline: bool) -> Scheduler {
+    pub fn new() -> Scheduler {
         Scheduler {
             task_states: TaskStates::new(),
             waiting_tasks: Arc::new(Mutex::new(RBTree::new(WaitingTaskAdapter::new()))),
+            parked: Mutex::new(HashMap::new()),
             current_task: None,
             id_counter: AtomicU64::new(1),
-            tsc_deadline,
-            lapic_freq: distros_cpuid::get_processor_frequency_info()
-                .map(|s| s.bus_frequency())
-                .unwrap_or(100) as u64
-                * 1000
-                * 1000
-                / Self::DIVIDER,
+            async_base: KERNEL_STACK_BASE + KERNEL_STACK_SIZE,
+            dead_stack: None,
         }
     }
 
+    pub fn set_async_base(&mut self, rsp: VirtAddr) {
+        self.async_base = rsp;
+    }
+
     pub fn get_state(&self, task: TaskId) -> Option<TaskState> {
         self.task_states.get_state(task)
     }
 
+    pub fn get_status(&self, task: TaskId) -> Option<TaskStatus> {
+        self.task_states.get_status(task)
+    }
+
+    pub fn set_affinity(&self, task: TaskId, affinity: CpuSet) -> bool {
+        self.task_states.set_affinity(task, affinity)
+    }
+
     pub fn current(&self) -> Option<TaskId> {
         without_interrupts(|| self.current_task.as_ref().map(|s| s.id.clone()))
     }
 
+    pub(crate) fn current_running(&self) -> Option<CurrentTask> {
+        without_interrupts(|| {
+            self.current_task.as_ref().map(|task| CurrentTask {
+                id: task.id,
+                flags: task.flags,
+                thread: task.thread,
+                recovery: task.recovery,
+                exited: task.exited,
+            })
+        })
+    }
+
     pub fn add(
         &self,
-        task: Pin<Box<dyn Future<Output = ()>>>,
+        task: Executable,
         nice_level: NiceLevel,
         flags: TaskFlags,
+        affinity: CpuSet,
+        group: GroupId,
     ) -> TaskId {
         let id = self.id_counter.fetch_add(1, Ordering::SeqCst);
+        distros_trace::record(TraceEvent::Spawn, id);
+        // the group could have been removed after the spawner picked it
+        let group = if group::attach(group) {
+            group
+        } else {
+            group::attach(GroupId::ROOT);
+            GroupId::ROOT
+        };
+        self.task_states.insert(TaskId(id), affinity, group);
+        let (state, buffer_handle, thread) = match task {
+            Executable::Future(future) => (WaitingTaskState::Ready(future), None, false),
+            Executable::Thread { body, stack_size } => {
+                let (buffer, handle) = new_buffer(id, stack_size);
+                let body = Box::into_raw(Box::new(body));
+                let ctx = unsafe {
+                    TaskContext::new_thread(
+                        VirtAddr::new(thread_entry as u64),
+                        body as u64,
+                        buffer.start() + buffer.capacity() as u64,
+                    )
+                };
+                (WaitingTaskState::Paused(ctx), Some(handle), true)
+            }
+        };
         without_interrupts(|| {
             let mut tasks = self.waiting_tasks.lock();
             tasks.insert(Box::new(WaitingTask {
                 id: TaskId(id),
-                state: WaitingTaskState::Ready(task),
-                run_time: tsc(),
+                state,
+                run_time: queue_key(group),
                 nice: nice_level,
                 link: Default::default(),
                 flags,
-                buffer_handle: None,
+                buffer_handle,
+                thread,
+                recovery: None,
+                fpu: TaskFpu::new(),
             }));
         });
+        idle::notify();
         TaskId(id)
     }
 
-    fn setup_timer(&self, deadline: Duration) {
-        if self.tsc_deadline {
-            distros_interrupt_pic::lapic_timer_set_tsc_deadline(distros_timer_tsc::tsc_cycles(
-                deadline,
-            ));
-        } else {
-            distros_interrupt_pic::lapic_timer_add_initial(
-                (self.lapic_freq / (1000_000 / deadline.as_micros()) as u64) as u32,
-            )
+    /// Marks current thread to be switched out on the next scheduler interrupt
+    pub fn request_switch(&mut self, request: SwitchRequest) -> bool {
+        match self.current_task.as_mut() {
+            Some(task) if task.thread => {
+                task.switch = Some(request);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn enqueue(&self, task: Box<WaitingTask>) {
+        let id = task.id;
+        without_interrupts(|| self.waiting_tasks.lock().insert(task));
+        self.task_states.set_state(id, TaskState::Waiting);
+        idle::notify();
+    }
+
+    fn park(&self, task: Box<WaitingTask>) {
+        let mut parked = self.parked.lock();
+        match parked.remove(&task.id) {
+            Some(ParkSlot::Token) => {
+                drop(parked);
+                self.enqueue(task);
+            }
+            _ => {
+                self.task_states.set_state(task.id, TaskState::Parked);
+                parked.insert(task.id, ParkSlot::Parked(task));
+            }
         }
     }
 
+    pub fn move_task(&self, task: TaskId, group: GroupId) -> Result<(), GroupError> {
+        without_interrupts(|| {
+            if !group::attach(group) {
+                return Err(GroupError::NotFound);
+            }
+            match self.task_states.set_group(task, group) {
+                Some(previous) => {
+                    group::detach(previous);
+                    Ok(())
+                }
+                None => {
+                    group::detach(group);
+                    Err(GroupError::TaskNotFound)
+                }
+            }
+        })
+    }
+
+    pub fn unpark(&self, task: TaskId) {
+        without_interrupts(|| {
+            let mut parked = self.parked.lock();
+            match parked.remove(&task) {
+                Some(ParkSlot::Parked(waiting)) => {
+                    drop(parked);
+                    distros_trace::record(TraceEvent::Wake, task.0);
+                    self.task_states.add_wakeup(task);
+                    self.enqueue(waiting);
+                }
+                _ => {
+                    if self.task_states.get_state(task).is_some() {
+                        parked.insert(task, ParkSlot::Token);
+                    }
+                }
+            }
+        })
+    }
+
+    /// Lowest stack pointer of async tasks preempted on the kernel stack. Their frames, and frames
+    /// of the scheduler interrupts that polled them, are above it.
+    fn async_stack_top(&self) -> VirtAddr {
+        let kernel_stack = KERNEL_STACK_BASE..KERNEL_STACK_BASE + KERNEL_STACK_SIZE;
+        let queue = self.waiting_tasks.lock();
+        let lowest = queue
+            .iter()
+            .filter_map(|task| match &task.state {
+                WaitingTaskState::Paused(ctx) if !task.thread => Some(ctx.stack_pointer),
+                _ => None,
+            })
+            .filter(|rsp| kernel_stack.contains(rsp))
+            .min()
+            .unwrap_or(self.async_base);
+        (lowest - ASYNC_STACK_GAP).align_down(16u64)
+    }
+
+    /// Leaves the stack of a switched out thread: the interrupt returns to [`reschedule`] on the
+    /// kernel stack, so futures are never polled on a thread stack that is resumed later
+    unsafe fn bounce(&self, stack_frame: &mut InterruptStackFrame) {
+        let frame = stack_frame.as_mut().extract_inner();
+        frame.instruction_pointer = VirtAddr::new(reschedule as u64);
+        frame.stack_pointer = self.async_stack_top();
+        frame.cpu_flags = RFlags::empty();
+        frame.code_segment = CS::get_reg();
+        frame.stack_segment = SS::get_reg();
+        tick::eoi();
+    }
+
+    /// Programs the scheduler timer to fire at TSC `deadline`
+    fn program_deadline(&self, deadline: u64) {
+        tick::arm(deadline);
+    }
+
+    /// Arms the timer for the end of the time slice, for the next sleeping task or for the end of
+    /// the group quota, whichever is earlier
+    fn arm_slice(&self, task: TaskId, nice: NiceLevel) {
+        let slice = DEADLINE - NICE_PERIOD * nice.level() as u32;
+        let now = tsc();
+        let mut deadline = now + tsc_cycles(slice);
+        if let Some(next) = timer::next_deadline() {
+            deadline = deadline.min(next);
+        }
+        if let Some(left) = group::remaining(self.task_states.group(task)) {
+            deadline = deadline.min(now + left.max(1));
+        }
+        self.program_deadline(deadline);
+    }
+
     pub unsafe fn int(&mut self, stack_frame: &mut InterruptStackFrame, regs: &mut Regs) {
-        distros_interrupt_pic::lapic_timer_disable();
-        warn!("A");
+        tick::disable();
+        watchdog::flush_report();
+        timer::fire_expired(tsc());
+        drop(self.dead_stack.take());
         if let Some(mut task) = self.current_task.take() {
-            if task.flags.contains(TaskFlags::NOPREEMPT) {
-                distros_interrupt_pic::lapic_eoi();
+            let switch = task.switch.take();
+            if task.flags.contains(TaskFlags::NOPREEMPT)
+                && switch.is_none()
+                && !watchdog::take_force_preempt()
+            {
+                self.current_task = Some(task);
+                tick::eoi();
                 x86_64::instructions::interrupts::enable();
                 return;
             }
+            watchdog::task_switched_out();
+            distros_trace::record(TraceEvent::SwitchOut, task.id.0);
+
+            if switch == Some(SwitchRequest::Exit) {
+                distros_trace::record(TraceEvent::Exit, task.id.0);
+                self.task_states.remove_state(task.id);
+                self.parked.lock().remove(&task.id);
+                // still running on this stack, it is freed on the next scheduler entry
+                self.dead_stack = task.stack_handle;
+                self.bounce(stack_frame);
+                return;
+            }
+            self.task_states.add_cpu_time(task.id, tsc() - task.started);
+            let rsp = stack_frame.stack_pointer;
+            if let Some(buffer) = find_buffer(rsp, task.id.0) {
+                let top = buffer.start() + buffer.capacity() as u64;
+                self.task_states.record_stack_usage(task.id, top - rsp);
+            }
 
             let stack = if let Some(stack) = task.stack_handle {
                 stack
@@ -216,72 +597,130 @@ impl Scheduler {
                 regs.rdi = buffer.start().as_u64() + delta;
                 handle
             };
-            warn!("BCTX");
             let ctx = TaskContext::fill_from(&stack_frame, regs);
-            warn!("CTX");
-            {
-                let mut tasks = self.waiting_tasks.lock();
-                tasks.insert(Box::new(WaitingTask {
-                    run_time: tsc(),
-                    state: WaitingTaskState::Paused(ctx.clone()),
-                    link: RBTreeLink::new(),
-                    id: task.id,
-                    nice: task.nice,
-                    flags: task.flags,
-                    buffer_handle: Some(stack),
-                }));
+            let waiting = Box::new(WaitingTask {
+                run_time: queue_key(self.task_states.group(task.id)),
+                state: WaitingTaskState::Paused(ctx),
+                link: RBTreeLink::new(),
+                id: task.id,
+                nice: task.nice,
+                flags: task.flags,
+                buffer_handle: Some(stack),
+                thread: task.thread,
+                recovery: task.recovery,
+                fpu: task.fpu,
+            });
+            if switch == Some(SwitchRequest::Park) {
+                self.park(waiting);
+            } else {
+                self.waiting_tasks.lock().insert(waiting);
+                self.task_states.set_state(task.id, TaskState::Waiting);
+            }
+            if task.thread {
+                self.bounce(stack_frame);
+                return;
             }
-            self.task_states.set_state(task.id, TaskState::Waiting);
         }
 
         loop {
+            timer::fire_expired(tsc());
             let task = {
                 let mut tasks = self.waiting_tasks.lock();
-                tasks.front_mut().remove()
+                self.task_states.pop_runnable(&mut tasks, current_cpu())
             };
             match task {
                 None => {
-                    distros_interrupt_pic::lapic_eoi();
-                    x86_64::instructions::interrupts::enable();
-                    x86_64::instructions::hlt();
+                    tick::eoi();
+                    // throttled tasks can be waiting for the next quota period
+                    let deadline = [timer::next_deadline(), group::next_refill()]
+                        .into_iter()
+                        .flatten()
+                        .min();
+                    idle::enter(deadline, |deadline| self.program_deadline(deadline));
+                    tick::disable();
                 }
-                Some(task) => {
+                Some(mut task) => {
+                    task.fpu.activate();
                     self.current_task = Some(RunningTask {
                         id: task.id,
                         nice: task.nice,
                         flags: task.flags,
                         stack_handle: task.buffer_handle,
+                        started: tsc(),
+                        thread: task.thread,
+                        switch: None,
+                        recovery: task.recovery,
+                        exited: false,
+                        fpu: task.fpu,
                     });
+                    watchdog::task_switched_in(task.id);
+                    distros_trace::record(TraceEvent::SwitchIn, task.id.0);
                     match task.state {
                         WaitingTaskState::Paused(ctx) => {
                             // if paused once - cannot be NOPREEMPT
                             self.task_states.set_state(task.id, TaskState::Running);
-                            let deadline = DEADLINE - NICE_PERIOD * task.nice.level() as u32;
-                            self.setup_timer(deadline);
+                            self.arm_slice(task.id, task.nice);
                             ctx.save_info(stack_frame.as_mut().extract_inner(), regs);
-                            distros_interrupt_pic::lapic_timer_enable();
-                            distros_interrupt_pic::lapic_eoi();
+                            tick::eoi();
                             x86_64::instructions::interrupts::enable();
-                            warn!("N");
                             return;
                         }
                         WaitingTaskState::Ready(mut future) => {
                             self.task_states.set_state(task.id, TaskState::Running);
-                            let waker = Arc::new(TaskWaker::new(&self.waiting_tasks));
+                            let waker = Arc::new(TaskWaker::new(
+                                task.id,
+                                &self.waiting_tasks,
+                                &self.task_states,
+                            ));
                             if !task.flags.contains(TaskFlags::NOPREEMPT) {
-                                let deadline = DEADLINE - NICE_PERIOD * task.nice.level() as u32;
-                                self.setup_timer(deadline);
-                                distros_interrupt_pic::lapic_timer_enable();
+                                self.arm_slice(task.id, task.nice);
                             }
-                            distros_interrupt_pic::lapic_eoi();
+                            let task_waker: Waker = waker.clone().into();
+                            let mut point = RecoveryPoint::default();
+                            let point = NonNull::from(&mut point);
+                            if let Some(current) = self.current_task.as_mut() {
+                                current.recovery = Some(point);
+                            }
+                            let mut result = Poll::Pending;
+                            tick::eoi();
                             x86_64::instructions::interrupts::enable();
-                            let result = future
-                                .as_mut()
-                                .poll(&mut Context::from_waker(&waker.clone().into()));
+                            let completed = guarded(point, || {
+                                result =
+                                    future.as_mut().poll(&mut Context::from_waker(&task_waker));
+                            });
                             x86_64::instructions::interrupts::disable();
-                            distros_interrupt_pic::lapic_timer_disable();
+                            tick::disable();
+                            watchdog::task_switched_out();
+                            distros_trace::record(TraceEvent::SwitchOut, task.id.0);
+                            if let Some(current) = self.current_task.as_ref() {
+                                self.task_states
+                                    .add_cpu_time(task.id, tsc() - current.started);
+                            }
+                            if !completed {
+                                // the task panicked and `isolate_panic` released what lives
+                                // outside of the scheduler. The future can be half-updated, so
+                                // it is dropped in its own guarded call and leaked only if the
+                                // drop panics too
+                                let mut point = RecoveryPoint::default();
+                                let point = NonNull::from(&mut point);
+                                if let Some(current) = self.current_task.as_mut() {
+                                    current.recovery = Some(point);
+                                    current.exited = true;
+                                }
+                                x86_64::instructions::interrupts::enable();
+                                guarded(point, move || drop(future));
+                                x86_64::instructions::interrupts::disable();
+                                self.current_task = None;
+                                distros_trace::record(TraceEvent::Exit, task.id.0);
+                                self.task_states.remove_state(task.id);
+                                continue;
+                            }
                             match result {
-                                Poll::Ready(_) => self.task_states.remove_state(task.id),
+                                Poll::Ready(_) => {
+                                    self.current_task = None;
+                                    distros_trace::record(TraceEvent::Exit, task.id.0);
+                                    self.task_states.remove_state(task.id)
+                                }
                                 Poll::Pending => {
                                     let taken_current = self.current_task.take().unwrap();
                                     let ntask = WaitingTask {
@@ -289,11 +728,15 @@ impl Scheduler {
                                         nice: task.nice,
                                         link: RBTreeLink::new(),
                                         state: WaitingTaskState::Ready(future),
-                                        run_time: tsc(),
+                                        run_time: queue_key(self.task_states.group(task.id)),
                                         flags: task.flags,
                                         buffer_handle: taken_current.stack_handle,
+                                        thread: false,
+                                        recovery: None,
+                                        fpu: taken_current.fpu,
                                     };
                                     if waker.wake_called.load(Ordering::SeqCst) {
+                                        self.task_states.set_state(task.id, TaskState::Waiting);
                                         let mut queue = self.waiting_tasks.lock();
                                         queue.insert(Box::new(ntask));
                                     } else {
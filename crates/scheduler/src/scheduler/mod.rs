@@ -1,19 +1,18 @@
+use crate::group::{GroupError, GroupId};
+use crate::registry::Executable;
 use crate::scheduler::context::Regs;
-use crate::scheduler::logic::Scheduler;
-use crate::{NiceLevel, TaskFlags, TaskId};
-use alloc::boxed::Box;
+pub use crate::scheduler::logic::TaskStatus;
+use crate::scheduler::logic::{CurrentTask, Scheduler};
+use crate::{idle, CpuSet, NiceLevel, TaskFlags, TaskId};
 use core::arch::{asm, naked_asm};
-use core::future::Future;
-use core::pin::Pin;
-use distros_interrupt::OverrideMode;
-use distros_timer_tsc::tsc;
-use log::debug;
-use x2apic::lapic::{TimerDivide, TimerMode};
-use x86_64::instructions::hlt;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
 
 mod context;
 mod logic;
+mod tick;
 
 static mut SCHED: Option<Scheduler> = None;
 
@@ -24,99 +23,192 @@ pub enum TaskState {
     Parked,
 }
 
+/// Why a thread gives up the CPU on its own
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub(crate) enum SwitchRequest {
+    Yield,
+    Park,
+    Exit,
+}
+
+/// Interrupt entry that saves registers of the interrupted code and passes them to `$int`, which
+/// may switch to another task
+macro_rules! switch_entry {
+    ($(#[$attr:meta])* $vis:vis $name:ident => $int:ident) => {
+        $(#[$attr])*
+        #[unsafe(naked)]
+        $vis extern "x86-interrupt" fn $name(frame: InterruptStackFrame) {
+            unsafe {
+                naked_asm!(
+                "mov     qword ptr [rsp - 120], r15",
+                "mov     qword ptr [rsp - 112], r14",
+                "mov     qword ptr [rsp - 104], r13",
+                "mov     qword ptr [rsp - 96], r12",
+                "mov     qword ptr [rsp - 88], r11",
+                "mov     qword ptr [rsp - 80], r10",
+                "mov     qword ptr [rsp - 72], r9",
+                "mov     qword ptr [rsp - 64], r8",
+                "mov     qword ptr [rsp - 56], rsi",
+                "mov     qword ptr [rsp - 48], rdi",
+                "mov     qword ptr [rsp - 40], rbp",
+                "mov     qword ptr [rsp - 32], rdx",
+                "mov     qword ptr [rsp - 24], rcx",
+                "mov     qword ptr [rsp - 16], rbx",
+                "mov     qword ptr [rsp - 8], rax",
+                "sub     rsp, 168",
+                "lea     rdi, [rsp + 8]",
+                "call    {}",
+                "add     rsp, 168",
+                "mov     r15, qword ptr [rsp - 120]",
+                "mov     r14, qword ptr [rsp - 112]",
+                "mov     r13, qword ptr [rsp - 104]",
+                "mov     r12, qword ptr [rsp - 96]",
+                "mov     r11, qword ptr [rsp - 88]",
+                "mov     r10, qword ptr [rsp - 80]",
+                "mov     r9, qword ptr [rsp - 72]",
+                "mov     r8, qword ptr [rsp - 64]",
+                "mov     rsi, qword ptr [rsp - 56]",
+                "mov     rdi, qword ptr [rsp - 48]",
+                "mov     rbp, qword ptr [rsp - 40]",
+                "mov     rdx, qword ptr [rsp - 32]",
+                "mov     rcx, qword ptr [rsp - 24]",
+                "mov     rbx, qword ptr [rsp - 16]",
+                "mov     rax, qword ptr [rsp - 8]",
+                "iretq",
+                sym $int
+                )
+            }
+        }
+    };
+}
+
+switch_entry!(pub switch_context => switch_context_int);
+/// Entry of the PIT interrupt when it drives the scheduler, see [`tick`]
+switch_entry!(pub(crate) pit_switch_context => pit_switch_context_int);
+
+/// Continues scheduling on the kernel stack after a thread was switched out. Raises the scheduler
+/// interrupt ([`distros_interrupt_pic::INT_LAPIC_TIMER`]) with no current task.
 #[unsafe(naked)]
-pub extern "x86-interrupt" fn switch_context(frame: InterruptStackFrame) {
-    unsafe {
-        naked_asm!(
-        "mov     qword ptr [rsp - 120], r15",
-        "mov     qword ptr [rsp - 112], r14",
-        "mov     qword ptr [rsp - 104], r13",
-        "mov     qword ptr [rsp - 96], r12",
-        "mov     qword ptr [rsp - 88], r11",
-        "mov     qword ptr [rsp - 80], r10",
-        "mov     qword ptr [rsp - 72], r9",
-        "mov     qword ptr [rsp - 64], r8",
-        "mov     qword ptr [rsp - 56], rsi",
-        "mov     qword ptr [rsp - 48], rdi",
-        "mov     qword ptr [rsp - 40], rbp",
-        "mov     qword ptr [rsp - 32], rdx",
-        "mov     qword ptr [rsp - 24], rcx",
-        "mov     qword ptr [rsp - 16], rbx",
-        "mov     qword ptr [rsp - 8], rax",
-        "sub     rsp, 168",
-        "lea     rdi, [rsp + 8]",
-        "call    {}",
-        "add     rsp, 168",
-        "mov     r15, qword ptr [rsp - 120]",
-        "mov     r14, qword ptr [rsp - 112]",
-        "mov     r13, qword ptr [rsp - 104]",
-        "mov     r12, qword ptr [rsp - 96]",
-        "mov     r11, qword ptr [rsp - 88]",
-        "mov     r10, qword ptr [rsp - 80]",
-        "mov     r9, qword ptr [rsp - 72]",
-        "mov     r8, qword ptr [rsp - 64]",
-        "mov     rsi, qword ptr [rsp - 56]",
-        "mov     rdi, qword ptr [rsp - 48]",
-        "mov     rbp, qword ptr [rsp - 40]",
-        "mov     rdx, qword ptr [rsp - 32]",
-        "mov     rcx, qword ptr [rsp - 24]",
-        "mov     rbx, qword ptr [rsp - 16]",
-        "mov     rax, qword ptr [rsp - 8]",
-        "iretq",
-        sym switch_context_int
-        )
-    }
+pub(crate) extern "C" fn reschedule() -> ! {
+    unsafe { naked_asm!("2:", "int 32", "jmp 2b") }
 }
 
 #[inline]
 unsafe extern "C" fn switch_context_int(mut stack_frame: InterruptStackFrame, regs: &mut Regs) {
-    if let Some(sched) = SCHED.as_mut() {
-        sched.int(&mut stack_frame, regs);
+    // not timed, the idle loop runs inside this handler
+    distros_interrupt::count_interrupt(distros_interrupt_pic::INT_LAPIC_TIMER);
+    schedule(&mut stack_frame, regs);
+    stack_frame.iretq()
+}
+
+#[inline]
+unsafe extern "C" fn pit_switch_context_int(mut stack_frame: InterruptStackFrame, regs: &mut Regs) {
+    if tick::pit_tick() {
+        schedule(&mut stack_frame, regs);
     }
     stack_frame.iretq()
 }
 
+unsafe fn schedule(stack_frame: &mut InterruptStackFrame, regs: &mut Regs) {
+    if idle::in_idle() {
+        // woken up from the idle loop, which is already inside the scheduler
+        tick::eoi();
+    } else if let Some(sched) = SCHED.as_mut() {
+        sched.int(stack_frame, regs);
+    }
+}
+
 pub fn init() {
-    let has_tsc_deadline = distros_cpuid::get_feature_info().has_tsc_deadline();
+    distros_cpuid::set_cpu_id(distros_interrupt_pic::lapic_id);
+    distros_cpuid::register_cpu().expect("Failed to register bootstrap CPU");
     unsafe {
-        SCHED = Some(Scheduler::new(has_tsc_deadline));
-    }
-    distros_interrupt_pic::lapic_timer_disable();
-    if has_tsc_deadline {
-        debug!("Scheduler will use TSC-deadline mode on LAPIC");
-        distros_interrupt_pic::lapic_timer_set_mode(TimerMode::TscDeadline, TimerDivide::Div2);
-    } else {
-        debug!("Scheduler will use counter on LAPIC");
-        distros_interrupt_pic::lapic_timer_set_mode(TimerMode::OneShot, Scheduler::LAPIC_DIVIDER);
+        SCHED = Some(Scheduler::new());
     }
-    distros_interrupt::set_handler(
-        distros_interrupt_pic::INT_LAPIC_TIMER,
-        switch_context,
-        OverrideMode::Panic,
-    );
+    tick::init();
+    idle::init();
+    distros_pmu::set_task_id(crate::watchdog::current_task);
+    distros_trace::init_cpu(crate::current_cpu());
 }
 
+/// Enters the scheduler. The idle loop lives in the scheduler interrupt, so this only has to wait
+/// for the first tick.
 pub fn start() -> ! {
-    let has_tsc_deadline = distros_cpuid::get_feature_info().has_tsc_deadline();
-    if has_tsc_deadline {
-        distros_interrupt_pic::lapic_timer_set_tsc_deadline(tsc());
-    } else {
-        distros_interrupt_pic::lapic_timer_add_initial(10000);
+    let rsp: u64;
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp);
+        SCHED
+            .as_mut()
+            .expect("Scheduler not initialized")
+            .set_async_base(VirtAddr::new(rsp));
     }
-    distros_interrupt_pic::lapic_timer_enable();
+    kick();
     loop {
-        hlt();
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Fires scheduler interrupt as soon as possible. Safe to call from NMI with LAPIC.
+pub(crate) fn kick() {
+    tick::kick();
+}
+
+/// Makes CPU with APIC id `cpu` enter the scheduler as soon as possible
+pub fn kick_cpu(cpu: u32) {
+    if cpu == distros_interrupt_pic::lapic_id() {
+        without_interrupts(kick);
+    } else {
+        distros_interrupt_pic::send_ipi(
+            distros_interrupt_pic::IpiTarget::Cpu(cpu),
+            distros_interrupt_pic::INT_LAPIC_TIMER,
+        );
     }
 }
 
 pub fn add(
-    task: Pin<Box<dyn Future<Output = ()>>>,
+    task: Executable,
     nice_level: NiceLevel,
     flags: TaskFlags,
+    affinity: CpuSet,
+    group: GroupId,
 ) -> TaskId {
     unsafe {
         let sched = SCHED.as_ref().expect("Scheduler not initialized");
-        sched.add(task, nice_level, flags)
+        sched.add(task, nice_level, flags, affinity, group)
+    }
+}
+
+/// Switches current thread out by raising the scheduler interrupt. Returns `false` without
+/// switching if the current task is not a thread.
+pub(crate) fn switch_current(request: SwitchRequest) -> bool {
+    without_interrupts(|| unsafe {
+        let sched = SCHED.as_mut().expect("Scheduler not initialized");
+        if !sched.request_switch(request) {
+            return false;
+        }
+        // INT_LAPIC_TIMER
+        asm!("int 32");
+        true
+    })
+}
+
+pub(crate) fn unpark(task_id: TaskId) {
+    unsafe {
+        let sched = SCHED.as_ref().expect("Scheduler not initialized");
+        sched.unpark(task_id)
+    }
+}
+
+pub fn set_affinity(task_id: TaskId, affinity: CpuSet) -> bool {
+    unsafe {
+        let sched = SCHED.as_ref().expect("Scheduler not initialized");
+        sched.set_affinity(task_id, affinity)
+    }
+}
+
+pub fn move_task(task_id: TaskId, group: GroupId) -> Result<(), GroupError> {
+    unsafe {
+        let sched = SCHED.as_ref().expect("Scheduler not initialized");
+        sched.move_task(task_id, group)
     }
 }
 
@@ -127,6 +219,20 @@ pub fn get_state(task_id: TaskId) -> Option<TaskState> {
     }
 }
 
+pub fn get_status(task_id: TaskId) -> Option<TaskStatus> {
+    unsafe {
+        let sched = SCHED.as_ref().expect("Scheduler not initialized");
+        sched.get_status(task_id)
+    }
+}
+
+pub(crate) fn current_running() -> Option<CurrentTask> {
+    unsafe {
+        let sched = SCHED.as_ref().expect("Scheduler not initialized");
+        sched.current_running()
+    }
+}
+
 pub fn current() -> Option<TaskId> {
     unsafe {
         let sched = SCHED.as_ref().expect("Scheduler not initialized");
@@ -0,0 +1,131 @@
+//! Timer behind the scheduler interrupt.
+//!
+//! The scheduler runs on [`INT_LAPIC_TIMER`]. Normally the LAPIC timer raises it directly. Without
+//! LAPIC the PIT is programmed in one-shot mode instead: its IRQ arrives through PIC8259 on its own
+//! vector, and its entry runs the scheduler directly once the deadline is reached.
+use crate::scheduler::{pit_switch_context, switch_context};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use distros_interrupt::{InterruptId, OverrideMode};
+use distros_interrupt_pic::{InterruptController, IsaIrq, INT_LAPIC_TIMER};
+use distros_timer_pit::OperatingMode;
+use distros_timer_tsc::{tsc, tsc_duration};
+use log::debug;
+use x2apic::lapic::{TimerDivide, TimerMode};
+
+const LAPIC_DIVIDER: u64 = 32;
+const LAPIC_TIMER_DIVIDE: TimerDivide = TimerDivide::Div32;
+const PIT_FREQUENCY: u128 = 1193180;
+
+static PIT: AtomicBool = AtomicBool::new(false);
+static TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+/// LAPIC timer ticks per second
+static LAPIC_FREQ: AtomicU64 = AtomicU64::new(0);
+static PIT_VECTOR: AtomicU8 = AtomicU8::new(0);
+/// TSC when the PIT interrupt should run the scheduler, `0` when disarmed
+static PIT_DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn init() {
+    distros_interrupt::set_handler(INT_LAPIC_TIMER, switch_context, OverrideMode::Panic);
+    if distros_interrupt_pic::controller() == InterruptController::Pic8259 {
+        PIT.store(true, Ordering::Release);
+        let vector = distros_interrupt_pic::set_irq_handler(IsaIrq::Pit.into(), pit_switch_context)
+            .expect("Failed to route PIT interrupt");
+        PIT_VECTOR.store(vector.int(), Ordering::Release);
+        debug!("Scheduler will use PIT through PIC8259");
+        return;
+    }
+    distros_interrupt_pic::lapic_timer_disable();
+    if distros_cpuid::get_feature_info().has_tsc_deadline() {
+        TSC_DEADLINE.store(true, Ordering::Release);
+        debug!("Scheduler will use TSC-deadline mode on LAPIC");
+        distros_interrupt_pic::lapic_timer_set_mode(TimerMode::TscDeadline, TimerDivide::Div2);
+    } else {
+        let freq = distros_cpuid::get_processor_frequency_info()
+            .map(|s| s.bus_frequency())
+            .unwrap_or(100) as u64
+            * 1000
+            * 1000
+            / LAPIC_DIVIDER;
+        LAPIC_FREQ.store(freq, Ordering::Release);
+        debug!("Scheduler will use counter on LAPIC");
+        distros_interrupt_pic::lapic_timer_set_mode(TimerMode::OneShot, LAPIC_TIMER_DIVIDE);
+    }
+}
+
+fn pit() -> bool {
+    PIT.load(Ordering::Acquire)
+}
+
+/// Acknowledges the scheduler interrupt. PIT interrupts are acknowledged by their own handler.
+pub(crate) fn eoi() {
+    if !pit() {
+        distros_interrupt_pic::lapic_eoi();
+    }
+}
+
+pub(crate) fn disable() {
+    if pit() {
+        PIT_DEADLINE.store(0, Ordering::Release);
+    } else {
+        distros_interrupt_pic::lapic_timer_disable();
+    }
+}
+
+/// Fires the scheduler interrupt at TSC `deadline`
+pub(crate) fn arm(deadline: u64) {
+    if pit() {
+        PIT_DEADLINE.store(deadline.max(1), Ordering::Release);
+        program_pit(deadline);
+    } else if TSC_DEADLINE.load(Ordering::Acquire) {
+        distros_interrupt_pic::lapic_timer_set_tsc_deadline(deadline);
+        distros_interrupt_pic::lapic_timer_enable();
+    } else {
+        let delay = tsc_duration(deadline.saturating_sub(tsc()));
+        let ticks = LAPIC_FREQ.load(Ordering::Acquire) as u128 * delay.as_nanos() / 1_000_000_000;
+        distros_interrupt_pic::lapic_timer_set_initial(ticks.clamp(1, u32::MAX as u128) as u32);
+        distros_interrupt_pic::lapic_timer_enable();
+    }
+}
+
+/// Fires the scheduler interrupt as soon as possible. Safe to call from NMI with LAPIC.
+pub(crate) fn kick() {
+    if pit() {
+        arm(tsc());
+    } else if TSC_DEADLINE.load(Ordering::Acquire) {
+        distros_interrupt_pic::lapic_timer_set_tsc_deadline(tsc());
+        distros_interrupt_pic::lapic_timer_enable();
+    } else {
+        distros_interrupt_pic::lapic_timer_add_initial(1);
+        distros_interrupt_pic::lapic_timer_enable();
+    }
+}
+
+/// Starts PIT countdown to `deadline`. Its 16-bit counter covers about 55ms, later deadlines are
+/// reached in several interrupts.
+fn program_pit(deadline: u64) {
+    let delay = tsc_duration(deadline.saturating_sub(tsc()));
+    let count = PIT_FREQUENCY * delay.as_nanos() / 1_000_000_000;
+    distros_timer_pit::setup_timer(
+        OperatingMode::InterruptOnTerminalCount,
+        false,
+        count.clamp(1, u16::MAX as u128) as u16,
+    );
+}
+
+/// Acknowledges the PIT interrupt. Returns `true` if the deadline is reached and the scheduler
+/// should run, otherwise the countdown to the deadline continues.
+pub(crate) fn pit_tick() -> bool {
+    // not timed, like the scheduler interrupt
+    distros_interrupt::count_interrupt(InterruptId::new(PIT_VECTOR.load(Ordering::Acquire)));
+    distros_interrupt_pic::eoi();
+    let deadline = PIT_DEADLINE.load(Ordering::Acquire);
+    if deadline == 0 {
+        return false;
+    }
+    if deadline > tsc() {
+        program_pit(deadline);
+        return false;
+    }
+    PIT_DEADLINE.store(0, Ordering::Release);
+    true
+}
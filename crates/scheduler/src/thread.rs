@@ -0,0 +1,53 @@
+//! Blocking primitives for kernel threads, see [`TaskBuilder::thread`](crate::TaskBuilder::thread)
+use crate::scheduler::{self, SwitchRequest};
+use crate::TaskId;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::pin;
+use core::task::{Context, Waker};
+use core::time::Duration;
+use distros_timer_tsc::{tsc, tsc_cycles};
+
+pub(crate) extern "C" fn thread_entry(body: *mut Box<dyn FnOnce() + Send>) -> ! {
+    let body = unsafe { Box::from_raw(body) };
+    body();
+    scheduler::switch_current(SwitchRequest::Exit);
+    unreachable!("Exited thread was scheduled again")
+}
+
+/// Gives up the rest of the time slice. Does nothing outside of a thread.
+pub fn yield_now() {
+    scheduler::switch_current(SwitchRequest::Yield);
+}
+
+/// Blocks current thread until [`unpark`] is called for it. If the thread was unparked before,
+/// returns immediately. Does nothing outside of a thread.
+pub fn park() {
+    scheduler::switch_current(SwitchRequest::Park);
+}
+
+/// Wakes parked thread up. Threads that are not parked now return from their next [`park`] right
+/// away.
+pub fn unpark(task: TaskId) {
+    scheduler::unpark(task);
+}
+
+struct Unparker(TaskId);
+
+impl Wake for Unparker {
+    fn wake(self: Arc<Self>) {
+        unpark(self.0);
+    }
+}
+
+/// Blocks current thread for at least `duration` without occupying the CPU
+pub fn sleep(duration: Duration) {
+    let waker = Waker::from(Arc::new(Unparker(crate::current_task())));
+    let mut context = Context::from_waker(&waker);
+    let mut timer = pin!(crate::timer::sleep_until(tsc() + tsc_cycles(duration)));
+    while timer.as_mut().poll(&mut context).is_pending() {
+        park();
+    }
+}
@@ -0,0 +1,83 @@
+//! TSC deadline queue for sleeping tasks. The scheduler fires expired timers on every tick and
+//! programs the next deadline before going idle, so an idle CPU wakes up only when needed.
+use alloc::collections::BTreeMap;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use distros_timer_tsc::{tsc, tsc_cycles};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+type TimerKey = (u64, u64);
+
+static TIMERS: Mutex<BTreeMap<TimerKey, Waker>> = Mutex::new(BTreeMap::new());
+static SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Future returned by [`sleep`] and [`sleep_until`]
+pub struct Sleep {
+    deadline: u64,
+    key: Option<TimerKey>,
+}
+
+/// Suspends current task for at least `duration`
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(tsc() + tsc_cycles(duration))
+}
+
+/// Suspends current task until TSC reaches `deadline`
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep {
+        deadline,
+        key: None,
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if tsc() >= self.deadline {
+            if let Some(key) = self.key.take() {
+                without_interrupts(|| TIMERS.lock().remove(&key));
+            }
+            return Poll::Ready(());
+        }
+        let key = *self
+            .key
+            .get_or_insert_with(|| (self.deadline, SEQ.fetch_add(1, Ordering::Relaxed)));
+        without_interrupts(|| TIMERS.lock().insert(key, cx.waker().clone()));
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            without_interrupts(|| TIMERS.lock().remove(&key));
+        }
+    }
+}
+
+/// Closest deadline in the queue
+pub(crate) fn next_deadline() -> Option<u64> {
+    without_interrupts(|| TIMERS.lock().first_key_value().map(|(key, _)| key.0))
+}
+
+/// Wakes all timers with deadline before `now`
+pub(crate) fn fire_expired(now: u64) {
+    loop {
+        let waker = without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            match timers.first_key_value() {
+                Some((key, _)) if key.0 <= now => timers.pop_first().map(|(_, waker)| waker),
+                _ => None,
+            }
+        });
+        match waker {
+            Some(waker) => waker.wake(),
+            None => break,
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! Soft and hard lockup detection.
+//!
+//! A free HPET comparator periodically checks the task that is currently running. When a task
+//! runs longer than the budget without yielding (which is possible for `no_preempt` tasks), the
+//! watchdog reports the task name and instruction pointer, and can force preemption or panic.
+//! In NMI mode the check also fires while interrupts are disabled, so a CPU stuck with
+//! interrupts off is detected too.
+//!
+//! The check itself only records the lockup in per-CPU atomics, because it can interrupt code that
+//! holds the logger or registry locks. Reports are printed from the next scheduler interrupt.
+use crate::cpu::{current_cpu, MAX_CPUS};
+use crate::TaskId;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use distros_interrupt::{InterruptId, IrqContext, IrqReturn};
+use distros_interrupt_pic::IrqMode;
+use distros_timer_hpet::HpetError;
+use distros_timer_tsc::{tsc, tsc_cycles, tsc_duration};
+use log::{error, info, warn};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrame;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[repr(u8)]
+pub enum WatchdogAction {
+    /// Only log stuck task
+    Log = 0,
+    /// Log and preempt the task even if it is `no_preempt`
+    Preempt = 1,
+    Panic = 2,
+}
+
+#[derive(Clone, Debug)]
+pub struct WatchdogConfig {
+    /// How long a task can run without yielding
+    pub budget: Duration,
+    /// How often the watchdog checks the CPU
+    pub period: Duration,
+    pub action: WatchdogAction,
+    /// Deliver checks as NMI, which also catches CPUs stuck with interrupts disabled
+    pub nmi: bool,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            budget: Duration::from_secs(2),
+            period: Duration::from_millis(250),
+            action: WatchdogAction::Log,
+            nmi: true,
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static BUDGET: AtomicU64 = AtomicU64::new(0);
+static ACTION: AtomicU8 = AtomicU8::new(WatchdogAction::Log as u8);
+/// HPET comparator that drives the checks
+static TIMER: AtomicU8 = AtomicU8::new(0);
+
+/// Lockup saved by the check until it is logged outside of NMI
+struct Report {
+    pending: AtomicBool,
+    task: AtomicU64,
+    rip: AtomicU64,
+    cycles: AtomicU64,
+}
+
+impl Report {
+    const fn new() -> Self {
+        Report {
+            pending: AtomicBool::new(false),
+            task: AtomicU64::new(0),
+            rip: AtomicU64::new(0),
+            cycles: AtomicU64::new(0),
+        }
+    }
+
+    fn save(&self, task: u64, rip: u64, cycles: u64) {
+        self.task.store(task, Ordering::Relaxed);
+        self.rip.store(rip, Ordering::Relaxed);
+        self.cycles.store(cycles, Ordering::Relaxed);
+        self.pending.store(true, Ordering::Release);
+    }
+
+    /// Returns `(task, rip, cycles)` of the saved lockup once
+    fn take(&self) -> Option<(u64, u64, u64)> {
+        if !self.pending.swap(false, Ordering::AcqRel) {
+            return None;
+        }
+        Some((
+            self.task.load(Ordering::Relaxed),
+            self.rip.load(Ordering::Relaxed),
+            self.cycles.load(Ordering::Relaxed),
+        ))
+    }
+}
+
+struct CpuWatch {
+    /// Task that is running now, `0` when the scheduler itself or the idle loop runs
+    current: AtomicU64,
+    running_since: AtomicU64,
+    /// TSC when interrupts were first seen disabled, `0` if they were enabled on the last check
+    irq_off_since: AtomicU64,
+    reported: AtomicBool,
+    force_preempt: AtomicBool,
+    stuck_task: Report,
+    irq_off: Report,
+}
+
+impl CpuWatch {
+    const fn new() -> Self {
+        CpuWatch {
+            current: AtomicU64::new(0),
+            running_since: AtomicU64::new(0),
+            irq_off_since: AtomicU64::new(0),
+            reported: AtomicBool::new(false),
+            force_preempt: AtomicBool::new(false),
+            stuck_task: Report::new(),
+            irq_off: Report::new(),
+        }
+    }
+}
+
+static CPUS: [CpuWatch; MAX_CPUS] = [const { CpuWatch::new() }; MAX_CPUS];
+
+fn cpu() -> &'static CpuWatch {
+    &CPUS[current_cpu()]
+}
+
+pub fn init(config: WatchdogConfig) -> Result<(), HpetError> {
+    BUDGET.store(tsc_cycles(config.budget), Ordering::Release);
+    ACTION.store(config.action as u8, Ordering::Release);
+    let (vector, mode) = if config.nmi {
+        distros_interrupt::register_nmi_handler(check);
+        (InterruptId::new(2), IrqMode::NonMaskable)
+    } else {
+        let vector = distros_interrupt::alloc_vector().expect("Failed to alloc new interrupt");
+        distros_interrupt::register_handler(vector, "watchdog", watchdog_handler)
+            .expect("Failed to register watchdog handler");
+        (vector, IrqMode::Fixed)
+    };
+    let timer = distros_timer_hpet::start_periodic(config.period, vector, mode, true)?;
+    TIMER.store(timer, Ordering::Release);
+    ENABLED.store(true, Ordering::Release);
+    info!("Watchdog started: {:?}", config);
+    Ok(())
+}
+
+fn watchdog_handler(context: &IrqContext) -> IrqReturn {
+    if check(context.frame) {
+        IrqReturn::Handled
+    } else {
+        IrqReturn::Unhandled
+    }
+}
+
+pub(crate) fn task_switched_in(task: TaskId) {
+    let cpu = cpu();
+    cpu.running_since.store(tsc(), Ordering::Release);
+    cpu.current.store(task.0, Ordering::Release);
+    cpu.reported.store(false, Ordering::Release);
+}
+
+/// Id of the task running on this CPU, `0` outside of tasks. Safe to call from NMI.
+pub(crate) fn current_task() -> u64 {
+    cpu().current.load(Ordering::Acquire)
+}
+
+pub(crate) fn task_switched_out() {
+    cpu().current.store(0, Ordering::Release);
+}
+
+/// Returns `true` once after the watchdog requested preemption of a `no_preempt` task
+pub(crate) fn take_force_preempt() -> bool {
+    cpu().force_preempt.swap(false, Ordering::AcqRel)
+}
+
+fn action() -> WatchdogAction {
+    match ACTION.load(Ordering::Acquire) {
+        0 => WatchdogAction::Log,
+        1 => WatchdogAction::Preempt,
+        _ => WatchdogAction::Panic,
+    }
+}
+
+fn task_name(task: u64) -> alloc::string::String {
+    crate::get_name(TaskId(task))
+        .flatten()
+        .unwrap_or_else(|| alloc::string::String::from("<unnamed>"))
+}
+
+/// Logs reports saved by the checks. Called by the scheduler from its own interrupt.
+///
+/// CPUs stuck with interrupts disabled never get here, so their reports are printed by any CPU
+/// that does. Stuck tasks are reported by their own CPU, which is also the one to panic.
+pub(crate) fn flush_report() {
+    for (index, watch) in CPUS.iter().enumerate() {
+        if let Some((_, rip, cycles)) = watch.irq_off.take() {
+            error!(
+                "Watchdog: CPU {} stuck with interrupts disabled for {:?}, RIP = {:#x}",
+                index,
+                tsc_duration(cycles),
+                rip
+            );
+        }
+    }
+    let Some((task, rip, cycles)) = cpu().stuck_task.take() else {
+        return;
+    };
+    if action() == WatchdogAction::Panic {
+        panic!(
+            "Watchdog: task {} ({}) is running for {:?} without yielding, RIP = {:#x}",
+            task,
+            task_name(task),
+            tsc_duration(cycles),
+            rip
+        );
+    }
+    warn!(
+        "Watchdog: task {} ({}) is running for {:?} without yielding, RIP = {:#x}",
+        task,
+        task_name(task),
+        tsc_duration(cycles),
+        rip
+    );
+}
+
+/// Runs from NMI, so it must not take locks: everything it finds is saved for [`flush_report`]
+fn check(frame: &InterruptStackFrame) -> bool {
+    if !ENABLED.load(Ordering::Acquire)
+        || !distros_timer_hpet::take_level_int(TIMER.load(Ordering::Acquire))
+    {
+        return false;
+    }
+    let cpu = cpu();
+    let now = tsc();
+    let budget = BUDGET.load(Ordering::Acquire);
+    let rip = frame.instruction_pointer.as_u64();
+
+    if frame.cpu_flags.contains(RFlags::INTERRUPT_FLAG) {
+        cpu.irq_off_since.store(0, Ordering::Release);
+    } else {
+        let since =
+            match cpu
+                .irq_off_since
+                .compare_exchange(0, now, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => now,
+                Err(since) => since,
+            };
+        if now - since > budget {
+            cpu.irq_off_since.store(now, Ordering::Release);
+            if action() == WatchdogAction::Panic {
+                // this CPU won't reach the scheduler again, so the panic can't be deferred. The
+                // panic handler force-unlocks the logger, so it doesn't wait for a held lock
+                panic!(
+                    "Watchdog: CPU stuck with interrupts disabled, RIP = {:#x}",
+                    rip
+                );
+            }
+            cpu.irq_off.save(0, rip, now - since);
+        }
+    }
+
+    let task = cpu.current.load(Ordering::Acquire);
+    if task == 0 || cpu.reported.load(Ordering::Acquire) {
+        return true;
+    }
+    let running = now.saturating_sub(cpu.running_since.load(Ordering::Acquire));
+    if running <= budget {
+        return true;
+    }
+    cpu.reported.store(true, Ordering::Release);
+    cpu.stuck_task.save(task, rip, running);
+    if action() == WatchdogAction::Preempt {
+        cpu.force_preempt.store(true, Ordering::Release);
+    }
+    crate::scheduler::kick();
+    true
+}
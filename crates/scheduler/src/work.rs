@@ -0,0 +1,75 @@
+//! Worker threads of deferred work, see [`distros_work`]
+use crate::{CpuSet, NiceLevel, TaskBuilder, TaskId};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use distros_work::{Queue, WorkItem};
+
+/// Runs work of `queue` until it is closed
+fn run(queue: &Queue) {
+    loop {
+        queue.run();
+        if queue.is_closed() && queue.is_empty() {
+            return;
+        }
+        crate::park();
+    }
+}
+
+pub(crate) fn init() {
+    distros_work::set_wake(|task| crate::unpark(TaskId(task)));
+    distros_work::adopt_early_work();
+}
+
+/// Starts high-priority worker of `cpu`. Deferred work runs in place of interrupt handlers, so a
+/// panic in it stops the kernel.
+pub(crate) fn init_cpu(cpu: usize) {
+    let Some(queue) = distros_work::cpu_queue(cpu) else {
+        return;
+    };
+    let worker = crate::spawn(
+        TaskBuilder::thread(move || run(queue))
+            .name(format!("work/{}", cpu))
+            .nice(NiceLevel::MIN)
+            .affinity(CpuSet::single(cpu))
+            .critical(),
+    );
+    queue.set_worker(worker.as_u64());
+}
+
+/// Queue of longer driver work with its own worker thread. The worker finishes queued work and
+/// exits when the queue is dropped.
+pub struct WorkQueue {
+    queue: Arc<Queue>,
+}
+
+impl WorkQueue {
+    pub fn new(name: impl Into<String>, nice: NiceLevel) -> WorkQueue {
+        let queue = Arc::new(Queue::new());
+        let worker_queue = queue.clone();
+        let worker = crate::spawn(
+            TaskBuilder::thread(move || run(&worker_queue))
+                .name(name)
+                .nice(nice)
+                .critical(),
+        );
+        queue.set_worker(worker.as_u64());
+        WorkQueue { queue }
+    }
+
+    /// Queues `func`, can be called from interrupt handlers
+    pub fn queue(&self, func: impl FnOnce() + Send + 'static) {
+        self.queue.queue(func);
+    }
+
+    /// Queues `item` unless it is already queued, can be called from interrupt handlers
+    pub fn schedule(&self, item: &'static WorkItem) -> bool {
+        item.schedule_on(&self.queue)
+    }
+}
+
+impl Drop for WorkQueue {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
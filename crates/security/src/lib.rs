@@ -0,0 +1,73 @@
+#![no_std]
+
+mod user;
+
+use bitflags::bitflags;
+use distros_cpuid::SecurityInfo;
+use log::{debug, info, warn};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+
+pub use user::{copy_from_user, copy_to_user, UserAccess, UserCopyError};
+
+bitflags! {
+    #[derive(Clone, Copy, Eq, PartialEq, Debug)]
+    pub struct Protections: u8 {
+        /// Supervisor mode execution prevention: kernel cannot execute user pages
+        const SMEP = 1 << 0;
+        /// Supervisor mode access prevention: kernel cannot access user pages outside of
+        /// [`UserAccess`]
+        const SMAP = 1 << 1;
+        /// User mode instruction prevention: `sgdt`, `sidt`, `sldt`, `smsw` and `str` fault in
+        /// user mode
+        const UMIP = 1 << 2;
+        /// No-execute page flag
+        const NXE = 1 << 3;
+        /// Kernel cannot write to read-only pages
+        const WP = 1 << 4;
+    }
+}
+
+static mut PROTECTIONS: Protections = Protections::empty();
+
+/// Enables every protection supported by the CPU
+pub fn init() {
+    info!("Enabling CPU protections");
+    let info = SecurityInfo::load();
+    debug!("CPU info: {:?}", &info);
+    let mut enabled = Protections::WP;
+    unsafe {
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+        if info.nx {
+            Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+            enabled |= Protections::NXE;
+        } else {
+            warn!("CPU does not have NX support");
+        }
+        if info.smep {
+            Cr4::update(|flags| flags.insert(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION));
+            enabled |= Protections::SMEP;
+        } else {
+            warn!("CPU does not have SMEP support");
+        }
+        if info.smap {
+            Cr4::update(|flags| flags.insert(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION));
+            enabled |= Protections::SMAP;
+        } else {
+            warn!("CPU does not have SMAP support");
+        }
+        if info.umip {
+            Cr4::update(|flags| flags.insert(Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION));
+            enabled |= Protections::UMIP;
+        } else {
+            warn!("CPU does not have UMIP support");
+        }
+        PROTECTIONS = enabled;
+    }
+    info!("CPU protections enabled: {:?}", enabled);
+}
+
+/// Protections enabled by [`init`]
+pub fn protections() -> Protections {
+    unsafe { PROTECTIONS }
+}
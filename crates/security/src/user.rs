@@ -0,0 +1,102 @@
+//! Access to user memory with SMAP enabled
+use crate::{protections, Protections};
+use core::arch::asm;
+use core::marker::PhantomData;
+use distros_interrupt::exception_fixup;
+use x86_64::VirtAddr;
+
+/// End of the lower half of the address space
+const USER_END: u64 = 0x0000_8000_0000_0000;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum UserCopyError {
+    /// Range starts at null, or does not fit into user half of the address space
+    InvalidRange,
+    /// Page fault while accessing the range
+    Fault,
+}
+
+/// Allows the kernel to access user pages until dropped, by setting `RFLAGS.AC`. Does nothing if
+/// SMAP is not enabled.
+///
+/// The guard is bound to the current CPU and must not be held across task switches.
+pub struct UserAccess {
+    _not_send: PhantomData<*mut ()>,
+}
+
+impl UserAccess {
+    pub fn new() -> Self {
+        if protections().contains(Protections::SMAP) {
+            unsafe { asm!("stac", options(nomem, nostack)) };
+        }
+        UserAccess {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Default for UserAccess {
+    fn default() -> Self {
+        UserAccess::new()
+    }
+}
+
+impl Drop for UserAccess {
+    fn drop(&mut self) {
+        if protections().contains(Protections::SMAP) {
+            unsafe { asm!("clac", options(nomem, nostack)) };
+        }
+    }
+}
+
+fn check_range(addr: VirtAddr, len: usize) -> Result<(), UserCopyError> {
+    let start = addr.as_u64();
+    match start.checked_add(len as u64) {
+        Some(end) if start != 0 && end <= USER_END => Ok(()),
+        _ => Err(UserCopyError::InvalidRange),
+    }
+}
+
+/// Copies `len` bytes with a fault on the copy resuming after it. Returns number of bytes left.
+#[inline]
+unsafe fn copy(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    let left: usize;
+    asm!(
+        "2: rep movsb",
+        "3:",
+        exception_fixup!("2b", "3b"),
+        inout("rcx") len => left,
+        inout("rdi") dst => _,
+        inout("rsi") src => _,
+        options(nostack),
+    );
+    left
+}
+
+/// Copies `dst.len()` bytes from user memory at `src`. Unmapped pages return
+/// [`UserCopyError::Fault`], `dst` can be partially written then.
+///
+/// # Safety
+/// The range must not contain kernel memory mapped in the lower half
+pub unsafe fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), UserCopyError> {
+    check_range(src, dst.len())?;
+    let _access = UserAccess::new();
+    match copy(dst.as_mut_ptr(), src.as_ptr::<u8>(), dst.len()) {
+        0 => Ok(()),
+        _ => Err(UserCopyError::Fault),
+    }
+}
+
+/// Copies `src` into user memory at `dst`. Unmapped pages return [`UserCopyError::Fault`], the
+/// range can be partially written then.
+///
+/// # Safety
+/// The range must not contain kernel memory mapped in the lower half
+pub unsafe fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), UserCopyError> {
+    check_range(dst, src.len())?;
+    let _access = UserAccess::new();
+    match copy(dst.as_mut_ptr::<u8>(), src.as_ptr(), src.len()) {
+        0 => Ok(()),
+        _ => Err(UserCopyError::Fault),
+    }
+}
@@ -0,0 +1,77 @@
+use crate::irq::IrqLock;
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+
+struct Inner {
+    arrived: usize,
+    generation: u64,
+    waiters: Vec<Waker>,
+}
+
+/// Lets `parties` tasks wait until all of them reach the same point. The barrier is reusable:
+/// after all parties arrive it resets for the next round.
+///
+/// Cancelling [`Barrier::wait`] after it was polled still counts the task as arrived.
+pub struct Barrier {
+    parties: usize,
+    inner: IrqLock<Inner>,
+}
+
+impl Barrier {
+    pub const fn new(parties: usize) -> Self {
+        Barrier {
+            parties: if parties == 0 { 1 } else { parties },
+            inner: IrqLock::new(Inner {
+                arrived: 0,
+                generation: 0,
+                waiters: Vec::new(),
+            }),
+        }
+    }
+
+    pub async fn wait(&self) -> BarrierWaitResult {
+        let generation = self.inner.with(|inner| {
+            inner.arrived += 1;
+            if inner.arrived < self.parties {
+                return Ok(inner.generation);
+            }
+            inner.arrived = 0;
+            inner.generation = inner.generation.wrapping_add(1);
+            Err(core::mem::take(&mut inner.waiters))
+        });
+        let generation = match generation {
+            Ok(generation) => generation,
+            Err(waiters) => {
+                // wakers can take scheduler locks, so they are woken after the lock is released
+                for waker in waiters {
+                    waker.wake();
+                }
+                return BarrierWaitResult(true);
+            }
+        };
+        poll_fn(|cx| {
+            self.inner.with(|inner| {
+                if inner.generation != generation {
+                    Poll::Ready(BarrierWaitResult(false))
+                } else {
+                    if !inner.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                        inner.waiters.push(cx.waker().clone());
+                    }
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    /// Exactly one task in every round is the leader - the one that arrived last
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
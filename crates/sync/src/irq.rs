@@ -0,0 +1,23 @@
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Short critical section guard shared by all primitives.
+///
+/// Interrupts stay disabled while the lock is held, so the same primitive can be signalled from
+/// an interrupt handler without deadlocking against the task it interrupted.
+pub(crate) struct IrqLock<T> {
+    inner: Mutex<T>,
+}
+
+impl<T> IrqLock<T> {
+    pub const fn new(value: T) -> Self {
+        IrqLock {
+            inner: Mutex::new(value),
+        }
+    }
+
+    #[inline]
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        without_interrupts(|| f(&mut self.inner.lock()))
+    }
+}
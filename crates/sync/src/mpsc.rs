@@ -0,0 +1,188 @@
+//! Bounded multi-producer single-consumer channel
+
+use crate::irq::IrqLock;
+use crate::semaphore::{Semaphore, TryAcquireError};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::fmt::{Debug, Formatter};
+use core::future::poll_fn;
+use core::task::{Context, Poll, Waker};
+
+struct Chan<T> {
+    queue: VecDeque<T>,
+    rx_waker: Option<Waker>,
+    senders: usize,
+}
+
+struct Shared<T> {
+    chan: IrqLock<Chan<T>>,
+    /// Free slots in the queue
+    slots: Semaphore,
+}
+
+/// Receiver is closed. Contains the value that was not sent.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct SendError<T>(pub T);
+
+impl<T> Debug for SendError<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+impl<T> Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Closed(_) => f.write_str("Closed(..)"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+/// Creates a channel that holds at most `capacity` values. Senders wait for free space.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "mpsc channel capacity must be positive");
+    let shared = Arc::new(Shared {
+        chan: IrqLock::new(Chan {
+            queue: VecDeque::with_capacity(capacity),
+            rx_waker: None,
+            senders: 1,
+        }),
+        slots: Semaphore::new(capacity),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        match self.shared.slots.acquire().await {
+            Ok(permit) => {
+                permit.forget();
+                self.push(value);
+                Ok(())
+            }
+            Err(_) => Err(SendError(value)),
+        }
+    }
+
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        match self.shared.slots.try_acquire() {
+            Ok(permit) => {
+                permit.forget();
+                self.push(value);
+                Ok(())
+            }
+            Err(TryAcquireError::Closed) => Err(TrySendError::Closed(value)),
+            Err(TryAcquireError::NoPermits) => Err(TrySendError::Full(value)),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.slots.is_closed()
+    }
+
+    fn push(&self, value: T) {
+        let waker = self.shared.chan.with(|chan| {
+            chan.queue.push_back(value);
+            chan.rx_waker.take()
+        });
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.chan.with(|chan| chan.senders += 1);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = self.shared.chan.with(|chan| {
+            chan.senders -= 1;
+            if chan.senders == 0 {
+                chan.rx_waker.take()
+            } else {
+                None
+            }
+        });
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Receives next value. Returns `None` when all senders are dropped and the queue is empty.
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let result = self.shared.chan.with(|chan| match chan.queue.pop_front() {
+            Some(value) => Poll::Ready(Some(value)),
+            None if chan.senders == 0 => Poll::Ready(None),
+            None => {
+                chan.rx_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        });
+        if let Poll::Ready(Some(_)) = result {
+            self.shared.slots.add_permits(1);
+        }
+        result
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let result = self.shared.chan.with(|chan| match chan.queue.pop_front() {
+            Some(value) => Ok(value),
+            None if chan.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        });
+        if result.is_ok() {
+            self.shared.slots.add_permits(1);
+        }
+        result
+    }
+
+    /// Stops accepting new values. Already queued values can still be received.
+    pub fn close(&mut self) {
+        self.shared.slots.close();
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
@@ -0,0 +1,161 @@
+use crate::irq::IrqLock;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum SlotState {
+    Waiting,
+    NotifiedOne,
+    NotifiedAll,
+}
+
+struct Slot {
+    state: SlotState,
+    waker: Option<Waker>,
+}
+
+type SlotRef = Arc<IrqLock<Slot>>;
+
+struct Inner {
+    permit: bool,
+    waiters: VecDeque<SlotRef>,
+}
+
+impl Inner {
+    /// Returns waker of the notified waiter, it must be woken after the lock is released
+    fn notify_one(&mut self) -> Option<Waker> {
+        match self.waiters.pop_front() {
+            Some(slot) => notify(&slot, SlotState::NotifiedOne),
+            None => {
+                self.permit = true;
+                None
+            }
+        }
+    }
+}
+
+fn notify(slot: &SlotRef, state: SlotState) -> Option<Waker> {
+    slot.with(|slot| {
+        slot.state = state;
+        slot.waker.take()
+    })
+}
+
+/// Wakes up a single task or all waiting tasks.
+///
+/// [`Notify::notify_one`] stores a permit when nobody waits, so the next [`Notify::notified`]
+/// completes immediately. [`Notify::notify_waiters`] only wakes tasks that are already waiting,
+/// i.e. whose `Notified` future was polled at least once.
+pub struct Notify {
+    inner: IrqLock<Inner>,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Notify {
+            inner: IrqLock::new(Inner {
+                permit: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn notify_one(&self) {
+        if let Some(waker) = self.inner.with(|inner| inner.notify_one()) {
+            waker.wake();
+        }
+    }
+
+    pub fn notify_waiters(&self) {
+        let wakers = self.inner.with(|inner| {
+            inner
+                .waiters
+                .drain(..)
+                .filter_map(|slot| notify(&slot, SlotState::NotifiedAll))
+                .collect::<Vec<_>>()
+        });
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            slot: None,
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Notify::new()
+    }
+}
+
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    slot: Option<SlotRef>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = &mut *self;
+        if let Some(slot) = &this.slot {
+            let state = slot.with(|slot| {
+                if slot.state == SlotState::Waiting {
+                    slot.waker = Some(cx.waker().clone());
+                }
+                slot.state
+            });
+            if state == SlotState::Waiting {
+                return Poll::Pending;
+            }
+            this.slot = None;
+            return Poll::Ready(());
+        }
+
+        let slot = &mut this.slot;
+        this.notify.inner.with(|inner| {
+            if inner.permit {
+                inner.permit = false;
+                return Poll::Ready(());
+            }
+            let waiter = Arc::new(IrqLock::new(Slot {
+                state: SlotState::Waiting,
+                waker: Some(cx.waker().clone()),
+            }));
+            inner.waiters.push_back(waiter.clone());
+            *slot = Some(waiter);
+            Poll::Pending
+        })
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            let waker = self
+                .notify
+                .inner
+                .with(|inner| match slot.with(|slot| slot.state) {
+                    SlotState::Waiting => {
+                        inner.waiters.retain(|s| !Arc::ptr_eq(s, &slot));
+                        None
+                    }
+                    // notify_one was consumed by a cancelled waiter - pass it on
+                    SlotState::NotifiedOne => inner.notify_one(),
+                    SlotState::NotifiedAll => None,
+                });
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Single-value channel
+
+use crate::irq::IrqLock;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+struct Shared<T> {
+    value: Option<T>,
+    rx_waker: Option<Waker>,
+    tx_dropped: bool,
+    rx_dropped: bool,
+}
+
+/// Sender side is dropped without sending a value
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct RecvError;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TryRecvError {
+    Empty,
+    Closed,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(IrqLock::new(Shared {
+        value: None,
+        rx_waker: None,
+        tx_dropped: false,
+        rx_dropped: false,
+    }));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+pub struct Sender<T> {
+    shared: Arc<IrqLock<Shared<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends the value. Returns it back if the receiver is already dropped.
+    pub fn send(self, value: T) -> Result<(), T> {
+        let result = self.shared.with(|shared| {
+            if shared.rx_dropped {
+                Err(value)
+            } else {
+                shared.value = Some(value);
+                Ok(shared.rx_waker.take())
+            }
+        });
+        match result {
+            Ok(waker) => {
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+                Ok(())
+            }
+            Err(value) => Err(value),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.with(|shared| shared.rx_dropped)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = self.shared.with(|shared| {
+            shared.tx_dropped = true;
+            shared.rx_waker.take()
+        });
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Receiving half. Await it to get the value.
+pub struct Receiver<T> {
+    shared: Arc<IrqLock<Shared<T>>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.shared.with(|shared| match shared.value.take() {
+            Some(value) => Ok(value),
+            None if shared.tx_dropped => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        })
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.shared.with(|shared| match shared.value.take() {
+            Some(value) => Poll::Ready(Ok(value)),
+            None if shared.tx_dropped => Poll::Ready(Err(RecvError)),
+            None => {
+                shared.rx_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.with(|shared| {
+            shared.rx_dropped = true;
+            shared.rx_waker = None;
+        })
+    }
+}
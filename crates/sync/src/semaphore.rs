@@ -0,0 +1,249 @@
+use crate::irq::IrqLock;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum SlotState {
+    Waiting,
+    Granted,
+    Closed,
+}
+
+struct Slot {
+    state: SlotState,
+    waker: Option<Waker>,
+}
+
+type SlotRef = Arc<IrqLock<Slot>>;
+
+struct Inner {
+    permits: usize,
+    closed: bool,
+    waiters: VecDeque<(usize, SlotRef)>,
+}
+
+impl Inner {
+    /// Hands permits out to queued waiters in FIFO order. Returns wakers of the granted waiters,
+    /// they must be woken after the lock is released.
+    fn grant(&mut self) -> Vec<Waker> {
+        let mut wakers = Vec::new();
+        while let Some((needed, _)) = self.waiters.front() {
+            if *needed > self.permits {
+                break;
+            }
+            let (needed, slot) = self.waiters.pop_front().unwrap();
+            self.permits -= needed;
+            wakers.extend(slot.with(|slot| {
+                slot.state = SlotState::Granted;
+                slot.waker.take()
+            }));
+        }
+        wakers
+    }
+}
+
+/// Wakers can take scheduler locks, so they are never woken under the semaphore lock
+fn wake_all(wakers: Vec<Waker>) {
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct AcquireError;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TryAcquireError {
+    Closed,
+    NoPermits,
+}
+
+/// Fair counting semaphore. Waiters are served strictly in arrival order and park on their
+/// task waker until enough permits are released.
+pub struct Semaphore {
+    inner: IrqLock<Inner>,
+}
+
+impl Semaphore {
+    pub const MAX_PERMITS: usize = usize::MAX >> 3;
+
+    pub const fn new(permits: usize) -> Self {
+        Semaphore {
+            inner: IrqLock::new(Inner {
+                permits,
+                closed: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.inner.with(|inner| inner.permits)
+    }
+
+    pub fn add_permits(&self, n: usize) {
+        let wakers = self.inner.with(|inner| {
+            inner.permits += n;
+            inner.grant()
+        });
+        wake_all(wakers);
+    }
+
+    /// Closes the semaphore: all current and future acquires fail with [`AcquireError`]
+    pub fn close(&self) {
+        let wakers = self.inner.with(|inner| {
+            inner.closed = true;
+            inner
+                .waiters
+                .drain(..)
+                .filter_map(|(_, slot)| {
+                    slot.with(|slot| {
+                        slot.state = SlotState::Closed;
+                        slot.waker.take()
+                    })
+                })
+                .collect::<Vec<_>>()
+        });
+        wake_all(wakers);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.with(|inner| inner.closed)
+    }
+
+    pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.try_acquire_many(1)
+    }
+
+    pub fn try_acquire_many(&self, n: usize) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.inner.with(|inner| {
+            if inner.closed {
+                Err(TryAcquireError::Closed)
+            } else if inner.waiters.is_empty() && inner.permits >= n {
+                inner.permits -= n;
+                Ok(SemaphorePermit {
+                    semaphore: self,
+                    permits: n,
+                })
+            } else {
+                Err(TryAcquireError::NoPermits)
+            }
+        })
+    }
+
+    pub async fn acquire(&self) -> Result<SemaphorePermit<'_>, AcquireError> {
+        self.acquire_many(1).await
+    }
+
+    pub async fn acquire_many(&self, n: usize) -> Result<SemaphorePermit<'_>, AcquireError> {
+        Acquire {
+            semaphore: self,
+            permits: n,
+            slot: None,
+        }
+        .await?;
+        Ok(SemaphorePermit {
+            semaphore: self,
+            permits: n,
+        })
+    }
+}
+
+struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+    slot: Option<SlotRef>,
+}
+
+impl Future for Acquire<'_> {
+    type Output = Result<(), AcquireError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        if let Some(slot) = &this.slot {
+            let state = slot.with(|slot| {
+                if slot.state == SlotState::Waiting {
+                    slot.waker = Some(cx.waker().clone());
+                }
+                slot.state
+            });
+            return match state {
+                SlotState::Waiting => Poll::Pending,
+                SlotState::Granted => {
+                    this.slot = None;
+                    Poll::Ready(Ok(()))
+                }
+                SlotState::Closed => {
+                    this.slot = None;
+                    Poll::Ready(Err(AcquireError))
+                }
+            };
+        }
+
+        let permits = this.permits;
+        let slot = &mut this.slot;
+        this.semaphore.inner.with(|inner| {
+            if inner.closed {
+                return Poll::Ready(Err(AcquireError));
+            }
+            if inner.waiters.is_empty() && inner.permits >= permits {
+                inner.permits -= permits;
+                return Poll::Ready(Ok(()));
+            }
+            let waiter = Arc::new(IrqLock::new(Slot {
+                state: SlotState::Waiting,
+                waker: Some(cx.waker().clone()),
+            }));
+            inner.waiters.push_back((permits, waiter.clone()));
+            *slot = Some(waiter);
+            Poll::Pending
+        })
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            let permits = self.permits;
+            let wakers = self.semaphore.inner.with(|inner| {
+                match slot.with(|slot| slot.state) {
+                    SlotState::Waiting => inner.waiters.retain(|(_, s)| !Arc::ptr_eq(s, &slot)),
+                    // permits were handed to us after the last poll - give them back
+                    SlotState::Granted => inner.permits += permits,
+                    SlotState::Closed => {}
+                }
+                inner.grant()
+            });
+            wake_all(wakers);
+        }
+    }
+}
+
+/// Permits acquired from a [`Semaphore`]. They are returned back on drop.
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Forgets the permits without returning them back to the semaphore
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+
+    pub fn num_permits(&self) -> usize {
+        self.permits
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
@@ -12,7 +12,7 @@ pub struct Hpet {
 }
 
 const CONFIG_REG_OFFSET: u64 = 0x010u64;
-const GIS_REG_OFFSET: u64 = 0x020u64;
+pub(crate) const GIS_REG_OFFSET: u64 = 0x020u64;
 const MAIN_COUNTER_REG_OFFSET: u64 = 0x0F0u64;
 
 impl Hpet {
@@ -128,11 +128,11 @@ impl Hpet {
     pub fn get_main_counter(&self) -> u64 {
         let reg = self.address + MAIN_COUNTER_REG_OFFSET;
         return if self.capable_64bit() {
-            let ptr: *const u32 = reg.as_ptr();
-            unsafe { *ptr as u64 }
-        } else {
             let ptr: *const u64 = reg.as_ptr();
-            unsafe { *ptr }
+            unsafe { ptr.read_volatile() }
+        } else {
+            let ptr: *const u32 = reg.as_ptr();
+            unsafe { ptr.read_volatile() as u64 }
         };
     }
 
@@ -143,11 +143,11 @@ impl Hpet {
         }
         let reg = self.address + MAIN_COUNTER_REG_OFFSET;
         if self.capable_64bit() {
-            let ptr: *mut u32 = reg.as_mut_ptr();
-            unsafe { *ptr = value as u32 }
-        } else {
             let ptr: *mut u64 = reg.as_mut_ptr();
             unsafe { *ptr = value }
+        } else {
+            let ptr: *mut u32 = reg.as_mut_ptr();
+            unsafe { *ptr = value as u32 }
         }
         if enabled {
             self.enable();
@@ -193,6 +193,23 @@ impl Hpet {
         }
     }
 
+    /// Makes non-periodic timer fire `ticks` after now. Only touches comparator register, so
+    /// shared access is enough.
+    pub fn arm_oneshot(&self, timer: u8, ticks: u64) {
+        let Some(config) = self.get_timer(timer) else {
+            return;
+        };
+        let deadline = self.get_main_counter().wrapping_add(ticks);
+        let reg = self.address + (0x108u64 + 0x20u64 * timer as u64);
+        if config.is_64bit() && !config.is_32_bit_mode_enabled() {
+            let ptr: *mut u64 = reg.as_mut_ptr();
+            unsafe { ptr.write_volatile(deadline) }
+        } else {
+            let ptr: *mut u32 = reg.as_mut_ptr();
+            unsafe { ptr.write_volatile(deadline as u32) }
+        }
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = Timer> + '_ {
         TimerIter {
             hpet: self,
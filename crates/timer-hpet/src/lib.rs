@@ -3,7 +3,9 @@
 use crate::hpet::Hpet;
 use crate::timer::Timer;
 use acpi::platform::interrupt::TriggerMode;
+use core::sync::atomic::{AtomicU32, Ordering};
 use core::time::Duration;
+use distros_interrupt::InterruptId;
 use distros_interrupt_pic::{Irq, IrqDestination, IrqId, IrqMode};
 use distros_memory::translate_kernel;
 use distros_timer_rtc::{rtc_handler, ExternalTimerInfo};
@@ -19,6 +21,16 @@ mod timer;
 const HPET_ADDR: VirtAddr = VirtAddr::new_truncate(1024 * 1024 * 1024 * 502);
 
 static mut HPET: Option<RwLock<Hpet>> = None;
+static USED_TIMERS: AtomicU32 = AtomicU32::new(0);
+static USED_LINES: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum HpetError {
+    NotInitialized,
+    NoFreeTimer,
+    NoFreeApicLine,
+    IoApicEntry,
+}
 
 pub fn init(info: &acpi::hpet::HpetInfo) {
     let phys = PhysAddr::new(info.base_address as u64);
@@ -46,21 +58,12 @@ pub fn init(info: &acpi::hpet::HpetInfo) {
         Some(timer) => {
             let duration = Duration::from_millis(50); // lower value will not work in QEMU
             let comp = (duration.as_nanos() as u64 * 10_u64.pow(6)) / hpet.period() as u64;
-            let handler = distros_interrupt::alloc_handler(rtc_handler)
-                .expect("Failed to alloc new interrupt");
             let line = timer
                 .first_available_apic_line()
                 .expect("Failed to find APIC line");
             let irq: Irq = Irq::new(IrqId::new(line as u32)).trigger_mode(TriggerMode::Edge);
-            distros_interrupt_pic::ioapic_set_entry(
-                irq,
-                IrqDestination::Local,
-                handler,
-                IrqMode::Fixed,
-            )
-            .expect("Failed to register IOAPIC entry");
-            distros_interrupt_pic::ioapic_enable(irq.get_global_system_interrupt())
-                .expect("Failed to register IOAPIC entry");
+            distros_interrupt_pic::request_irq(irq, "hpet-rtc", rtc_handler)
+                .expect("Failed to route HPET interrupt");
             let timer = timer
                 .set_periodic(true)
                 .set_interrupts_enabled(true)
@@ -69,6 +72,8 @@ pub fn init(info: &acpi::hpet::HpetInfo) {
             hpet.set_timer(timer);
             hpet.set_timer_comparator(timer, comp);
             hpet.set_timer_comparator(timer, 0);
+            USED_TIMERS.fetch_or(1 << timer.timer(), Ordering::AcqRel);
+            USED_LINES.fetch_or(1 << line, Ordering::AcqRel);
             distros_timer_rtc::init(Some(ExternalTimerInfo { delay: duration }));
             info!("RTC set up with HPET {:?}", &timer);
         }
@@ -87,6 +92,102 @@ pub fn enable() {
     debug!("HPET enabled");
 }
 
+fn ticks(hpet: &Hpet, duration: Duration) -> u64 {
+    (duration.as_nanos() as u64 * 10_u64.pow(6)) / hpet.period() as u64
+}
+
+/// Finds a free comparator and routes its APIC line through IOAPIC to `vector` with `mode`
+fn claim_timer(
+    hpet: &Hpet,
+    periodic: bool,
+    vector: InterruptId,
+    mode: IrqMode,
+) -> Result<(Timer, u8), HpetError> {
+    let used_timers = USED_TIMERS.load(Ordering::Acquire);
+    let timer = hpet
+        .iter()
+        .find(|t| (!periodic || t.supports_periodic()) && used_timers & (1 << t.timer()) == 0)
+        .ok_or(HpetError::NoFreeTimer)?;
+    let used_lines = USED_LINES.load(Ordering::Acquire);
+    let line = (0..32)
+        .find(|line| timer.supports_apic_line(*line) && used_lines & (1 << line) == 0)
+        .ok_or(HpetError::NoFreeApicLine)?;
+
+    let irq: Irq = Irq::new(IrqId::new(line as u32)).trigger_mode(TriggerMode::Edge);
+    distros_interrupt_pic::ioapic_set_entry(irq, IrqDestination::Local, vector, mode)
+        .map_err(|_| HpetError::IoApicEntry)?;
+    distros_interrupt_pic::ioapic_enable(irq.get_global_system_interrupt())
+        .map_err(|_| HpetError::IoApicEntry)?;
+    USED_TIMERS.fetch_or(1 << timer.timer(), Ordering::AcqRel);
+    USED_LINES.fetch_or(1 << line, Ordering::AcqRel);
+    Ok((timer, line))
+}
+
+/// Starts a free comparator in periodic mode and routes it through IOAPIC to `vector` with `mode`.
+/// Returns index of the used comparator.
+///
+/// With `level` the comparator holds its status bit until the interrupt is acknowledged with
+/// [`take_level_int`], which lets handlers of shared vectors check whether the comparator fired.
+pub fn start_periodic(
+    period: Duration,
+    vector: InterruptId,
+    mode: IrqMode,
+    level: bool,
+) -> Result<u8, HpetError> {
+    let mut hpet = unsafe { HPET.as_ref().ok_or(HpetError::NotInitialized)?.write() };
+    let (timer, line) = claim_timer(&hpet, true, vector, mode)?;
+    let ticks = ticks(&hpet, period);
+    let timer = timer
+        .set_periodic(true)
+        .set_level_trigger(level)
+        .set_interrupts_enabled(true)
+        .set_apic_line(line)
+        .allow_set_accumulator();
+    hpet.set_timer(timer);
+    // with accumulator set, the first write sets next deadline and the second one sets period
+    let now = hpet.get_main_counter();
+    hpet.set_timer_comparator(timer, now + ticks);
+    hpet.set_timer_comparator(timer, ticks);
+    debug!("HPET timer {:?} started with period {:?}", &timer, period);
+    Ok(timer.timer())
+}
+
+/// Returns `true` and acknowledges the interrupt if level-triggered comparator `timer` has fired.
+/// Doesn't take any locks, so it is safe to call from NMI.
+pub fn take_level_int(timer: u8) -> bool {
+    let status: *mut u64 = (HPET_ADDR + hpet::GIS_REG_OFFSET).as_mut_ptr();
+    let mask = 1u64 << timer;
+    unsafe {
+        if status.read_volatile() & mask == 0 {
+            return false;
+        }
+        // status bits are write-one-to-clear, so only the bit of this comparator is written
+        status.write_volatile(mask);
+    }
+    true
+}
+
+/// Reserves a free comparator for one-shot interrupts delivered to `vector`, see [`arm_oneshot`]
+pub fn alloc_oneshot(vector: InterruptId) -> Result<u8, HpetError> {
+    let mut hpet = unsafe { HPET.as_ref().ok_or(HpetError::NotInitialized)?.write() };
+    let (timer, line) = claim_timer(&hpet, false, vector, IrqMode::Fixed)?;
+    let timer = timer
+        .set_periodic(false)
+        .set_interrupts_enabled(true)
+        .set_apic_line(line);
+    hpet.set_timer(timer);
+    debug!("HPET one-shot timer {:?} allocated", &timer);
+    Ok(timer.timer())
+}
+
+/// Fires interrupt of one-shot comparator after `delay`. Can be called from interrupt context.
+pub fn arm_oneshot(timer: u8, delay: Duration) {
+    unsafe {
+        let hpet = HPET.as_ref().expect("HPET not initialized").read();
+        hpet.arm_oneshot(timer, ticks(&hpet, delay).max(1));
+    }
+}
+
 pub fn sleep(duration: Duration) {
     unsafe {
         let hpet = HPET.as_ref().expect("HPET not initialized").read();
@@ -1,10 +1,12 @@
 #![no_std]
 
-use crate::pit::{Channel, OperatingMode, Pit};
+use crate::pit::Pit;
 use spin::Mutex;
 
 mod pit;
 
+pub use pit::{Channel, OperatingMode};
+
 static PIT: Mutex<Pit> = Mutex::new(Pit::new());
 
 pub fn set(channel: Channel, value: u16) {
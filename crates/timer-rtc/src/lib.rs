@@ -1,11 +1,10 @@
 #![no_std]
-#![feature(abi_x86_interrupt)]
 
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::time::Duration;
-use distros_interrupt::int_handler;
+use distros_interrupt::{IrqContext, IrqReturn};
+use distros_work::WorkItem;
 use log::warn;
-use x86_64::structures::idt::InterruptStackFrame;
 
 mod cmos;
 mod rtc;
@@ -41,20 +40,28 @@ pub fn init(external_timer: Option<ExternalTimerInfo>) {
     }
 }
 
-int_handler!(pub noint rtc_handler |_frame: InterruptStackFrame| {
+/// Corrects the time from CMOS, which is too slow to read in the interrupt handler
+static SYNC_TIME: WorkItem = WorkItem::new(sync_time);
+
+fn sync_time() {
+    if let Some(time) = cmos::read_time() {
+        let ms_part = TIME.load(Ordering::Acquire) % 1000;
+        TIME.store(time.timestamp_millis() as u64 + ms_part, Ordering::Relaxed);
+    }
+}
+
+/// Handler of the periodic timer interrupt, for [`distros_interrupt_pic::request_irq`]
+pub fn rtc_handler(_: &IrqContext) -> IrqReturn {
     distros_timer_tsc::tsc_calibration_sample();
     let ms = TIME.fetch_add(unsafe { DELAY.as_millis() } as u64, Ordering::AcqRel);
-    if ms % 100 == 0 { // Every 100 ms
-        let ms_part = ms % 1000;
-        let time = cmos::read_time_unsafe();
-        if let Some(time) = time {
-            TIME.store(time.timestamp_millis() as u64 + ms_part, Ordering::Relaxed);
-        }
+    if ms % 100 == 0 {
+        // Every 100 ms
+        SYNC_TIME.schedule();
     };
 
     rtc::eoi();
-    distros_interrupt_pic::lapic_eoi();
-});
+    IrqReturn::Handled
+}
 
 #[inline]
 pub fn now() -> u64 {
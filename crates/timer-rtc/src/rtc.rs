@@ -1,7 +1,7 @@
 use crate::rtc_handler;
 use core::sync::atomic::{AtomicU64, Ordering};
 use distros_interrupt::{int_handler, without_nmi};
-use distros_interrupt_pic::{Irq, IrqDestination, IrqMode, IsaIrq};
+use distros_interrupt_pic::IsaIrq;
 use lazy_static::lazy_static;
 use log::info;
 use spin::Mutex;
@@ -18,11 +18,8 @@ lazy_static! {
 }
 
 pub fn init_rtc() {
-    let handler =
-        distros_interrupt::alloc_handler(rtc_handler).expect("Failed to alloc new interrupt");
-    let irq: Irq = IsaIrq::Rtc.into();
-    distros_interrupt_pic::ioapic_set_entry(irq, IrqDestination::Local, handler, IrqMode::Fixed)
-        .expect("Failed to register IOAPIC entry");
+    distros_interrupt_pic::request_irq(IsaIrq::Rtc.into(), "rtc", rtc_handler)
+        .expect("Failed to route RTC interrupt");
     unsafe {
         without_interrupts(|| {
             without_nmi(|| {
@@ -33,8 +30,6 @@ pub fn init_rtc() {
                 address.write(0x8B);
                 data.write((prev | 0x40) & 0xF0 | RATE);
             });
-            distros_interrupt_pic::ioapic_enable(irq.get_global_system_interrupt())
-                .expect("Failed to enable interrupt");
         });
         ENABLED = true;
     }
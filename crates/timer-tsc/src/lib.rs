@@ -61,6 +61,17 @@ pub fn tsc() -> u64 {
     unsafe { _rdtsc() }
 }
 
+/// Converts TSC cycle count into time
+pub fn tsc_duration(cycles: u64) -> Duration {
+    unsafe {
+        if CALIB_MEAN == 0 {
+            return Duration::ZERO;
+        }
+        let nanos = cycles as u128 * CALIB_FREQ.as_nanos() / CALIB_MEAN as u128;
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
 pub fn tsc_cycles(duration: Duration) -> u64 {
     unsafe { duration.as_nanos() as u64 / CALIB_FREQ.as_nanos() as u64 * CALIB_MEAN }
 }
@@ -0,0 +1,162 @@
+//! Low-overhead scheduler and interrupt tracepoints.
+//!
+//! Every CPU has its own fixed-size ring of binary records with TSC timestamps. Recording is a
+//! slot reservation and a few stores, so it is safe in interrupt and NMI context. When the ring is
+//! full the oldest records are overwritten. [`export`] writes the rings as text lines that
+//! `runner/src/bin/trace2chrome.rs` converts into Chrome trace JSON, which Perfetto opens too.
+#![no_std]
+
+extern crate alloc;
+
+mod ring;
+mod serial;
+
+use crate::ring::Ring;
+use alloc::boxed::Box;
+use core::fmt::Write;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use core::time::Duration;
+use distros_cpuid::{cpu_index, MAX_CPUS};
+use distros_timer_tsc::{tsc, tsc_cycles};
+
+pub use serial::SerialPort;
+
+/// Records in a ring of one CPU
+pub const RING_SIZE: usize = 4096;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[repr(u8)]
+pub enum TraceEvent {
+    /// Task started running, argument is task id
+    SwitchIn = 1,
+    /// Task stopped running, argument is task id
+    SwitchOut = 2,
+    /// Task became runnable, argument is task id
+    Wake = 3,
+    Spawn = 4,
+    Exit = 5,
+    /// Interrupt handler started, argument is handler name
+    IrqEnter = 6,
+    IrqExit = 7,
+}
+
+impl TraceEvent {
+    fn from_u8(value: u8) -> Option<TraceEvent> {
+        Some(match value {
+            1 => TraceEvent::SwitchIn,
+            2 => TraceEvent::SwitchOut,
+            3 => TraceEvent::Wake,
+            4 => TraceEvent::Spawn,
+            5 => TraceEvent::Exit,
+            6 => TraceEvent::IrqEnter,
+            7 => TraceEvent::IrqExit,
+            _ => return None,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TraceEvent::SwitchIn => "switch_in",
+            TraceEvent::SwitchOut => "switch_out",
+            TraceEvent::Wake => "wake",
+            TraceEvent::Spawn => "spawn",
+            TraceEvent::Exit => "exit",
+            TraceEvent::IrqEnter => "irq_enter",
+            TraceEvent::IrqExit => "irq_exit",
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static RINGS: [AtomicPtr<Ring>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+
+/// Allocates trace ring for `cpu`. Events of CPUs without a ring are dropped.
+pub fn init_cpu(cpu: usize) {
+    let Some(slot) = RINGS.get(cpu) else {
+        return;
+    };
+    let ring = Box::into_raw(Ring::new_boxed());
+    if slot
+        .compare_exchange(ptr::null_mut(), ring, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        drop(unsafe { Box::from_raw(ring) });
+    }
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Release);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[inline]
+pub fn record(event: TraceEvent, arg: u64) {
+    record_raw(event, arg, 0);
+}
+
+#[inline]
+fn record_raw(event: TraceEvent, arg: u64, extra: u32) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let ring = RINGS[cpu_index()].load(Ordering::Acquire);
+    if let Some(ring) = unsafe { ring.as_ref() } {
+        ring.push(tsc(), event as u8, arg, extra);
+    }
+}
+
+/// Handler names are `'static`, so the record keeps only the pointer and length
+#[inline]
+pub fn irq_enter(name: &'static str) {
+    record_raw(
+        TraceEvent::IrqEnter,
+        name.as_ptr() as u64,
+        name.len() as u32,
+    );
+}
+
+#[inline]
+pub fn irq_exit(name: &'static str) {
+    record_raw(TraceEvent::IrqExit, name.as_ptr() as u64, name.len() as u32);
+}
+
+/// Writes all rings to `w`, oldest records of each CPU first. `prelude` can write extra metadata
+/// lines (like task names) after the header.
+pub fn export<W: Write>(
+    w: &mut W,
+    prelude: impl FnOnce(&mut W) -> core::fmt::Result,
+) -> core::fmt::Result {
+    writeln!(
+        w,
+        "# distros-trace begin tsc_hz={}",
+        tsc_cycles(Duration::from_secs(1))
+    )?;
+    prelude(w)?;
+    for (cpu, ring) in RINGS.iter().enumerate() {
+        let Some(ring) = (unsafe { ring.load(Ordering::Acquire).as_ref() }) else {
+            continue;
+        };
+        ring.for_each(|tsc, event, arg, extra| {
+            let Some(event) = TraceEvent::from_u8(event) else {
+                return Ok(());
+            };
+            match event {
+                TraceEvent::IrqEnter | TraceEvent::IrqExit => {
+                    let name = unsafe {
+                        core::str::from_utf8_unchecked(core::slice::from_raw_parts(
+                            arg as *const u8,
+                            extra as usize,
+                        ))
+                    };
+                    writeln!(w, "E {} {} {} {}", cpu, tsc, event.name(), name)
+                }
+                _ => writeln!(w, "E {} {} {} {}", cpu, tsc, event.name(), arg),
+            }
+        })?;
+    }
+    writeln!(w, "# distros-trace end")
+}
@@ -0,0 +1,78 @@
+use crate::RING_SIZE;
+use alloc::alloc::{alloc_zeroed, handle_alloc_error, Layout};
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+struct Slot {
+    /// Index of the record plus one, written after the data. Lets the reader skip slots that are
+    /// being written or were already overwritten.
+    seq: AtomicU64,
+    data: UnsafeCell<Record>,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct Record {
+    tsc: u64,
+    arg: u64,
+    extra: u32,
+    event: u8,
+}
+
+pub(crate) struct Ring {
+    head: AtomicU64,
+    slots: [Slot; RING_SIZE],
+}
+
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    /// The ring is too big for the stack, so it is allocated zeroed, which is its empty state
+    pub(crate) fn new_boxed() -> Box<Ring> {
+        let layout = Layout::new::<Ring>();
+        unsafe {
+            let ring = alloc_zeroed(layout) as *mut Ring;
+            if ring.is_null() {
+                handle_alloc_error(layout);
+            }
+            Box::from_raw(ring)
+        }
+    }
+
+    /// Nested interrupts on the same CPU reserve their own slots, so writers never share a slot
+    pub(crate) fn push(&self, tsc: u64, event: u8, arg: u64, extra: u32) {
+        let index = self.head.fetch_add(1, Ordering::AcqRel);
+        let slot = &self.slots[index as usize % RING_SIZE];
+        slot.seq.store(0, Ordering::Release);
+        unsafe {
+            slot.data.get().write_volatile(Record {
+                tsc,
+                arg,
+                extra,
+                event,
+            });
+        }
+        slot.seq.store(index + 1, Ordering::Release);
+    }
+
+    pub(crate) fn for_each<E>(
+        &self,
+        mut f: impl FnMut(u64, u8, u64, u32) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let head = self.head.load(Ordering::Acquire);
+        for index in head.saturating_sub(RING_SIZE as u64)..head {
+            let slot = &self.slots[index as usize % RING_SIZE];
+            if slot.seq.load(Ordering::Acquire) != index + 1 {
+                continue;
+            }
+            let record = unsafe { slot.data.get().read_volatile() };
+            // the slot could have been reused while it was read
+            if slot.seq.load(Ordering::Acquire) != index + 1 {
+                continue;
+            }
+            f(record.tsc, record.event, record.arg, record.extra)?;
+        }
+        Ok(())
+    }
+}
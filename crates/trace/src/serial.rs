@@ -0,0 +1,48 @@
+use core::fmt::Write;
+use x86_64::instructions::port::Port;
+
+/// Minimal polling driver of a 16550 UART, used to get traces out of the machine
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    pub const COM1: u16 = 0x3F8;
+
+    /// Initializes port at `base` to 115200 baud, 8N1, without interrupts
+    pub fn new(base: u16) -> Self {
+        unsafe {
+            Port::<u8>::new(base + 1).write(0x00); // disable interrupts
+            Port::<u8>::new(base + 3).write(0x80); // enable DLAB
+            Port::<u8>::new(base).write(0x01); // divisor low byte: 115200 baud
+            Port::<u8>::new(base + 1).write(0x00); // divisor high byte
+            Port::<u8>::new(base + 3).write(0x03); // 8 bits, no parity, one stop bit
+            Port::<u8>::new(base + 2).write(0xC7); // enable and clear FIFO
+            Port::<u8>::new(base + 4).write(0x03); // DTR + RTS
+        }
+        SerialPort { base }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        unsafe {
+            let mut status = Port::<u8>::new(self.base + 5);
+            // wait for empty transmitter holding register
+            while status.read() & 0x20 == 0 {
+                core::hint::spin_loop();
+            }
+            Port::<u8>::new(self.base).write(byte);
+        }
+    }
+}
+
+impl Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+//! Deferred work.
+//!
+//! Interrupt handlers should only acknowledge their device and defer the rest. [`defer`] and
+//! [`WorkItem::schedule`] queue work to the high-priority worker of the current CPU, which runs it
+//! with interrupts enabled as soon as the handler returns. Longer driver work goes to workqueues,
+//! each with its own worker. Workers are threads of the scheduler, which wakes them with the hook
+//! from [`set_wake`]. Handlers pass data to a [`WorkItem`] through a [`Ring`], so a busy device
+//! costs no allocation per interrupt.
+#![no_std]
+
+extern crate alloc;
+
+mod ring;
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use distros_cpuid::{cpu_index, MAX_CPUS};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub use ring::Ring;
+
+/// Statically allocated work. It is queued at most once until it runs, so scheduling it does not
+/// allocate and repeated interrupts coalesce into one run.
+pub struct WorkItem {
+    pending: AtomicBool,
+    func: fn(),
+}
+
+impl WorkItem {
+    pub const fn new(func: fn()) -> Self {
+        WorkItem {
+            pending: AtomicBool::new(false),
+            func,
+        }
+    }
+
+    /// Queues the item to the high-priority worker of the current CPU. Returns `false` if the item
+    /// is already queued.
+    pub fn schedule(&'static self) -> bool {
+        self.schedule_on(local())
+    }
+
+    /// Queues the item to `queue`. Returns `false` if the item is already queued.
+    pub fn schedule_on(&'static self, queue: &Queue) -> bool {
+        if self.pending.swap(true, Ordering::AcqRel) {
+            return false;
+        }
+        queue.push(Work::Item(self));
+        true
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+}
+
+enum Work {
+    Item(&'static WorkItem),
+    Boxed(Box<dyn FnOnce() + Send>),
+}
+
+impl Work {
+    fn run(self) {
+        match self {
+            Work::Item(item) => {
+                // scheduling from the work itself queues it again
+                item.pending.store(false, Ordering::Release);
+                (item.func)();
+            }
+            Work::Boxed(func) => func(),
+        }
+    }
+}
+
+/// Work run by one worker in queueing order
+pub struct Queue {
+    work: Mutex<VecDeque<Work>>,
+    /// Task id of the worker, `0` until it is started
+    worker: AtomicU64,
+    closed: AtomicBool,
+}
+
+impl Queue {
+    pub const fn new() -> Self {
+        Queue {
+            work: Mutex::new(VecDeque::new()),
+            worker: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    fn push(&self, work: Work) {
+        // also pushed from interrupt handlers
+        without_interrupts(|| self.work.lock().push_back(work));
+        self.wake();
+    }
+
+    fn wake(&self) {
+        let worker = self.worker.load(Ordering::Acquire);
+        if worker != 0 {
+            unsafe { WAKE(worker) };
+        }
+    }
+
+    /// Queues `func`. Allocates, so interrupt handlers that fire often should use [`WorkItem`].
+    pub fn queue(&self, func: impl FnOnce() + Send + 'static) {
+        self.push(Work::Boxed(Box::new(func)));
+    }
+
+    /// Makes task `worker` the one woken up when work is queued
+    pub fn set_worker(&self, worker: u64) {
+        self.worker.store(worker, Ordering::Release);
+        self.wake();
+    }
+
+    /// Runs queued work until the queue is empty. Returns number of work items that ran.
+    pub fn run(&self) -> usize {
+        let mut count = 0;
+        while let Some(work) = without_interrupts(|| self.work.lock().pop_front()) {
+            work.run();
+            count += 1;
+        }
+        count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        without_interrupts(|| self.work.lock().is_empty())
+    }
+
+    /// Asks the worker to stop once the queue is empty
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.wake();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Queue::new()
+    }
+}
+
+static QUEUES: [Queue; MAX_CPUS] = [const { Queue::new() }; MAX_CPUS];
+static mut WAKE: fn(u64) = |_| {};
+
+/// Moves work deferred before the CPU index was known to the current CPU. Called once the index
+/// provider is set, see [`distros_cpuid::set_cpu_id`].
+pub fn adopt_early_work() {
+    without_interrupts(|| {
+        let cpu = cpu_index();
+        if cpu != 0 {
+            let early = core::mem::take(&mut *QUEUES[0].work.lock());
+            QUEUES[cpu].work.lock().extend(early);
+        }
+    });
+}
+
+/// Sets function that wakes worker task up
+pub fn set_wake(fun: fn(u64)) {
+    unsafe {
+        WAKE = fun;
+    }
+}
+
+/// High-priority queue of CPU `cpu`, `None` if there is no such CPU index
+pub fn cpu_queue(cpu: usize) -> Option<&'static Queue> {
+    QUEUES.get(cpu)
+}
+
+fn local() -> &'static Queue {
+    &QUEUES[cpu_index()]
+}
+
+/// Queues `func` to the high-priority worker of the current CPU
+pub fn defer(func: impl FnOnce() + Send + 'static) {
+    local().queue(func);
+}
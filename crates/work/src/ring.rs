@@ -0,0 +1,70 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// Fixed-size queue of values from an interrupt handler to the work that processes them.
+///
+/// Pushing is lock-free and never allocates, values that don't fit are dropped and counted. There
+/// must be one producer at a time, which holds for the handler of one IRQ line. Draining is
+/// serialized, so the work can run on several CPUs at once.
+pub struct Ring<T: Copy, const N: usize> {
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+    /// Next slot to read
+    head: AtomicUsize,
+    /// Next slot to write
+    tail: AtomicUsize,
+    dropped: AtomicU64,
+    consumer: Mutex<()>,
+}
+
+unsafe impl<T: Copy + Send, const N: usize> Sync for Ring<T, N> {}
+
+impl<T: Copy, const N: usize> Ring<T, N> {
+    pub const fn new() -> Self {
+        Ring {
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+            consumer: Mutex::new(()),
+        }
+    }
+
+    /// Adds `value`, returns `false` if the ring is full
+    pub fn push(&self, value: T) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) >= N {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        unsafe { (*self.slots[tail % N].get()).write(value) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Calls `f` with every queued value, including the ones pushed meanwhile
+    pub fn drain(&self, mut f: impl FnMut(T)) {
+        let _consumer = self.consumer.lock();
+        loop {
+            let head = self.head.load(Ordering::Relaxed);
+            if head == self.tail.load(Ordering::Acquire) {
+                return;
+            }
+            let value = unsafe { (*self.slots[head % N].get()).assume_init_read() };
+            self.head.store(head.wrapping_add(1), Ordering::Release);
+            f(value);
+        }
+    }
+
+    /// Values dropped because the ring was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T: Copy, const N: usize> Default for Ring<T, N> {
+    fn default() -> Self {
+        Ring::new()
+    }
+}
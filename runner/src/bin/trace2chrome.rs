@@ -0,0 +1,129 @@
+//! Converts kernel trace dump (written to COM1 through `/sys/trace/dump`) into Chrome trace JSON
+//! that can be opened in `chrome://tracing` or Perfetto.
+//!
+//! Usage: `cargo run --bin trace2chrome -- target/serial.log trace.json`
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::{env, fs, process};
+
+struct Event<'a> {
+    cpu: u64,
+    tsc: u64,
+    kind: &'a str,
+    arg: &'a str,
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Returns lines of the last complete dump in the log
+fn last_dump(log: &str) -> Option<(u64, Vec<&str>)> {
+    let lines: Vec<&str> = log.lines().map(|l| l.trim_end_matches('\r')).collect();
+    let begin = lines
+        .iter()
+        .rposition(|l| l.starts_with("# distros-trace begin"))?;
+    let end = begin
+        + lines[begin..]
+            .iter()
+            .position(|l| *l == "# distros-trace end")?;
+    let tsc_hz = lines[begin]
+        .split_whitespace()
+        .find_map(|part| part.strip_prefix("tsc_hz="))?
+        .parse()
+        .ok()?;
+    Some((tsc_hz, lines[begin + 1..end].to_vec()))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <serial log> <output json>", args[0]);
+        process::exit(1);
+    }
+    let log = fs::read_to_string(&args[1]).expect("Failed to read serial log");
+    let Some((tsc_hz, lines)) = last_dump(&log) else {
+        eprintln!("No complete trace dump found in {}", args[1]);
+        process::exit(1);
+    };
+    if tsc_hz == 0 {
+        eprintln!("TSC frequency is unknown, was TSC calibrated?");
+        process::exit(1);
+    }
+
+    let mut names: HashMap<&str, &str> = HashMap::new();
+    let mut events = Vec::new();
+    for line in lines {
+        let mut parts = line.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some("T"), Some(rest)) => {
+                let mut parts = rest.splitn(2, ' ');
+                if let Some(id) = parts.next() {
+                    names.insert(id, parts.next().unwrap_or(""));
+                }
+            }
+            (Some("E"), Some(rest)) => {
+                let parts: Vec<&str> = rest.splitn(4, ' ').collect();
+                if let [cpu, tsc, kind, arg] = parts[..] {
+                    if let (Ok(cpu), Ok(tsc)) = (cpu.parse(), tsc.parse()) {
+                        events.push(Event {
+                            cpu,
+                            tsc,
+                            kind,
+                            arg,
+                        });
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    let start = events.iter().map(|e| e.tsc).min().unwrap_or(0);
+    let task_name = |id: &str| match names.get(id) {
+        Some(name) if !name.is_empty() => format!("{} ({})", name, id),
+        _ => format!("task {}", id),
+    };
+
+    let mut out = Vec::new();
+    let mut cpus: Vec<u64> = events.iter().map(|e| e.cpu).collect();
+    cpus.sort();
+    cpus.dedup();
+    for cpu in cpus {
+        out.push(format!(
+            r#"{{"name":"thread_name","ph":"M","pid":0,"tid":{},"args":{{"name":"CPU {}"}}}}"#,
+            cpu, cpu
+        ));
+    }
+    for event in &events {
+        let ts = (event.tsc - start) as f64 * 1_000_000.0 / tsc_hz as f64;
+        let (name, cat, phase) = match event.kind {
+            "switch_in" => (task_name(event.arg), "task", "B"),
+            "switch_out" => (task_name(event.arg), "task", "E"),
+            "irq_enter" => (format!("irq {}", event.arg), "irq", "B"),
+            "irq_exit" => (format!("irq {}", event.arg), "irq", "E"),
+            kind => (format!("{} {}", kind, task_name(event.arg)), "sched", "i"),
+        };
+        let scope = if phase == "i" { r#","s":"t""# } else { "" };
+        out.push(format!(
+            r#"{{"name":"{}","cat":"{}","ph":"{}","ts":{:.3},"pid":0,"tid":{}{}}}"#,
+            escape(&name),
+            cat,
+            phase,
+            ts,
+            event.cpu,
+            scope
+        ));
+    }
+    let json = format!("{{\"traceEvents\":[\n{}\n]}}\n", out.join(",\n"));
+    fs::write(&args[2], json).expect("Failed to write output");
+    println!("{} events written to {}", events.len(), args[2]);
+}
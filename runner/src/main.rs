@@ -1,7 +1,11 @@
+mod symbols;
+
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
 
 fn main() {
+    // `--isapc` boots on a machine without APIC, ACPI and PCI to test the PIC8259 fallback
+    let isapc = std::env::args().any(|arg| arg == "--isapc");
     if !Command::new("cargo")
         .arg("+nightly")
         .arg("build")
@@ -16,6 +20,7 @@ fn main() {
     }
 
     let kernel = PathBuf::from("../target/x86_64-unknown-none/debug/distros");
+    symbols::embed(&kernel).unwrap();
     let out_dir = PathBuf::from("target");
     let uefi_path = out_dir.join("uefi.img");
     bootloader::UefiBoot::new(&kernel)
@@ -27,13 +32,30 @@ fn main() {
         .create_disk_image(&bios_path)
         .unwrap();
 
-    let mut child = Command::new("qemu-system-x86_64")
+    let bios_path = bios_path.to_string_lossy();
+
+    let mut qemu = Command::new("qemu-system-x86_64");
+    if isapc {
+        // OVMF needs PCI, so the BIOS image is booted
+        qemu.arg("-machine")
+            .arg("isapc")
+            .arg("-cpu")
+            .arg("qemu64")
+            .arg("-drive")
+            .arg(format!("format=raw,file={bios_path}"));
+    } else {
+        qemu.arg("-machine")
+            .arg("q35")
+            .arg("-bios")
+            .arg(ovmf_prebuilt::ovmf_pure_efi())
+            .arg("-drive")
+            .arg(format!("format=raw,file={uefi_path}"));
+    }
+    let mut child = qemu
         .arg("-gdb")
         .arg("tcp::9002")
         .arg("-no-reboot")
         .arg("-no-shutdown")
-        .arg("-machine")
-        .arg("q35")
         // .arg("-accel")
         // .arg("kvm")
         .arg("-audiodev")
@@ -45,13 +67,11 @@ fn main() {
         // .arg("-device").arg("pcie-root-port,id=rp1,slot=1")
         // .arg("-device").arg("pcie-pci-bridge,id=br1,bus=rp1")
         // .arg("-device").arg("rtl8139,bus=br1,addr=8")
-        .arg("-bios")
-        .arg(ovmf_prebuilt::ovmf_pure_efi())
-        .arg("-drive")
-        .arg(format!("format=raw,file={uefi_path}"))
         .arg("-d")
         .arg("int")
-        // .arg("-drive").arg(format!("format=raw,file={bios_path}"))
+        // trace dumps from `/sys/trace/dump`, see `trace2chrome`
+        .arg("-serial")
+        .arg("file:target/serial.log")
         .stdin(Stdio::inherit())
         .stdout(Stdio::inherit())
         .stderr(Stdio::inherit())
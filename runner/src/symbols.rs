@@ -0,0 +1,95 @@
+//! Embeds the kernel symbol table into the `.ksyms` section reserved by `distros-backtrace`. See
+//! the crate for the format.
+use goblin::elf::Elf;
+use std::fs;
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"KSYMS\x01\0\0";
+
+fn leb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Length of the common prefix that ends on a char boundary of both names
+fn shared_prefix(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .zip(b.chars())
+        .take_while(|((_, x), y)| x == y)
+        .map(|((i, x), _)| i + x.len_utf8())
+        .take_while(|len| *len <= u8::MAX as usize)
+        .last()
+        .unwrap_or(0)
+}
+
+fn encode(table_address: u64, mut symbols: Vec<(u64, u64, String)>) -> Vec<u8> {
+    symbols.sort_by_key(|(address, _, _)| *address);
+    symbols.dedup_by_key(|(address, _, _)| *address);
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&table_address.to_le_bytes());
+    out.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    let mut prev_address = 0;
+    let mut prev_name = "";
+    for (address, size, name) in &symbols {
+        leb128(&mut out, address - prev_address);
+        leb128(&mut out, *size);
+        let shared = shared_prefix(prev_name, name);
+        out.push(shared as u8);
+        leb128(&mut out, (name.len() - shared) as u64);
+        out.extend_from_slice(&name.as_bytes()[shared..]);
+        prev_address = *address;
+        prev_name = name;
+    }
+    out
+}
+
+/// Writes function symbols of `kernel` into its own `.ksyms` section
+pub fn embed(kernel: &Path) -> Result<(), String> {
+    let mut data = fs::read(kernel).map_err(|e| format!("Failed to read kernel: {}", e))?;
+    let (offset, capacity, table) = {
+        let elf = Elf::parse(&data).map_err(|e| format!("Failed to parse kernel: {}", e))?;
+        let section = elf
+            .section_headers
+            .iter()
+            .find(|s| elf.shdr_strtab.get_at(s.sh_name) == Some(".ksyms"))
+            .ok_or("Kernel does not have .ksyms section")?;
+        let table_address = elf
+            .syms
+            .iter()
+            .find(|s| elf.strtab.get_at(s.st_name) == Some("KSYMS"))
+            .ok_or("Kernel does not have KSYMS symbol")?
+            .st_value;
+        let symbols = elf
+            .syms
+            .iter()
+            .filter(|s| s.is_function() && s.st_value != 0)
+            .filter_map(|s| {
+                let name = elf.strtab.get_at(s.st_name)?;
+                let name = format!("{:#}", rustc_demangle::demangle(name));
+                Some((s.st_value, s.st_size, name))
+            })
+            .collect();
+        (
+            section.sh_offset as usize,
+            section.sh_size as usize,
+            encode(table_address, symbols),
+        )
+    };
+    if table.len() > capacity {
+        return Err(format!(
+            "Symbol table needs {} bytes, .ksyms has {}",
+            table.len(),
+            capacity
+        ));
+    }
+    data[offset..offset + table.len()].copy_from_slice(&table);
+    fs::write(kernel, data).map_err(|e| format!("Failed to write kernel: {}", e))
+}
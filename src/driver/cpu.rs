@@ -0,0 +1,12 @@
+//! `/dev/cpu/{n}` flow subtree with CPUID description of the CPU
+use distros_cpuid::CpuInfo;
+
+pub fn init() {
+    // CPUID describes the CPU it runs on, and only the bootstrap CPU is running for now
+    let cpu = distros_scheduler::current_cpu();
+    let info = CpuInfo::load();
+    debug!("[CPU] CPU {}: {:?}", cpu, &info);
+    if let Err(e) = register!(serial format!("/dev/cpu/{}", cpu) => info) {
+        error!("[CPU] Failed to register CPU {} info: {:?}", cpu, e);
+    }
+}
@@ -1,12 +1,12 @@
 use crate::driver::keyboard::KeyboardMessage;
 use crate::driver::mouse::MouseMessage;
 use crate::flow::{FlowManager, Producer};
-use crate::interrupts;
-use crate::interrupts::Irq;
 use alloc::sync::Arc;
 use core::ops::Deref;
 use core::sync::atomic::{AtomicBool, Ordering};
-use distros_interrupt::OverrideMode;
+use distros_interrupt::{IrqContext, IrqReturn};
+use distros_interrupt_pic::IsaIrq;
+use distros_work::{Ring, WorkItem};
 use fixedbitset::FixedBitSet;
 use libkernel::flow::Sender;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet2};
@@ -14,7 +14,7 @@ use ps2::error::{ControllerError, KeyboardError, MouseError};
 use ps2::flags::{ControllerConfigFlags, KeyboardLedFlags, MouseMovementFlags};
 use ps2::Controller;
 use spin::{Lazy, Mutex};
-use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::instructions::interrupts::without_interrupts;
 
 static KEYBOARD_SENDER: Lazy<Arc<Mutex<Producer<KeyboardMessage>>>> =
     Lazy::new(|| Arc::new(Mutex::new(Producer::new())));
@@ -29,6 +29,10 @@ static KEYBOARD_PARSER: Lazy<Mutex<Keyboard<layouts::Us104Key, ScancodeSet2>>> =
         HandleControl::MapLettersToUnicode,
     ))
 });
+static KEYBOARD_BYTES: Ring<u8, 64> = Ring::new();
+static KEYBOARD_WORK: WorkItem = WorkItem::new(drain_keyboard);
+static MOUSE_PACKETS: Ring<(MouseMovementFlags, i16, i16), 32> = Ring::new();
+static MOUSE_WORK: WorkItem = WorkItem::new(drain_mouse);
 static CAPS_STATE: AtomicBool = AtomicBool::new(false);
 static NUM_STATE: AtomicBool = AtomicBool::new(false);
 static SCROLL_STATE: AtomicBool = AtomicBool::new(false);
@@ -118,8 +122,12 @@ pub fn init() -> Result<(), ControllerError> {
             None,
         )
         .unwrap();
-        let int = Irq::from_raw(1).map_to_int(0);
-        distros_interrupt::set_handler(int, keyboard_handler, OverrideMode::Panic);
+        distros_interrupt_pic::request_irq(
+            IsaIrq::PS2Keyboard.into(),
+            "ps2-keyboard",
+            keyboard_handler,
+        )
+        .expect("Failed to route PS/2 keyboard interrupt");
         info!("PS/2 keyboard started");
     }
     if mouse_works {
@@ -138,8 +146,8 @@ pub fn init() -> Result<(), ControllerError> {
             let sender = MOUSE_SENDER.deref();
             FlowManager::register_endpoint::<MouseMessage>("/dev/ps2/mouse", sender.clone(), None)
                 .unwrap();
-            let int = Irq::from_raw(12).map_to_int(0);
-            distros_interrupt::set_handler(int, mouse_handler, OverrideMode::Panic);
+            distros_interrupt_pic::request_irq(IsaIrq::PS2Mouse.into(), "ps2-mouse", mouse_handler)
+                .expect("Failed to route PS/2 mouse interrupt");
             info!("PS/2 mouse started");
         }
     }
@@ -177,62 +185,83 @@ async fn send_mouse(packet: (MouseMovementFlags, i16, i16)) {
         .await;
 }
 
-int_handler!(noint keyboard_handler |_: InterruptStackFrame| {
-    let mut controller = INT_CONTROLLER.lock();
-    // ignore timeouts
-    if let Ok(byte) = controller.read_data() {
-        let mut keyboard = KEYBOARD_PARSER.lock();
-        if let Ok(Some(key)) = keyboard.add_byte(byte) {
-            if let Some(decoded) = keyboard.process_keyevent(key.clone()) {
-                spawn!("driver/ps2/keyboard_send" => send_decoded(decoded))
+/// Processes bytes queued by [`keyboard_handler`]
+fn drain_keyboard() {
+    KEYBOARD_BYTES.drain(process_keyboard_byte);
+}
+
+/// Sends packets queued by [`mouse_handler`]
+fn drain_mouse() {
+    MOUSE_PACKETS.drain(|packet| spawn!("driver/ps2/mouse_send" => send_mouse(packet)));
+}
+
+/// Decodes `byte` and updates LEDs
+fn process_keyboard_byte(byte: u8) {
+    let mut keyboard = KEYBOARD_PARSER.lock();
+    if let Ok(Some(key)) = keyboard.add_byte(byte) {
+        if let Some(decoded) = keyboard.process_keyevent(key.clone()) {
+            spawn!("driver/ps2/keyboard_send" => send_decoded(decoded))
+        }
+        let change_led = match key.code {
+            KeyCode::CapsLock => {
+                CAPS_STATE.store(match key.state {
+                    KeyState::Up => false,
+                    KeyState::Down => true
+                }, Ordering::SeqCst);
+                true
             }
-            let change_led = match key.code {
-                KeyCode::CapsLock => {
-                    CAPS_STATE.store(match key.state {
-                        KeyState::Up => false,
-                        KeyState::Down => true
-                    }, Ordering::SeqCst);
-                    true
-                }
-                KeyCode::NumpadLock => {
-                    NUM_STATE.store(match key.state {
-                        KeyState::Up => false,
-                        KeyState::Down => true
-                    }, Ordering::SeqCst);
-                    true
-                }
-                KeyCode::ScrollLock => {
-                    SCROLL_STATE.store(match key.state {
-                        KeyState::Up => false,
-                        KeyState::Down => true
-                    }, Ordering::SeqCst);
-                    true
-                }
-                _ => false
-            };
-            if change_led {
-                let mut flags = KeyboardLedFlags::empty();
-                if SCROLL_STATE.load(Ordering::SeqCst) {
-                    flags |= KeyboardLedFlags::SCROLL_LOCK;
-                }
-                if CAPS_STATE.load(Ordering::SeqCst) {
-                    flags |= KeyboardLedFlags::CAPS_LOCK;
-                }
-                if NUM_STATE.load(Ordering::SeqCst) {
-                    flags |= KeyboardLedFlags::NUM_LOCK;
-                }
-                if controller.keyboard().set_leds(flags).is_err() {
+            KeyCode::NumpadLock => {
+                NUM_STATE.store(match key.state {
+                    KeyState::Up => false,
+                    KeyState::Down => true
+                }, Ordering::SeqCst);
+                true
+            }
+            KeyCode::ScrollLock => {
+                SCROLL_STATE.store(match key.state {
+                    KeyState::Up => false,
+                    KeyState::Down => true
+                }, Ordering::SeqCst);
+                true
+            }
+            _ => false
+        };
+        if change_led {
+            let mut flags = KeyboardLedFlags::empty();
+            if SCROLL_STATE.load(Ordering::SeqCst) {
+                flags |= KeyboardLedFlags::SCROLL_LOCK;
+            }
+            if CAPS_STATE.load(Ordering::SeqCst) {
+                flags |= KeyboardLedFlags::CAPS_LOCK;
+            }
+            if NUM_STATE.load(Ordering::SeqCst) {
+                flags |= KeyboardLedFlags::NUM_LOCK;
+            }
+            // the controller is shared with the interrupt handlers
+            without_interrupts(|| {
+                if INT_CONTROLLER.lock().keyboard().set_leds(flags).is_err() {
                     // ignore timeout
                 }
-            }
+            });
         }
     }
-});
+}
 
-int_handler!(noint mouse_handler |_: InterruptStackFrame| {
+fn keyboard_handler(_: &IrqContext) -> IrqReturn {
+    // ignore timeouts
+    if let Ok(byte) = INT_CONTROLLER.lock().read_data() {
+        KEYBOARD_BYTES.push(byte);
+        KEYBOARD_WORK.schedule();
+    }
+    IrqReturn::Handled
+}
+
+fn mouse_handler(_: &IrqContext) -> IrqReturn {
     let mut controller = INT_CONTROLLER.lock();
     // ignore timeouts
     if let Ok(packet) = controller.mouse().read_data_packet() {
-        spawn!("driver/ps2/mouse_send" => send_mouse(packet));
+        MOUSE_PACKETS.push(packet);
+        MOUSE_WORK.schedule();
     }
-});
+    IrqReturn::Handled
+}
@@ -0,0 +1,70 @@
+//! `/dev/interrupts/{vector}` statistics of every vector and `/proc/interrupts` summary
+use crate::flow::{FlowManagerError, ValHandler, VarProvider};
+use alloc::string::String;
+use distros_interrupt::InterruptId;
+use libkernel::flow::{StringMessage, U64Message};
+
+type Result<T> = core::result::Result<T, FlowManagerError>;
+
+#[derive(Copy, Clone)]
+enum StatsField {
+    Count,
+    /// Time spent in handlers, in TSC cycles
+    Cycles,
+    MaxCycles,
+    Spurious,
+}
+
+struct StatsValue {
+    vector: InterruptId,
+    field: StatsField,
+}
+
+impl ValHandler<U64Message> for StatsValue {
+    fn get(&self) -> U64Message {
+        let stats = distros_interrupt::irq_stats(self.vector);
+        U64Message::new(match self.field {
+            StatsField::Count => stats.count,
+            StatsField::Cycles => stats.cycles,
+            StatsField::MaxCycles => stats.max_cycles,
+            StatsField::Spurious => stats.spurious,
+        })
+    }
+}
+
+struct Summary;
+
+impl ValHandler<StringMessage> for Summary {
+    fn get(&self) -> StringMessage {
+        let mut summary = String::new();
+        let _ = distros_interrupt::write_irq_summary(&mut summary);
+        StringMessage::new(&summary)
+    }
+}
+
+fn register_vector(vector: u8) -> Result<()> {
+    let fields = [
+        ("count", StatsField::Count),
+        ("cycles", StatsField::Cycles),
+        ("max_cycles", StatsField::MaxCycles),
+        ("spurious", StatsField::Spurious),
+    ];
+    for (name, field) in fields {
+        VarProvider::new_val(StatsValue {
+            vector: InterruptId::new(vector),
+            field,
+        })
+        .register(&format!("/dev/interrupts/{}/{}", vector, name))?;
+    }
+    Ok(())
+}
+
+pub fn init() {
+    let result = (0..=255u8).try_for_each(register_vector);
+    if let Err(e) = result {
+        error!("[INTERRUPTS] Failed to register vector stats: {:?}", e);
+    }
+    if let Err(e) = VarProvider::new_val(Summary).register("/proc/interrupts") {
+        error!("[INTERRUPTS] Failed to register summary: {:?}", e);
+    }
+}
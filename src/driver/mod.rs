@@ -1,4 +1,6 @@
+mod cpu;
 mod device;
+mod interrupts;
 pub mod keyboard;
 pub mod mouse;
 pub mod pci;
@@ -16,6 +18,8 @@ pub fn init() {
     device::init();
     info!("Device drivers started");
 
+    cpu::init();
+    interrupts::init();
     smbios::init();
     // pci::init();
     tty::init().unwrap();
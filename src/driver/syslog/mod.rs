@@ -113,7 +113,10 @@ impl Provider for SyslogProvider {
 }
 
 pub fn init() {
-    log::set_logger(&LOG_INSTANCE).expect("Cannot start syslog instance");
+    // console logger is installed first during early boot, syslog can only take over if it is absent
+    if log::set_logger(&LOG_INSTANCE).is_err() {
+        warn!("Global logger is already set, /dev/syslog will not receive kernel messages");
+    }
     FlowManager::register_endpoint::<SyslogMessage>(
         "/dev/syslog",
         Arc::new(Mutex::new(SyslogProvider::new())),
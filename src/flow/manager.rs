@@ -8,6 +8,7 @@ use alloc::vec::Vec;
 use core::any::TypeId;
 use core::fmt::{Debug, Formatter};
 use core::ops::DerefMut;
+use distros_sync::Mutex as AsyncMutex;
 use libkernel::flow::{AnyConsumer, Message, Provider, Sender, Subscription};
 use spin::{Lazy, Mutex, RwLock};
 
@@ -87,14 +88,15 @@ impl FlowManager {
         path: &str,
         message: T,
     ) -> Result<(), FlowManagerError> {
-        let inner = INNER.read();
-        match inner.endpoints.get(path) {
-            Some(endpoint) => match &endpoint.sender {
+        // the tree lock must not be held across awaits: the sender may block for a long time
+        let endpoint = INNER.read().endpoints.get(path);
+        match endpoint {
+            Some(endpoint) => match endpoint.sender {
                 Some(sender) => {
                     if endpoint.message_type != TypeId::of::<T>() {
                         Err(FlowManagerError::WrongMessageType)
                     } else {
-                        let mut sender = sender.lock();
+                        let mut sender = sender.lock().await;
                         let sender: &mut dyn Sender<Msg = T> =
                             unsafe { core::mem::transmute(sender.deref_mut()) };
                         sender.send(message).await;
@@ -110,7 +112,7 @@ impl FlowManager {
     pub fn register_endpoint<T: 'static + Message>(
         path: &str,
         provider: Arc<Mutex<dyn Provider + Send>>,
-        sender: Option<Arc<Mutex<dyn Sender<Msg = T> + Send>>>,
+        sender: Option<Arc<AsyncMutex<dyn Sender<Msg = T> + Send>>>,
     ) -> Result<(), FlowManagerError> {
         let mut endpoint = FlowTreeEndpoint::new::<T>(provider);
         if let Some(sender) = sender {
@@ -128,6 +130,16 @@ impl FlowManager {
             })
     }
 
+    /// Removes endpoint or the whole subtree at the path
+    pub fn unregister(path: &str) -> Result<(), FlowManagerError> {
+        let mut inner = INNER.write();
+        if inner.endpoints.remove(path) {
+            Ok(())
+        } else {
+            Err(FlowManagerError::ProviderNotFound)
+        }
+    }
+
     pub fn list(path: &str) -> Vec<ElementInfo> {
         let inner = INNER.read();
         inner.endpoints.list(path)
@@ -4,13 +4,14 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::any::TypeId;
 use core::fmt::{Display, Formatter};
+use distros_sync::Mutex as AsyncMutex;
 use hashbrown::HashMap;
 use libkernel::flow::{Message, Provider, Sender};
 use spin::Mutex;
 
 pub struct FlowTreeEndpoint {
     pub provider: Arc<Mutex<dyn Provider + Send>>,
-    pub sender: Option<Arc<Mutex<dyn Sender<Msg = dyn Message> + Send>>>,
+    pub sender: Option<Arc<AsyncMutex<dyn Sender<Msg = dyn Message> + Send>>>,
     pub message_type: TypeId,
 }
 
@@ -35,7 +36,7 @@ impl FlowTreeEndpoint {
 
     pub fn sender(
         &mut self,
-        sender: Arc<Mutex<dyn Sender<Msg = dyn Message> + Send>>,
+        sender: Arc<AsyncMutex<dyn Sender<Msg = dyn Message> + Send>>,
     ) -> &mut FlowTreeEndpoint {
         self.sender = Some(sender);
         self
@@ -155,6 +156,20 @@ impl FlowTree {
         }
     }
 
+    /// Removes endpoint or whole branch at the path. Returns `false` if nothing was there
+    pub fn remove(&mut self, path: &str) -> bool {
+        let parts = path.split('/').collect::<Vec<_>>();
+        let (last, parts) = parts.split_last().unwrap();
+        let mut current = &mut self.node;
+        for part in parts {
+            match current.nodes.get_mut(*part) {
+                Some(FlowTreeNode::Branch(branch)) => current = branch,
+                _ => return false,
+            }
+        }
+        current.nodes.remove(*last).is_some()
+    }
+
     pub fn list(&self, path: &str) -> Vec<ElementInfo> {
         let parts = path.split('/').collect::<Vec<_>>();
         let (last, parts) = parts.split_last().unwrap();
@@ -4,6 +4,7 @@ use alloc::sync::Arc;
 use async_trait::async_trait;
 use core::marker::PhantomData;
 use core::sync::atomic::{AtomicBool, Ordering};
+use distros_sync::Mutex as AsyncMutex;
 use libkernel::flow::{AnyConsumer, Message, Provider, Sender, Subscription};
 use spin::Mutex;
 
@@ -88,8 +89,12 @@ pub struct VarProviderImpl<F: VarHandler<T> + 'static, T: Message + 'static> {
 
 impl<F: VarHandler<T> + 'static, T: Message + 'static> VarProviderImpl<F, T> {
     pub fn register(self, path: &str) -> Result<(), FlowManagerError> {
+        let sender = Arc::new(AsyncMutex::new(VarSender {
+            handler: self.handler.clone(),
+            message_type: PhantomData,
+        }));
         let provider = Arc::new(Mutex::new(self));
-        FlowManager::register_endpoint::<T>(path, provider.clone(), Some(provider))
+        FlowManager::register_endpoint::<T>(path, provider, Some(sender))
     }
 }
 
@@ -99,8 +104,13 @@ impl<F: VarHandler<T> + 'static, T: 'static + Message> Provider for VarProviderI
     }
 }
 
+struct VarSender<F: VarHandler<T> + 'static, T: Message + 'static> {
+    handler: Arc<F>,
+    message_type: PhantomData<T>,
+}
+
 #[async_trait]
-impl<F: VarHandler<T> + 'static, T: 'static + Message> Sender for VarProviderImpl<F, T> {
+impl<F: VarHandler<T> + 'static, T: 'static + Message> Sender for VarSender<F, T> {
     type Msg = T;
 
     async fn send(&mut self, message: Self::Msg) {
@@ -1,6 +1,6 @@
 use crate::interrupts;
 use crate::interrupts::InterruptId;
-use distros_interrupt::OverrideMode;
+use distros_interrupt::{IrqAccounting, OverrideMode};
 use libkernel::syscall::{
     self, take_command, SyscallCommand, SyscallMessage, SYSCALL_IN_MEM, SYSCALL_SYNC_MEM,
 };
@@ -8,7 +8,10 @@ use x86_64::structures::idt::InterruptStackFrame;
 use x86_64::structures::paging::PageTableFlags;
 use x86_64::VirtAddr;
 
+const SYSCALL_VECTOR: InterruptId = InterruptId::new(80);
+
 int_handler!(noint syscall_handler | _stack_frame: InterruptStackFrame | {
+    let _accounting = IrqAccounting::start(SYSCALL_VECTOR);
     if let Some(cmd) = take_command() {
         match cmd {
             SyscallCommand::Test => {
@@ -26,7 +29,7 @@ int_handler!(noint syscall_handler | _stack_frame: InterruptStackFrame | {
 
 /// Setup global syscall handlers
 pub fn init() {
-    distros_interrupt::set_handler(InterruptId::new(80), syscall_handler, OverrideMode::Panic);
+    distros_interrupt::set_handler(SYSCALL_VECTOR, syscall_handler, OverrideMode::Panic);
 }
 
 /// Setup syscall memory for program
@@ -31,11 +31,11 @@ use crate::gui::TextDisplay;
 use bootloader_api::config::Mapping;
 use bootloader_api::{entry_point, BootInfo, BootloaderConfig};
 use chrono::NaiveDateTime;
+use distros_backtrace::Backtrace;
 use distros_framebuffer_vesa::VesaFrameBuffer;
 use distros_logging::Logger;
 use distros_memory_stack::{KERNEL_STACK_BASE, KERNEL_STACK_BASE_GUARD, KERNEL_STACK_SIZE};
-use distros_scheduler::TaskBuilder;
-use distros_timer::sleep;
+use distros_scheduler::{thread_sleep, TaskBuilder, WatchdogConfig};
 use log::LevelFilter;
 use pci_types::device_type::DeviceType;
 use x86_64::instructions::hlt;
@@ -55,7 +55,10 @@ mod gui;
 /// This function is called on panic.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    Logger::<TextDisplay<VesaFrameBuffer>>::panic(info);
+    let backtrace = Backtrace::capture();
+    // returns only if the panic cannot be contained in the current task
+    distros_scheduler::isolate_panic(info, &backtrace);
+    Logger::<TextDisplay<VesaFrameBuffer>>::panic(info, backtrace);
     loop {}
 }
 
@@ -74,20 +77,18 @@ pub static BOOTLOADER_CONFIG: BootloaderConfig = {
 
 entry_point!(main, config = &BOOTLOADER_CONFIG);
 
-async fn a() {
-    futures::future::lazy(|_| loop {
+fn a() {
+    loop {
         warn!("A");
-        sleep(Duration::from_secs(1));
-    })
-    .await;
+        thread_sleep(Duration::from_secs(1));
+    }
 }
 
-async fn b() {
-    futures::future::lazy(|_| loop {
+fn b() {
+    loop {
         warn!("B");
-        sleep(Duration::from_secs(1));
-    })
-    .await;
+        thread_sleep(Duration::from_secs(1));
+    }
 }
 
 pub fn main(boot_info: &'static mut BootInfo) -> ! {
@@ -97,22 +98,33 @@ pub fn main(boot_info: &'static mut BootInfo) -> ! {
         .init();
 
     distros_cpuid::load();
+    distros_security::init();
     distros_interrupt::init();
     distros_memory::init(
         boot_info.physical_memory_offset.into_option(),
         &boot_info.memory_regions,
     );
+    distros_backtrace::init();
+    distros_interrupt::init_ist_stacks();
     distros_acpi::init_acpi(boot_info.rsdp_addr.into_option());
     distros_interrupt_pic::init();
     distros_timer::init();
     distros_fpu::init();
+    distros_pmu::init();
+    distros_pmu::set_symbolizer(distros_backtrace::resolve);
     distros_pci_access::init();
     distros_scheduler::init();
+    distros_scheduler::set_panic_isolation(true);
     // distros_acpi_aml::init();
     x86_64::instructions::interrupts::enable();
     distros_timer::after_interrupt_enabled();
-    distros_scheduler::spawn(TaskBuilder::kernel(a()).no_preempt().name("a"));
-    distros_scheduler::spawn(TaskBuilder::kernel(b()).no_preempt().name("b"));
+    if let Err(e) = distros_scheduler::watchdog_init(WatchdogConfig::default()) {
+        warn!("Watchdog is not started: {:?}", e);
+    }
+    process::init();
+    driver::init();
+    distros_scheduler::spawn(TaskBuilder::thread(a).name("a").no_preempt());
+    distros_scheduler::spawn(TaskBuilder::thread(b).name("b").no_preempt());
     distros_scheduler::sched_start();
 
     // driver::pci::init();
@@ -121,10 +133,5 @@ pub fn main(boot_info: &'static mut BootInfo) -> ! {
     // //
     // // // ElfProgram::load(include_bytes!("../example_elf/target/config/release/example_elf")).unwrap().start_tmp();
     //
-    // process::setup();
-    //
-    // driver::init(&acpi);
     // basic_term::init().unwrap();
-    //
-    // unsafe { process::run() }
 }
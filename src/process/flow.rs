@@ -0,0 +1,159 @@
+//! `/sys/tasks` flow subtree
+//!
+//! Scheduler reports spawned and exited tasks through a hook that can run in interrupt context,
+//! so events are queued and applied to the flow tree by a separate task.
+use crate::flow::{FlowManager, FlowManagerError, ValHandler, VarHandler, VarProvider};
+use alloc::string::String;
+use crossbeam_queue::SegQueue;
+use distros_scheduler::{CpuSet, GroupId, TaskEvent, TaskId, TaskState};
+use distros_sync::Notify;
+use libkernel::flow::{I8Message, Message, StringMessage, U64Message};
+use spin::Lazy;
+
+type Result<T> = core::result::Result<T, FlowManagerError>;
+
+static EVENTS: Lazy<SegQueue<TaskEvent>> = Lazy::new(SegQueue::new);
+static EVENTS_NOTIFY: Notify = Notify::new();
+
+fn task_hook(event: TaskEvent) {
+    EVENTS.push(event);
+    EVENTS_NOTIFY.notify_one();
+}
+
+fn state_name(state: TaskState) -> &'static str {
+    match state {
+        TaskState::Running => "running",
+        TaskState::Waiting => "waiting",
+        TaskState::Parked => "parked",
+    }
+}
+
+fn task_path(id: TaskId) -> String {
+    format!("/sys/tasks/{}", id.as_u64())
+}
+
+struct TaskValue<T> {
+    id: TaskId,
+    fun: fn(TaskId) -> T,
+}
+
+impl<T: Message + 'static> ValHandler<T> for TaskValue<T> {
+    fn get(&self) -> T {
+        (self.fun)(self.id)
+    }
+}
+
+fn register_value<T: Message + 'static>(
+    id: TaskId,
+    name: &str,
+    fun: fn(TaskId) -> T,
+) -> Result<()> {
+    VarProvider::new_val(TaskValue { id, fun }).register(&format!("{}/{}", task_path(id), name))
+}
+
+struct AffinityVar {
+    id: TaskId,
+}
+
+impl VarHandler<U64Message> for AffinityVar {
+    fn get(&self) -> U64Message {
+        let affinity = distros_scheduler::get_affinity(self.id).unwrap_or_else(CpuSet::empty);
+        U64Message::new(affinity.bits())
+    }
+
+    fn set(&self, v: U64Message) {
+        if let Err(e) = distros_scheduler::set_affinity(self.id, CpuSet::from_bits(v.get())) {
+            warn!(
+                "[sys/tasks] Cannot set affinity of task {:?}: {:?}",
+                self.id, e
+            );
+        }
+    }
+}
+
+struct GroupVar {
+    id: TaskId,
+}
+
+impl VarHandler<U64Message> for GroupVar {
+    fn get(&self) -> U64Message {
+        let group = distros_scheduler::task_group(self.id).unwrap_or(GroupId::ROOT);
+        U64Message::new(group.as_u64())
+    }
+
+    fn set(&self, v: U64Message) {
+        let group = distros_scheduler::groups()
+            .into_iter()
+            .find(|g| g.as_u64() == v.get());
+        let result = match group {
+            Some(group) => distros_scheduler::move_task(self.id, group),
+            None => Err(distros_scheduler::GroupError::NotFound),
+        };
+        if let Err(e) = result {
+            warn!("[sys/tasks] Cannot move task {:?}: {:?}", self.id, e);
+        }
+    }
+}
+
+fn register_task(id: TaskId) -> Result<()> {
+    let path = task_path(id);
+    VarProvider::new_var(AffinityVar { id }).register(&format!("{}/affinity", path))?;
+    VarProvider::new_var(GroupVar { id }).register(&format!("{}/group", path))?;
+    register_value(id, "name", |id| {
+        let name = distros_scheduler::get_name(id).flatten();
+        StringMessage::new(name.as_deref().unwrap_or(""))
+    })?;
+    register_value(id, "state", |id| {
+        StringMessage::new(
+            distros_scheduler::get_state(id)
+                .map(state_name)
+                .unwrap_or("exited"),
+        )
+    })?;
+    register_value(id, "nice", |id| {
+        I8Message::new(
+            distros_scheduler::get_nice(id)
+                .map(|n| n.level())
+                .unwrap_or(0),
+        )
+    })?;
+    register_value(id, "cpu_time", |id| {
+        let info = distros_scheduler::task_info(id);
+        U64Message::new(info.map(|i| i.cpu_time.as_nanos() as u64).unwrap_or(0))
+    })?;
+    register_value(id, "stack_usage", |id| {
+        let usage = distros_scheduler::task_info(id).and_then(|i| i.stack_usage);
+        StringMessage::new(&usage.map_or(String::from("n/a"), |bytes| format!("{}", bytes)))
+    })?;
+    register_value(id, "wakeups", |id| {
+        U64Message::new(
+            distros_scheduler::task_info(id)
+                .map(|i| i.wakeups)
+                .unwrap_or(0),
+        )
+    })
+}
+
+async fn handle_events() {
+    loop {
+        while let Some(event) = EVENTS.pop() {
+            let result = match event {
+                TaskEvent::Spawned(id) => register_task(id),
+                TaskEvent::Exited(id) => FlowManager::unregister(&task_path(id)),
+            };
+            if let Err(e) = result {
+                debug!("[sys/tasks] Failed to apply {:?}: {:?}", event, e);
+            }
+        }
+        EVENTS_NOTIFY.notified().await;
+    }
+}
+
+pub fn init() {
+    Lazy::force(&EVENTS);
+    distros_scheduler::set_task_hook(task_hook);
+    for id in distros_scheduler::tasks() {
+        EVENTS.push(TaskEvent::Spawned(id));
+    }
+    super::spawn_kernel("sys_tasks", handle_events());
+}
@@ -0,0 +1,38 @@
+//! `/sys/cpu/{n}/fpu` flow subtree with lazy FPU switching statistics
+use crate::flow::{FlowManagerError, ValHandler, VarProvider};
+use distros_fpu::FpuStats;
+use libkernel::flow::U64Message;
+
+type Result<T> = core::result::Result<T, FlowManagerError>;
+
+struct FpuValue {
+    cpu: usize,
+    fun: fn(&FpuStats) -> u64,
+}
+
+impl ValHandler<U64Message> for FpuValue {
+    fn get(&self) -> U64Message {
+        U64Message::new(distros_fpu::stats(self.cpu).map_or(0, |s| (self.fun)(&s)))
+    }
+}
+
+fn register_value(cpu: usize, name: &str, fun: fn(&FpuStats) -> u64) -> Result<()> {
+    VarProvider::new_val(FpuValue { cpu, fun }).register(&format!("/sys/cpu/{}/fpu/{}", cpu, name))
+}
+
+fn register_values(cpu: usize) -> Result<()> {
+    register_value(cpu, "switches", |s| s.switches)?;
+    register_value(cpu, "traps", |s| s.traps)?;
+    register_value(cpu, "saves", |s| s.saves)
+}
+
+pub fn init() {
+    // only the bootstrap CPU runs the scheduler for now
+    let cpu = distros_scheduler::current_cpu();
+    if let Err(e) = register_values(cpu) {
+        warn!(
+            "[sys/cpu] Failed to register FPU stats of CPU {}: {:?}",
+            cpu, e
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! `/sys/groups` flow subtree with the task group hierarchy
+use crate::flow::{FlowManager, FlowManagerError, ValHandler, VarHandler, VarProvider};
+use alloc::string::String;
+use core::time::Duration;
+use crossbeam_queue::SegQueue;
+use distros_scheduler::{GroupEvent, GroupId, GroupInfo, Quota};
+use distros_sync::Notify;
+use libkernel::flow::{Message, StringMessage, U64Message};
+use spin::Lazy;
+
+type Result<T> = core::result::Result<T, FlowManagerError>;
+
+/// Period used when a quota is set on a group that had no limit
+const DEFAULT_PERIOD: Duration = Duration::from_millis(100);
+
+static EVENTS: Lazy<SegQueue<GroupEvent>> = Lazy::new(SegQueue::new);
+static EVENTS_NOTIFY: Notify = Notify::new();
+
+fn group_hook(event: GroupEvent) {
+    EVENTS.push(event);
+    EVENTS_NOTIFY.notify_one();
+}
+
+fn group_path(id: GroupId) -> String {
+    format!("/sys/groups/{}", id.as_u64())
+}
+
+/// Value of a group field, `fun` gets `None` if the group was removed
+struct GroupValue<T> {
+    id: GroupId,
+    fun: fn(Option<&GroupInfo>) -> T,
+}
+
+impl<T: Message + 'static> ValHandler<T> for GroupValue<T> {
+    fn get(&self) -> T {
+        (self.fun)(distros_scheduler::group_info(self.id).as_ref())
+    }
+}
+
+fn register_value<T: Message + 'static>(
+    id: GroupId,
+    name: &str,
+    fun: fn(Option<&GroupInfo>) -> T,
+) -> Result<()> {
+    VarProvider::new_val(GroupValue { id, fun }).register(&format!("{}/{}", group_path(id), name))
+}
+
+struct WeightVar {
+    id: GroupId,
+}
+
+impl VarHandler<U64Message> for WeightVar {
+    fn get(&self) -> U64Message {
+        let info = distros_scheduler::group_info(self.id);
+        U64Message::new(info.map(|i| i.weight as u64).unwrap_or(0))
+    }
+
+    fn set(&self, v: U64Message) {
+        let weight = v.get().min(u32::MAX as u64) as u32;
+        if let Err(e) = distros_scheduler::set_group_weight(self.id, weight) {
+            warn!(
+                "[sys/groups] Cannot set weight of group {:?}: {:?}",
+                self.id, e
+            );
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum QuotaField {
+    Quota,
+    Period,
+}
+
+/// Quota and period in microseconds, `0` quota means no limit
+struct QuotaVar {
+    id: GroupId,
+    field: QuotaField,
+}
+
+impl VarHandler<U64Message> for QuotaVar {
+    fn get(&self) -> U64Message {
+        let quota = distros_scheduler::group_info(self.id).and_then(|i| i.quota);
+        let value = quota.map(|q| match self.field {
+            QuotaField::Quota => q.quota,
+            QuotaField::Period => q.period,
+        });
+        U64Message::new(value.map(|v| v.as_micros() as u64).unwrap_or(0))
+    }
+
+    fn set(&self, v: U64Message) {
+        let value = Duration::from_micros(v.get());
+        let current = distros_scheduler::group_info(self.id).and_then(|i| i.quota);
+        let quota = match (self.field, current) {
+            (QuotaField::Quota, _) if value.is_zero() => None,
+            (QuotaField::Quota, current) => Some(Quota {
+                quota: value,
+                period: current.map(|q| q.period).unwrap_or(DEFAULT_PERIOD),
+            }),
+            (QuotaField::Period, Some(current)) => Some(Quota {
+                quota: current.quota,
+                period: value,
+            }),
+            (QuotaField::Period, None) => {
+                warn!(
+                    "[sys/groups] Group {:?} has no quota, set quota first",
+                    self.id
+                );
+                return;
+            }
+        };
+        if let Err(e) = distros_scheduler::set_group_quota(self.id, quota) {
+            warn!(
+                "[sys/groups] Cannot set quota of group {:?}: {:?}",
+                self.id, e
+            );
+        }
+    }
+}
+
+fn register_group(id: GroupId) -> Result<()> {
+    let path = group_path(id);
+    register_value(id, "name", |i| {
+        StringMessage::new(i.map_or("", |i| &i.name))
+    })?;
+    if id != GroupId::ROOT {
+        register_value(id, "parent", |i| {
+            U64Message::new(i.and_then(|i| i.parent).map_or(0, |p| p.as_u64()))
+        })?;
+    }
+    register_value(id, "usage", |i| {
+        U64Message::new(i.map_or(0, |i| i.usage.as_nanos() as u64))
+    })?;
+    register_value(id, "throttled", |i| {
+        U64Message::new(i.map_or(0, |i| i.throttled))
+    })?;
+    register_value(id, "tasks", |i| {
+        U64Message::new(i.map_or(0, |i| i.tasks as u64))
+    })?;
+    VarProvider::new_var(WeightVar { id }).register(&format!("{}/weight", path))?;
+    VarProvider::new_var(QuotaVar {
+        id,
+        field: QuotaField::Quota,
+    })
+    .register(&format!("{}/quota", path))?;
+    VarProvider::new_var(QuotaVar {
+        id,
+        field: QuotaField::Period,
+    })
+    .register(&format!("{}/period", path))
+}
+
+async fn handle_events() {
+    loop {
+        while let Some(event) = EVENTS.pop() {
+            let result = match event {
+                GroupEvent::Created(id) => register_group(id),
+                GroupEvent::Removed(id) => FlowManager::unregister(&group_path(id)),
+            };
+            if let Err(e) = result {
+                debug!("[sys/groups] Failed to apply {:?}: {:?}", event, e);
+            }
+        }
+        EVENTS_NOTIFY.notified().await;
+    }
+}
+
+pub fn init() {
+    Lazy::force(&EVENTS);
+    distros_scheduler::set_group_hook(group_hook);
+    for id in distros_scheduler::groups() {
+        EVENTS.push(GroupEvent::Created(id));
+    }
+    super::spawn_kernel("sys_groups", handle_events());
+}
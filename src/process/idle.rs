@@ -0,0 +1,72 @@
+//! `/sys/cpu/{n}/idle` flow subtree with idle residency statistics
+use crate::flow::{FlowManagerError, ValHandler, VarProvider};
+use libkernel::flow::U64Message;
+
+type Result<T> = core::result::Result<T, FlowManagerError>;
+
+#[derive(Copy, Clone)]
+enum IdleField {
+    /// Time spent idle in nanoseconds
+    Residency,
+    Entries,
+}
+
+struct IdleValue {
+    cpu: usize,
+    /// C-state number, `None` for all states together
+    state: Option<u8>,
+    field: IdleField,
+}
+
+impl ValHandler<U64Message> for IdleValue {
+    fn get(&self) -> U64Message {
+        let value = distros_scheduler::idle_stats(self.cpu).and_then(|stats| {
+            let (residency, entries) = match self.state {
+                None => (stats.residency, stats.entries),
+                Some(state) => {
+                    let stats = stats.states.iter().find(|s| s.state == state)?;
+                    (stats.residency, stats.entries)
+                }
+            };
+            Some(match self.field {
+                IdleField::Residency => residency.as_nanos() as u64,
+                IdleField::Entries => entries,
+            })
+        });
+        U64Message::new(value.unwrap_or(0))
+    }
+}
+
+fn register_values(cpu: usize, state: Option<u8>) -> Result<()> {
+    let path = match state {
+        None => format!("/sys/cpu/{}/idle", cpu),
+        Some(state) => format!("/sys/cpu/{}/idle/c{}", cpu, state),
+    };
+    VarProvider::new_val(IdleValue {
+        cpu,
+        state,
+        field: IdleField::Residency,
+    })
+    .register(&format!("{}/residency", path))?;
+    VarProvider::new_val(IdleValue {
+        cpu,
+        state,
+        field: IdleField::Entries,
+    })
+    .register(&format!("{}/entries", path))
+}
+
+pub fn init() {
+    // only the bootstrap CPU runs the scheduler for now
+    let cpu = distros_scheduler::current_cpu();
+    let states = distros_scheduler::idle_states();
+    let result = core::iter::once(None)
+        .chain(states.into_iter().map(Some))
+        .try_for_each(|state| register_values(cpu, state));
+    if let Err(e) = result {
+        warn!(
+            "[sys/cpu] Failed to register idle stats of CPU {}: {:?}",
+            cpu, e
+        );
+    }
+}
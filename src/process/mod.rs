@@ -1,11 +1,15 @@
-use alloc::borrow::ToOwned;
 use alloc::vec::Vec;
 use core::future::Future;
+use distros_scheduler::{TaskBuilder, TaskId};
 
+mod flow;
+mod fpu;
+mod groups;
+mod idle;
+mod pmu;
+mod security;
 pub mod sleep;
-mod task;
-
-use crate::process::task::ProcessTaskInfo;
+mod trace;
 
 struct Thread {}
 
@@ -13,21 +17,26 @@ struct Process {
     threads: Vec<Thread>,
 }
 
-pub fn spawn_kernel<F>(name: &str, future: F)
+pub fn init() {
+    flow::init();
+    fpu::init();
+    groups::init();
+    idle::init();
+    pmu::init();
+    security::init();
+    trace::init();
+}
+
+pub fn spawn_kernel<F>(name: &str, future: F) -> TaskId
 where
     F: Future<Output = ()> + 'static,
 {
-    task::add_task(
-        ProcessTaskInfo {
-            name: name.to_owned(),
-        },
-        future,
-    );
+    distros_scheduler::spawn(TaskBuilder::kernel(future).name(name))
 }
 
-/// Schedules future on main kernel loop
+/// Schedules future on the kernel scheduler
 macro_rules! spawn {
-    ($name:expr => $arg:expr) => {
-        crate::process::spawn_kernel($name, $arg)
-    };
+    ($name:expr => $arg:expr) => {{
+        crate::process::spawn_kernel($name, $arg);
+    }};
 }
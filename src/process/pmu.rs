@@ -0,0 +1,120 @@
+//! `/sys/cpu/{n}/pmu` counters and `/sys/pmu/profile` sampling profiler control
+use crate::flow::{FlowManagerError, ValHandler, VarHandler, VarProvider};
+use core::sync::atomic::{AtomicU64, Ordering};
+use distros_pmu::{Counter, Event};
+use distros_trace::SerialPort;
+use libkernel::flow::{BoolMessage, U64Message};
+
+type Result<T> = core::result::Result<T, FlowManagerError>;
+
+/// Cycles between samples
+const DEFAULT_PERIOD: u64 = 1_000_000;
+/// Functions written by a profile dump
+const DUMP_FUNCTIONS: usize = 50;
+
+static PERIOD: AtomicU64 = AtomicU64::new(DEFAULT_PERIOD);
+
+struct CounterValue {
+    counter: Counter,
+}
+
+impl ValHandler<U64Message> for CounterValue {
+    fn get(&self) -> U64Message {
+        U64Message::new(self.counter.read().unwrap_or(0))
+    }
+}
+
+struct Enabled;
+
+impl VarHandler<BoolMessage> for Enabled {
+    fn get(&self) -> BoolMessage {
+        BoolMessage::new(distros_pmu::sampling())
+    }
+
+    fn set(&self, v: BoolMessage) {
+        let result = if v.get() {
+            distros_pmu::start_sampling(Event::Cycles, PERIOD.load(Ordering::Acquire))
+        } else {
+            distros_pmu::stop_sampling()
+        };
+        if let Err(e) = result {
+            warn!("[sys/pmu] Failed to switch profiling: {:?}", e);
+        }
+    }
+}
+
+/// Cycles between samples, applied on the next start
+struct Period;
+
+impl VarHandler<U64Message> for Period {
+    fn get(&self) -> U64Message {
+        U64Message::new(PERIOD.load(Ordering::Acquire))
+    }
+
+    fn set(&self, v: U64Message) {
+        PERIOD.store(v.get(), Ordering::Release);
+    }
+}
+
+/// Writing `true` dumps the flat profile to COM1
+struct Dump;
+
+impl VarHandler<BoolMessage> for Dump {
+    fn get(&self) -> BoolMessage {
+        BoolMessage::new(false)
+    }
+
+    fn set(&self, v: BoolMessage) {
+        if !v.get() {
+            return;
+        }
+        let tasks = distros_scheduler::tasks();
+        let mut serial = SerialPort::new(SerialPort::COM1);
+        let result = distros_pmu::profile().write(&mut serial, DUMP_FUNCTIONS, |task| {
+            let id = tasks.iter().find(|id| id.as_u64() == task)?;
+            distros_scheduler::get_name(*id).flatten()
+        });
+        if result.is_err() {
+            warn!("[sys/pmu] Failed to dump profile");
+        }
+    }
+}
+
+fn register_counters(cpu: usize) -> Result<()> {
+    let events = [
+        ("cycles", Event::Cycles),
+        ("instructions", Event::Instructions),
+        ("cache_misses", Event::CacheMisses),
+    ];
+    for (name, event) in events {
+        match Counter::new(event) {
+            Ok(counter) => VarProvider::new_val(CounterValue { counter })
+                .register(&format!("/sys/cpu/{}/pmu/{}", cpu, name))?,
+            Err(e) => debug!("[sys/cpu] Cannot count {} on CPU {}: {:?}", name, cpu, e),
+        }
+    }
+    Ok(())
+}
+
+fn register_profile() -> Result<()> {
+    VarProvider::new_var(Enabled).register("/sys/pmu/profile/enabled")?;
+    VarProvider::new_var(Period).register("/sys/pmu/profile/period")?;
+    VarProvider::new_var(Dump).register("/sys/pmu/profile/dump")
+}
+
+pub fn init() {
+    if distros_pmu::info().is_none() {
+        return;
+    }
+    // only the bootstrap CPU runs the scheduler for now
+    let cpu = distros_scheduler::current_cpu();
+    if let Err(e) = register_counters(cpu) {
+        warn!(
+            "[sys/cpu] Failed to register PMU counters of CPU {}: {:?}",
+            cpu, e
+        );
+    }
+    if let Err(e) = register_profile() {
+        warn!("[sys/pmu] Failed to register: {:?}", e);
+    }
+}
@@ -0,0 +1,43 @@
+//! `/sys/cpu/{n}/security` flow subtree with enabled CPU protections
+use crate::flow::{FlowManagerError, ValHandler, VarProvider};
+use distros_security::Protections;
+use libkernel::flow::BoolMessage;
+
+type Result<T> = core::result::Result<T, FlowManagerError>;
+
+const PROTECTIONS: [(&str, Protections); 5] = [
+    ("smep", Protections::SMEP),
+    ("smap", Protections::SMAP),
+    ("umip", Protections::UMIP),
+    ("nxe", Protections::NXE),
+    ("wp", Protections::WP),
+];
+
+struct ProtectionValue {
+    protection: Protections,
+}
+
+impl ValHandler<BoolMessage> for ProtectionValue {
+    fn get(&self) -> BoolMessage {
+        BoolMessage::new(distros_security::protections().contains(self.protection))
+    }
+}
+
+fn register_values(cpu: usize) -> Result<()> {
+    for (name, protection) in PROTECTIONS {
+        VarProvider::new_val(ProtectionValue { protection })
+            .register(&format!("/sys/cpu/{}/security/{}", cpu, name))?;
+    }
+    Ok(())
+}
+
+pub fn init() {
+    // protections are only enabled on the bootstrap CPU for now
+    let cpu = distros_scheduler::current_cpu();
+    if let Err(e) = register_values(cpu) {
+        warn!(
+            "[sys/cpu] Failed to register protections of CPU {}: {:?}",
+            cpu, e
+        );
+    }
+}
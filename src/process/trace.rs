@@ -0,0 +1,56 @@
+//! `/sys/trace` flow subtree controlling scheduler tracepoints
+use crate::flow::{FlowManagerError, VarHandler, VarProvider};
+use core::fmt::Write;
+use distros_trace::SerialPort;
+use libkernel::flow::BoolMessage;
+
+type Result<T> = core::result::Result<T, FlowManagerError>;
+
+struct Enabled;
+
+impl VarHandler<BoolMessage> for Enabled {
+    fn get(&self) -> BoolMessage {
+        BoolMessage::new(distros_trace::enabled())
+    }
+
+    fn set(&self, v: BoolMessage) {
+        distros_trace::set_enabled(v.get());
+    }
+}
+
+/// Writing `true` dumps trace rings to COM1
+struct Dump;
+
+impl VarHandler<BoolMessage> for Dump {
+    fn get(&self) -> BoolMessage {
+        BoolMessage::new(false)
+    }
+
+    fn set(&self, v: BoolMessage) {
+        if !v.get() {
+            return;
+        }
+        let mut serial = SerialPort::new(SerialPort::COM1);
+        let result = distros_trace::export(&mut serial, |w| {
+            for id in distros_scheduler::tasks() {
+                let name = distros_scheduler::get_name(id).flatten();
+                writeln!(w, "T {} {}", id.as_u64(), name.as_deref().unwrap_or(""))?;
+            }
+            Ok(())
+        });
+        if result.is_err() {
+            warn!("[sys/trace] Failed to export trace");
+        }
+    }
+}
+
+fn register() -> Result<()> {
+    VarProvider::new_var(Enabled).register("/sys/trace/enabled")?;
+    VarProvider::new_var(Dump).register("/sys/trace/dump")
+}
+
+pub fn init() {
+    if let Err(e) = register() {
+        warn!("[sys/trace] Failed to register: {:?}", e);
+    }
+}